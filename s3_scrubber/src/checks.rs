@@ -1,169 +1,2439 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
 
-use anyhow::Context;
-use aws_sdk_s3::{types::ObjectIdentifier, Client};
+use aws_sdk_s3::types::ObjectIdentifier;
 use pageserver::tenant::remote_timeline_client::index::LayerFileMetadata;
-use pageserver_api::shard::ShardIndex;
+use pageserver_api::shard::{
+    ShardCount, ShardIdentity, ShardIndex, ShardNumber, ShardStripeSize, TenantShardId,
+};
 use tracing::{error, info, warn};
+use utils::bin_ser::BeSer;
 use utils::generation::Generation;
 use utils::id::TimelineId;
+use utils::lsn::Lsn;
 
 use crate::cloud_admin_api::BranchData;
-use crate::metadata_stream::stream_listing;
-use crate::{download_object_with_retries, RootTarget, TenantShardTimelineId};
+use crate::json_schema;
+use crate::metadata_stream::{stream_listing, stream_listing_with_sizes};
+use crate::{
+    download_object_prefix_with_retries, download_object_with_retries, get_object_tags_batch,
+    head_objects_batch, list_noncurrent_versions, ListingObject, NoncurrentVersion, ObjectTag,
+    ObjectTooLarge, PreservedInitdbPolicy, RetryBudgetExhausted, RetryPolicy, RootTarget,
+    ScrubOptions, StorageClient, TenantShardTimelineId,
+};
+use async_compression::tokio::bufread::ZstdDecoder;
 use futures_util::StreamExt;
-use pageserver::tenant::remote_timeline_client::parse_remote_index_path;
-use pageserver::tenant::storage_layer::LayerName;
+use pageserver::page_cache::PAGE_SZ;
+use pageserver::repository::Key;
+use pageserver::tenant::remote_timeline_client::{parse_remote_index_path, remote_layer_path};
+use pageserver::tenant::storage_layer::{
+    delta_layer, image_layer, DeltaLayerName, ImageLayerName, LayerName,
+};
 use pageserver::tenant::IndexPart;
+use pageserver::{DELTA_FILE_MAGIC, IMAGE_FILE_MAGIC};
 use remote_storage::RemotePath;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The findings of a timeline's checks.  Everything but the per-layer detail is serializable, so
+/// that a run's findings can be saved and compared against a later run's.
+#[derive(Serialize, Deserialize)]
+pub struct TimelineAnalysis {
+    pub(crate) id: TenantShardTimelineId,
+
+    /// The run that produced these findings: absent from findings saved by older versions
+    #[serde(default)]
+    pub(crate) run_id: Option<Uuid>,
 
-pub(crate) struct TimelineAnalysis {
     /// Anomalies detected
-    pub(crate) errors: Vec<String>,
+    pub(crate) errors: Vec<Finding>,
+
+    /// Healthy-but-noteworthy, like old-versioned structures that are readable but
+    /// worth reporting for awareness that we must not remove that old version decoding
+    /// yet.
+    pub(crate) warnings: Vec<Finding>,
+
+    /// Keys not referenced in metadata: candidates for removal, but NOT NECESSARILY: beware
+    /// of races between reading the metadata and reading the objects.
+    pub(crate) garbage_keys: Vec<String>,
+
+    /// The checks did not complete within the per-timeline timeout: the findings above are
+    /// whatever was found before giving up.
+    pub(crate) timed_out: bool,
+
+    /// Every layer referenced by the index, for reporting: only kept if the options ask for it,
+    /// or a check needs it
+    #[serde(skip)]
+    pub(crate) layers: Vec<LayerReport>,
+
+    /// How many of the referenced layers that are missing are not L0s, which weigh more in the
+    /// health score
+    #[serde(default)]
+    pub(crate) missing_non_l0_layers: usize,
+
+    /// How the index's layers and the layers listed under the timeline prefix line up
+    pub(crate) reconciliation: LayerReconciliation,
+
+    /// Whether the index's layers form a structurally valid layer map, and if not, how many
+    /// violations were found: the violations themselves are also in `errors`
+    pub(crate) layer_map_valid: bool,
+    pub(crate) layer_map_violations: usize,
+
+    /// Referenced layers left unchecked because they do not overlap the key range filter
+    #[serde(default)]
+    pub(crate) layers_outside_key_filter: usize,
+
+    /// Basic facts about the timeline from its index, if it has one, for an inventory of storage
+    #[serde(default)]
+    pub(crate) stats: Option<TimelineStats>,
+
+    /// Where the timeline was found, if the console was consulted: the prose findings about the
+    /// console are in `errors` and `warnings`
+    pub(crate) console: Option<ConsoleReconciliation>,
+
+    /// How findings are sorted into errors and warnings: not part of the findings
+    #[serde(skip)]
+    severity_policy: SeverityPolicy,
+}
+
+/// What a timeline's index says about its size and progress, whether or not it is healthy.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct TimelineStats {
+    pub(crate) layer_count: usize,
+    pub(crate) layer_bytes: u64,
+    pub(crate) disk_consistent_lsn: Lsn,
+    /// A checksum of the layers the index references: timelines whose fingerprints match in two
+    /// runs reference the same layers.  Absent from findings saved before fingerprints were
+    /// taken.
+    #[serde(default)]
+    pub(crate) fingerprint: Option<u32>,
+}
+
+/// Fingerprint a timeline by the layers its index references, with their generations, shards
+/// and sizes.  The order of layers in the index does not matter.  The disk consistent LSN is
+/// left out, as it moves on with every upload while a timeline ingests: compare it separately if
+/// the timelines should be quiescent.
+fn timeline_fingerprint(index_part: &IndexPart) -> u32 {
+    let mut lines: Vec<String> = index_part
+        .layer_metadata
+        .iter()
+        .map(|(layer, metadata)| {
+            format!(
+                "{layer}{} {} {}\n",
+                metadata.generation.get_suffix(),
+                metadata.shard,
+                metadata.file_size
+            )
+        })
+        .collect();
+    lines.sort();
+    lines
+        .iter()
+        .fold(0, |crc, line| crc32c::crc32c_append(crc, line.as_bytes()))
+}
+
+/// Whether a timeline exists in storage and in the console.  Of the four combinations, only
+/// present in both (live) or in neither (deleted) are consistent: a timeline only in storage is
+/// orphaned there, and one only in the console has lost its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ConsoleReconciliation {
+    pub(crate) in_s3: bool,
+    /// The console has a branch for the timeline that is not deleted
+    pub(crate) in_console: bool,
+}
+
+/// Three-way reconciliation between the layers an index references and the layers listed in storage.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct LayerReconciliation {
+    pub(crate) referenced_present: usize,
+    pub(crate) referenced_missing: usize,
+    /// Listed under this timeline shard's own prefix, but not referenced by its index
+    pub(crate) present_unreferenced: usize,
+}
+
+/// A layer referenced by a timeline's index, and whether it was found in remote storage.
+pub(crate) struct LayerReport {
+    name: LayerName,
+    metadata: LayerFileMetadata,
+    present: bool,
+    /// The layer's size according to the listing, if it was listed
+    listed_size: Option<u64>,
+}
+
+/// L0 layers are the deltas that span the whole key space, written straight from WAL
+fn is_l0(layer: &LayerName) -> bool {
+    matches!(layer, LayerName::Delta(delta) if delta.key_range == (Key::MIN..Key::MAX))
+}
+
+impl LayerReport {
+    pub(crate) const CSV_HEADER: &'static str =
+        "tenant,shard,timeline,layer,kind,key_range,lsn_range,generation,file_size,present";
+
+    /// One CSV row, in the column order of [`Self::CSV_HEADER`].  None of the fields can contain
+    /// commas or quotes, so no escaping is needed.
+    pub(crate) fn csv_row(&self, id: &TenantShardTimelineId) -> String {
+        let (kind, key_range, lsn_range) = match &self.name {
+            LayerName::Delta(delta) => ("delta", &delta.key_range, delta.lsn_range.clone()),
+            LayerName::Image(image) => ("image", &image.key_range, image.lsn_as_range()),
+        };
+        format!(
+            "{},{},{},{},{},{}-{},{}-{},{},{},{}",
+            id.tenant_shard_id.tenant_id,
+            id.tenant_shard_id.to_index(),
+            id.timeline_id,
+            self.name,
+            kind,
+            key_range.start,
+            key_range.end,
+            lsn_range.start,
+            lsn_range.end,
+            self.metadata
+                .generation
+                .into()
+                .map(|g| g.to_string())
+                .unwrap_or_default(),
+            self.metadata.file_size,
+            self.present,
+        )
+    }
+}
+
+/// A stable identifier for each kind of finding, so that tooling can tell findings apart without
+/// matching on their messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckCode {
+    ConsoleBranchMismatch,
+    Timeout,
+    UnparseableLayerFormat,
+    LayerNameRoundTrip,
+    CaseDuplicateLayerKey,
+    DuplicateIndexEntry,
+    UnknownIndexVersion,
+    IndexSchemaViolation,
+    OldIndexVersion,
+    IncompleteDeletion,
+    DiskConsistentLsnMismatch,
+    DiskConsistentLsnRegression,
+    LayerCountDrop,
+    ZeroDiskConsistentLsn,
+    ZeroAncestorLsn,
+    IndexIdentityMismatch,
+    LayerOutsideStripes,
+    LayerShardMismatch,
+    LayerShardFanOut,
+    PageserverLayerMismatch,
+    DuplicatedLayerData,
+    ImageWithinDelta,
+    DuplicateImageLayer,
+    MisalignedImageLayer,
+    FullKeyspaceLayer,
+    DeepL0Stack,
+    InvalidLayerMap,
+    GcHorizonGap,
+    LayerStackGap,
+    NoOwnLayers,
+    ZeroSizeLayer,
+    OversizedLayer,
+    UndersizedIndex,
+    LayerExistenceUnknown,
+    MissingLayer,
+    LayerContentMismatch,
+    LayerTaggedForDeletion,
+    LayerTagsUnknown,
+    DeepScrubBudgetSpent,
+    SupersededLayerGeneration,
+    LayerNewerThanIndex,
+    LayerGenerationMismatch,
+    LayerBeyondConsistentLsn,
+    MissingIndex,
+    ListingFailed,
+    PreservedInitdbArchive,
+    ParseError,
+    NoData,
+}
+
+impl CheckCode {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::ConsoleBranchMismatch => "console-branch-mismatch",
+            Self::Timeout => "timeout",
+            Self::UnparseableLayerFormat => "unparseable-layer-format",
+            Self::LayerNameRoundTrip => "layer-name-round-trip",
+            Self::CaseDuplicateLayerKey => "case-duplicate-layer-key",
+            Self::DuplicateIndexEntry => "duplicate-index-entry",
+            Self::UnknownIndexVersion => "unknown-index-version",
+            Self::IndexSchemaViolation => "index-schema-violation",
+            Self::OldIndexVersion => "old-index-version",
+            Self::IncompleteDeletion => "incomplete-deletion",
+            Self::DiskConsistentLsnMismatch => "disk-consistent-lsn-mismatch",
+            Self::DiskConsistentLsnRegression => "disk-consistent-lsn-regression",
+            Self::LayerCountDrop => "layer-count-drop",
+            Self::ZeroDiskConsistentLsn => "zero-disk-consistent-lsn",
+            Self::ZeroAncestorLsn => "zero-ancestor-lsn",
+            Self::IndexIdentityMismatch => "index-identity-mismatch",
+            Self::LayerOutsideStripes => "layer-outside-stripes",
+            Self::LayerShardMismatch => "layer-shard-mismatch",
+            Self::LayerShardFanOut => "layer-shard-fan-out",
+            Self::PageserverLayerMismatch => "pageserver-layer-mismatch",
+            Self::DuplicatedLayerData => "duplicated-layer-data",
+            Self::ImageWithinDelta => "image-within-delta",
+            Self::DuplicateImageLayer => "duplicate-image-layer",
+            Self::MisalignedImageLayer => "misaligned-image-layer",
+            Self::FullKeyspaceLayer => "full-keyspace-layer",
+            Self::DeepL0Stack => "deep-l0-stack",
+            Self::InvalidLayerMap => "invalid-layer-map",
+            Self::GcHorizonGap => "gc-horizon-gap",
+            Self::LayerStackGap => "layer-stack-gap",
+            Self::NoOwnLayers => "no-own-layers",
+            Self::ZeroSizeLayer => "zero-size-layer",
+            Self::OversizedLayer => "oversized-layer",
+            Self::UndersizedIndex => "undersized-index",
+            Self::LayerExistenceUnknown => "layer-existence-unknown",
+            Self::MissingLayer => "missing-layer",
+            Self::LayerContentMismatch => "layer-content-mismatch",
+            Self::LayerTaggedForDeletion => "layer-tagged-for-deletion",
+            Self::LayerTagsUnknown => "layer-tags-unknown",
+            Self::DeepScrubBudgetSpent => "deep-scrub-budget-spent",
+            Self::SupersededLayerGeneration => "superseded-layer-generation",
+            Self::LayerNewerThanIndex => "layer-newer-than-index",
+            Self::LayerGenerationMismatch => "layer-generation-mismatch",
+            Self::LayerBeyondConsistentLsn => "layer-beyond-consistent-lsn",
+            Self::MissingIndex => "missing-index",
+            Self::ListingFailed => "listing-failed",
+            Self::PreservedInitdbArchive => "preserved-initdb-archive",
+            Self::ParseError => "parse-error",
+            Self::NoData => "no-data",
+        }
+    }
+
+    /// What an operator might do about a finding of this kind, where there is more to say than
+    /// the message does.  Derived from the code alone, so that the same finding always comes
+    /// with the same advice.
+    pub(crate) fn suggestion(&self) -> Option<&'static str> {
+        match self {
+            Self::ConsoleBranchMismatch => Some(
+                "Find out whether the timeline was deleted or created behind the console's back, and reconcile the two",
+            ),
+            Self::Timeout
+            | Self::ListingFailed
+            | Self::LayerExistenceUnknown
+            | Self::LayerTagsUnknown => {
+                Some("Rerun the scan for this timeline: the check failed, not necessarily the data")
+            }
+            Self::UnparseableLayerFormat | Self::UnknownIndexVersion => {
+                Some("Upgrade the scrubber to a version that knows this format")
+            }
+            Self::CaseDuplicateLayerKey => {
+                Some("Delete the copy whose casing differs from what the pageserver writes")
+            }
+            Self::IncompleteDeletion => {
+                Some("Resume the timeline's deletion through the pageserver API")
+            }
+            Self::DiskConsistentLsnRegression => Some(
+                "Look for two pageservers attached in different generations, and keep older indices until it is resolved",
+            ),
+            Self::LayerCountDrop => Some(
+                "Compare the two generations' indices for layers dropped without a replacement, and keep the older index until then",
+            ),
+            Self::ZeroDiskConsistentLsn | Self::ZeroAncestorLsn | Self::InvalidLayerMap => {
+                Some("Compare with the indices of older generations, for one that is sound")
+            }
+            Self::UndersizedIndex => Some(
+                "Compare with the index of the previous generation, to tell whether this one was truncated",
+            ),
+            Self::IndexIdentityMismatch => {
+                Some("Find out whether the index was copied from another timeline or shard")
+            }
+            Self::LayerOutsideStripes => Some(
+                "Check the shard stripe size given to the scrubber: if it is right, the wrong shard wrote the layer",
+            ),
+            Self::LayerShardMismatch => Some(
+                "Copy the layer to the shard the index names, or correct the index to name the shard holding it",
+            ),
+            Self::DeepL0Stack => Some("Check that compaction is running for the tenant"),
+            Self::GcHorizonGap | Self::LayerStackGap | Self::MissingLayer | Self::MissingIndex => {
+                Some("Look for the missing object among noncurrent versions and older generations")
+            }
+            Self::ZeroSizeLayer => Some(
+                "Re-upload the layer from the pageserver's local state, or delete it and let compaction regenerate it",
+            ),
+            Self::LayerContentMismatch => {
+                Some("Re-upload the layer from the pageserver's local state, if that is sound")
+            }
+            Self::DeepScrubBudgetSpent => Some("Rerun the deep scrub with a larger budget"),
+            Self::LayerTaggedForDeletion => {
+                Some("Remove the deletion tag before the bucket's lifecycle rules act on it")
+            }
+            Self::LayerNewerThanIndex => Some(
+                "Look for an index of the layer's generation that the scan did not choose, before treating the layer as torn",
+            ),
+            Self::SupersededLayerGeneration => {
+                Some("Purge garbage to reclaim the older generations")
+            }
+            Self::LayerGenerationMismatch => Some(
+                "Compare the indices of the generations involved to tell which one wrote the layer",
+            ),
+            Self::PreservedInitdbArchive => {
+                Some("Restore the archive from a noncurrent version, if there is one")
+            }
+            Self::IndexSchemaViolation
+            | Self::OldIndexVersion
+            | Self::DuplicateIndexEntry
+            | Self::LayerNameRoundTrip
+            | Self::DiskConsistentLsnMismatch
+            | Self::LayerShardFanOut
+            | Self::PageserverLayerMismatch
+            | Self::DuplicatedLayerData
+            | Self::ImageWithinDelta
+            | Self::DuplicateImageLayer
+            | Self::MisalignedImageLayer
+            | Self::FullKeyspaceLayer
+            | Self::NoOwnLayers
+            | Self::OversizedLayer
+            | Self::LayerBeyondConsistentLsn
+            | Self::ParseError
+            | Self::NoData => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CheckCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One finding of a timeline's checks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Finding {
+    pub(crate) code: CheckCode,
+    pub(crate) message: String,
+    /// See [`CheckCode::suggestion`]: absent from findings saved by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) suggestion: Option<String>,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl TimelineAnalysis {
+    fn new(id: TenantShardTimelineId, options: &ScrubOptions) -> Self {
+        Self {
+            id,
+            run_id: Some(options.run_id),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            garbage_keys: Vec::new(),
+            timed_out: false,
+            layers: Vec::new(),
+            missing_non_l0_layers: 0,
+            reconciliation: LayerReconciliation::default(),
+            layer_map_valid: true,
+            layer_map_violations: 0,
+            layers_outside_key_filter: 0,
+            stats: None,
+            console: None,
+            severity_policy: options.severity_policy.clone(),
+        }
+    }
+
+    /// A timeline is healthy if its checks completed and found no errors: warnings are fine.
+    pub fn is_healthy(&self) -> bool {
+        self.errors.is_empty() && !self.timed_out
+    }
+
+    /// Record a finding whose severity is chosen by the caller rather than fixed by the check.
+    /// The severity policy has the last word.
+    fn push(&mut self, severity: Severity, code: CheckCode, message: String) {
+        let finding = Finding {
+            code,
+            message,
+            suggestion: code.suggestion().map(str::to_string),
+        };
+        match self.severity_policy.severity(code, severity) {
+            Severity::Info => info!("{finding}"),
+            Severity::Warning => self.warnings.push(finding),
+            Severity::Error => self.errors.push(finding),
+        }
+    }
+
+    /// Take on the findings of checks run separately on the same timeline
+    fn merge(&mut self, other: TimelineAnalysis) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+        self.layer_map_valid &= other.layer_map_valid;
+        self.layer_map_violations += other.layer_map_violations;
+    }
+
+    fn error(&mut self, code: CheckCode, message: String) {
+        self.push(Severity::Error, code, message)
+    }
+
+    fn warning(&mut self, code: CheckCode, message: String) {
+        self.push(Severity::Warning, code, message)
+    }
+
+    fn extend(&mut self, severity: Severity, code: CheckCode, messages: Vec<String>) {
+        for message in messages {
+            self.push(severity, code, message)
+        }
+    }
+
+    /// A 0-100 summary of the findings for triage, where 100 means nothing was found: each
+    /// finding costs points according to `weights`, down to a floor of 0.
+    pub(crate) fn health_score(&self, weights: &ScoreWeights) -> u8 {
+        let weight =
+            |code: CheckCode, default: u32| *weights.by_code.get(&code).unwrap_or(&default);
+
+        let missing_non_l0 = self.missing_non_l0_layers;
+        let mut penalty = (missing_non_l0 as u64) * u64::from(weights.missing_non_l0_layer);
+
+        let mut missing_non_l0_remaining = missing_non_l0;
+        for finding in &self.errors {
+            if finding.code == CheckCode::MissingLayer && missing_non_l0_remaining > 0 {
+                // Already counted above, at the missing non-L0 weight
+                missing_non_l0_remaining -= 1;
+                continue;
+            }
+            penalty += u64::from(weight(finding.code, weights.error));
+        }
+        for finding in &self.warnings {
+            penalty += u64::from(weight(finding.code, weights.warning));
+        }
+        if self.timed_out {
+            penalty += u64::from(weights.timed_out);
+        }
+
+        100 - std::cmp::min(penalty, 100) as u8
+    }
+}
+
+/// How many points of a timeline's health score each kind of finding costs.  Which anomalies
+/// matter most differs between operators, so every weight can be changed, and any kind of
+/// finding can be given its own weight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoreWeights {
+    pub error: u32,
+    pub warning: u32,
+    /// A referenced layer other than an L0 is missing: costs this instead of `error`
+    pub missing_non_l0_layer: u32,
+    pub timed_out: u32,
+    /// Weights for particular kinds of finding, in place of `error` or `warning`
+    pub by_code: HashMap<CheckCode, u32>,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            error: 20,
+            warning: 2,
+            missing_non_l0_layer: 50,
+            timed_out: 20,
+            by_code: HashMap::new(),
+        }
+    }
+}
+
+/// The severity to report each kind of finding at, in place of the one its check chooses.  Which
+/// anomalies should gate a pipeline differs between operators: by default, no kind of finding is
+/// overridden.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SeverityPolicy {
+    pub by_code: HashMap<CheckCode, Severity>,
+}
+
+impl SeverityPolicy {
+    /// The severity of a finding of kind `code` that its check reports at `severity`
+    fn severity(&self, code: CheckCode, severity: Severity) -> Severity {
+        self.by_code.get(&code).copied().unwrap_or(severity)
+    }
+}
+
+/// Findings that differ between two runs, keyed by timeline and kind of finding
+#[derive(Debug, Default, Serialize)]
+pub struct FindingsDiff {
+    pub appeared: Vec<(TenantShardTimelineId, CheckCode)>,
+    pub resolved: Vec<(TenantShardTimelineId, CheckCode)>,
+}
+
+/// Compare the findings of a previous run against the current one.  Findings are compared by
+/// their codes, not their messages, which may mention details such as LSNs that change between
+/// runs.  A timeline missing from a run has no findings in it.
+pub(crate) fn diff_findings(old: &[TimelineAnalysis], new: &[TimelineAnalysis]) -> FindingsDiff {
+    fn keys(analyses: &[TimelineAnalysis]) -> BTreeSet<(TenantShardTimelineId, CheckCode)> {
+        analyses
+            .iter()
+            .flat_map(|analysis| {
+                analysis
+                    .errors
+                    .iter()
+                    .chain(analysis.warnings.iter())
+                    .map(|finding| (analysis.id, finding.code))
+            })
+            .collect()
+    }
+    let (old, new) = (keys(old), keys(new));
+
+    FindingsDiff {
+        appeared: new.difference(&old).copied().collect(),
+        resolved: old.difference(&new).copied().collect(),
+    }
+}
+
+/// Timelines whose fingerprints differ between a baseline run and the current one
+#[derive(Debug, Default, Serialize)]
+pub struct FingerprintDiff {
+    pub changed: Vec<TenantShardTimelineId>,
+    pub missing: Vec<TenantShardTimelineId>,
+    pub added: Vec<TenantShardTimelineId>,
+}
+
+impl FingerprintDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Compare the fingerprints of timelines in a baseline run against the current one, e.g. from
+/// before and after a migration.  Only timelines with stats take part: both runs should have
+/// included healthy timelines, or every healthy timeline would look missing or added.  A
+/// timeline without a fingerprint in either run, e.g. in a baseline saved by an older version,
+/// cannot be told to have changed, so is left out of `changed`.
+///
+/// With `compare_lsn`, a timeline whose disk consistent LSN moved also counts as changed: only
+/// useful for timelines that were not ingesting between the runs.
+pub(crate) fn diff_fingerprints(
+    old: &[TimelineAnalysis],
+    new: &[TimelineAnalysis],
+    compare_lsn: bool,
+) -> FingerprintDiff {
+    fn stats(analyses: &[TimelineAnalysis]) -> BTreeMap<TenantShardTimelineId, TimelineStats> {
+        analyses
+            .iter()
+            .filter_map(|analysis| Some((analysis.id, analysis.stats?)))
+            .collect()
+    }
+    let (old, new) = (stats(old), stats(new));
+
+    let mut diff = FingerprintDiff::default();
+    for (ttid, old_stats) in &old {
+        let Some(new_stats) = new.get(ttid) else {
+            diff.missing.push(*ttid);
+            continue;
+        };
+        let fingerprint_changed = match (old_stats.fingerprint, new_stats.fingerprint) {
+            (Some(old_fingerprint), Some(new_fingerprint)) => old_fingerprint != new_fingerprint,
+            _ => false,
+        };
+        let lsn_changed =
+            compare_lsn && old_stats.disk_consistent_lsn != new_stats.disk_consistent_lsn;
+        if fingerprint_changed || lsn_changed {
+            diff.changed.push(*ttid);
+        }
+    }
+    diff.added = new
+        .keys()
+        .filter(|ttid| !old.contains_key(ttid))
+        .copied()
+        .collect();
+    diff
+}
+
+/// Invert per-timeline findings into the timelines affected by each kind of finding, for a view
+/// by category of a whole run.  Each timeline is listed once per kind, in order.
+pub(crate) fn group_by_code(
+    analyses: &[TimelineAnalysis],
+) -> BTreeMap<CheckCode, Vec<TenantShardTimelineId>> {
+    let mut by_code: BTreeMap<CheckCode, BTreeSet<TenantShardTimelineId>> = BTreeMap::new();
+    for analysis in analyses {
+        for finding in analysis.errors.iter().chain(analysis.warnings.iter()) {
+            by_code.entry(finding.code).or_default().insert(analysis.id);
+        }
+    }
+    by_code
+        .into_iter()
+        .map(|(code, ids)| (code, ids.into_iter().collect()))
+        .collect()
+}
+
+/// How seriously to treat a finding. Errors make a scrub fatal, warnings are reported for
+/// awareness, and info findings are only logged.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// Check a timeline's storage (and, optionally, its console state).  The storage checks are bounded
+/// by `options.timeline_timeout`, as the listing that they check is, so that one pathological timeline cannot
+/// stall a whole-bucket scrub: if they don't complete in time, the partial analysis is returned with `timed_out` set.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(
+    tenant_id = %id.tenant_shard_id.tenant_id,
+    shard_id = %id.tenant_shard_id.shard_slug(),
+    timeline_id = %id.timeline_id,
+    layer_count = tracing::field::Empty,
+    error_count = tracing::field::Empty,
+    warning_count = tracing::field::Empty,
+))]
+pub(crate) async fn branch_cleanup_and_check_errors(
+    s3_client: &StorageClient,
+    s3_root: &RootTarget,
+    id: &TenantShardTimelineId,
+    tenant_objects: &mut TenantObjectListing,
+    s3_active_branch: Option<&BranchData>,
+    console_branch: Option<BranchData>,
+    s3_data: Option<S3TimelineBlobData>,
+    options: &ScrubOptions,
+) -> TimelineAnalysis {
+    let mut result = TimelineAnalysis::new(*id, options);
+
+    info!("Checking timeline {id}");
+
+    // Whether the console considers this timeline deleted, if we consulted it at all
+    let console_deleted = s3_active_branch.map(|_| {
+        console_branch
+            .as_ref()
+            .map_or(true, |branch| branch.deleted)
+    });
+    result.console = console_deleted.map(|deleted| ConsoleReconciliation {
+        in_s3: s3_data.is_some(),
+        in_console: !deleted,
+    });
+
+    // Console reconciliation is an optional step: callers without console access (e.g. self-hosted
+    // deployments) pass no branch data, and only the storage consistency checks are run.
+    if let Some(s3_active_branch) = s3_active_branch {
+        result.extend(
+            Severity::Error,
+            CheckCode::ConsoleBranchMismatch,
+            check_console_branch(s3_active_branch, console_branch),
+        );
+    }
+
+    let timeout = options.timeline_timeout;
+    let storage_checks = check_timeline_storage(
+        s3_client,
+        s3_root,
+        &mut result,
+        id,
+        tenant_objects,
+        s3_data,
+        console_deleted,
+        options,
+    );
+    let timed_out = tokio::time::timeout(timeout, storage_checks).await.is_err();
+    if timed_out {
+        result.timed_out = true;
+        result.error(
+            CheckCode::Timeout,
+            format!("Timeline checks did not complete within {timeout:?}"),
+        );
+    }
+
+    if result.errors.is_empty() {
+        info!("No check errors found");
+    } else {
+        warn!("Timeline metadata errors: {0:?}", result.errors);
+    }
+
+    if !result.warnings.is_empty() {
+        warn!("Timeline metadata warnings: {0:?}", result.warnings);
+    }
+
+    if !result.garbage_keys.is_empty() {
+        error!(
+            "The following keys should be removed from S3: {0:?}",
+            result.garbage_keys
+        )
+    }
+
+    let span = tracing::Span::current();
+    span.record(
+        "layer_count",
+        result.reconciliation.referenced_present + result.reconciliation.referenced_missing,
+    );
+    span.record("error_count", result.errors.len());
+    span.record("warning_count", result.warnings.len());
+
+    result
+}
+
+/// Cross-check a timeline found in storage against the console's view of its branch.
+fn check_console_branch(
+    s3_active_branch: &BranchData,
+    console_branch: Option<BranchData>,
+) -> Vec<String> {
+    info!(
+        "Checking console status for timeline for branch {:?}/{:?}",
+        s3_active_branch.project_id, s3_active_branch.id
+    );
+    match console_branch {
+        Some(_) => vec![format!("Timeline has deleted branch data in the console (id = {:?}, project_id = {:?}), recheck whether it got removed during the check",
+            s3_active_branch.id, s3_active_branch.project_id)],
+        None => vec![format!("Timeline has no branch data in the console (id = {:?}, project_id = {:?}), recheck whether it got removed during the check",
+            s3_active_branch.id, s3_active_branch.project_id)],
+    }
+}
+
+/// Storage consistency checks for a timeline: these need nothing but the contents of remote storage,
+/// except that the console's view (`console_deleted`) qualifies how a timeline without any data is reported.
+#[allow(clippy::too_many_arguments)]
+async fn check_timeline_storage(
+    s3_client: &StorageClient,
+    s3_root: &RootTarget,
+    result: &mut TimelineAnalysis,
+    id: &TenantShardTimelineId,
+    tenant_objects: &mut TenantObjectListing,
+    s3_data: Option<S3TimelineBlobData>,
+    console_deleted: Option<bool>,
+    options: &ScrubOptions,
+) {
+    match s3_data {
+        Some(s3_data) => {
+            result.garbage_keys.extend(s3_data.keys_to_remove);
+
+            if let Some(example) = s3_data.unrecognized_layer_keys.first() {
+                result.warning(CheckCode::UnparseableLayerFormat, format!(
+                    "Timeline has {} objects that look like layers in a format this build cannot parse, e.g. {example}",
+                    s3_data.unrecognized_layer_keys.len()
+                ));
+            }
+
+            result.extend(
+                Severity::Warning,
+                CheckCode::IndexSchemaViolation,
+                s3_data
+                    .index_schema_violations
+                    .into_iter()
+                    .map(|violation| format!("index_part.json does not match the schema: {violation}"))
+                    .collect(),
+            );
+            for layer in &s3_data.duplicate_index_entries {
+                result.warning(CheckCode::DuplicateIndexEntry, format!(
+                    "index_part.json has more than one entry for layer {layer}: all but the last were lost in parsing"
+                ));
+            }
+            result.extend(
+                Severity::Error,
+                CheckCode::DiskConsistentLsnRegression,
+                check_index_history(&s3_data.index_history),
+            );
+            if let Some(max_drop_percent) = options.max_layer_count_drop_percent {
+                result.extend(
+                    Severity::Warning,
+                    CheckCode::LayerCountDrop,
+                    check_layer_count_history(&s3_data.index_history, max_drop_percent),
+                );
+            }
+
+            for key in &s3_data.duplicate_layer_keys {
+                result.error(CheckCode::CaseDuplicateLayerKey, format!(
+                    "Layer object {key} differs only in casing from another layer object of this timeline"
+                ));
+            }
+
+            if let Some(policy) = &options.preserved_initdb {
+                result.extend(
+                    Severity::Error,
+                    CheckCode::PreservedInitdbArchive,
+                    check_preserved_initdb_archive(
+                        s3_client,
+                        s3_root.bucket_name(),
+                        id,
+                        s3_data.initdb_preserved_archive.as_ref(),
+                        policy,
+                        &options.retry_policy,
+                    )
+                    .await,
+                );
+            }
+
+            match s3_data.blob_data {
+                BlobDataParseResult::Parsed {
+                    index_part,
+                    index_part_generation,
+                    mut s3_layers,
+                } => {
+                    result.stats = Some(TimelineStats {
+                        layer_count: index_part.layer_metadata.len(),
+                        layer_bytes: index_part.layer_metadata.values().map(|m| m.file_size).sum(),
+                        disk_consistent_lsn: index_part.metadata.disk_consistent_lsn(),
+                        fingerprint: Some(timeline_fingerprint(&index_part)),
+                    });
+
+                    // Narrowed down to a key range, only the layers overlapping it are checked
+                    let key_filter = options.key_range_filter.as_ref();
+                    if let Some(filter) = key_filter {
+                        s3_layers.retain(|(layer, _), _| filter.overlaps(layer));
+                    }
+                    result.merge(match key_filter {
+                        Some(filter) if filter.filter_layer_map_checks => {
+                            let mut filtered = (*index_part).clone();
+                            filtered
+                                .layer_metadata
+                                .retain(|layer, _| filter.overlaps(layer));
+                            check_index_self_consistency(id, &filtered, options)
+                        }
+                        _ => check_index_self_consistency(id, &index_part, options),
+                    });
+
+                    if let Some(index_part_size) = s3_data.index_part_size {
+                        result.extend(
+                            Severity::Warning,
+                            CheckCode::UndersizedIndex,
+                            check_index_size(
+                                index_part_size,
+                                index_part.layer_metadata.len(),
+                                options.min_index_bytes_per_layer,
+                            ),
+                        );
+                    }
+
+                    // A live index alongside a preserved initdb archive is also what a deletion
+                    // that stopped part way leaves behind
+                    if index_part.deleted_at.is_none()
+                        && s3_data.initdb_preserved_archive.is_some()
+                    {
+                        result.warning(
+                            CheckCode::IncompleteDeletion,
+                            "Timeline has a preserved initdb archive alongside a live index_part.json: its deletion may have stopped part way, and need to be resumed".to_string()
+                        )
+                    }
+
+                    let shard_index = id.tenant_shard_id.to_index();
+                    result.reconciliation.present_unreferenced = s3_layers
+                        .keys()
+                        .filter(|(layer, generation)| {
+                            index_part
+                                .layer_metadata
+                                .get(layer)
+                                .map_or(true, |metadata| {
+                                    metadata.generation != *generation
+                                        || metadata.shard != shard_index
+                                })
+                        })
+                        .count();
+
+                    // An older generation of a layer that the index references under a newer one
+                    // was superseded, and is reclaimable unless another shard's index references
+                    // it.  Newer generations are not reported: their index may not be written yet.
+                    let mut superseded = s3_layers.keys().filter(|(layer, generation)| {
+                        index_part
+                            .layer_metadata
+                            .get(layer)
+                            .is_some_and(|metadata| {
+                                metadata.shard == shard_index && *generation < metadata.generation
+                            })
+                    });
+                    if let Some((layer, generation)) = superseded.next() {
+                        result.warning(CheckCode::SupersededLayerGeneration, format!(
+                            "Timeline has {} layer(s) in older generations than the index references, e.g. {layer}{}",
+                            superseded.count() + 1,
+                            generation.get_suffix()
+                        ));
+                    }
+
+                    result.extend(
+                        Severity::Error,
+                        CheckCode::LayerNewerThanIndex,
+                        check_layers_newer_than_index(
+                            &index_part,
+                            index_part_generation,
+                            shard_index,
+                            &s3_layers,
+                        ),
+                    );
+
+                    result.extend(
+                        Severity::Error,
+                        CheckCode::LayerGenerationMismatch,
+                        check_layer_generations(&index_part, shard_index, &s3_layers),
+                    );
+
+                    result.extend(
+                        Severity::Warning,
+                        CheckCode::LayerBeyondConsistentLsn,
+                        check_layers_beyond_consistent_lsn(
+                            s3_layers.keys(),
+                            index_part.metadata.disk_consistent_lsn(),
+                        ),
+                    );
+
+                    if let Some(pageserver_layers) = options
+                        .pageserver_layers
+                        .as_ref()
+                        .and_then(|layers| layers.get(id))
+                    {
+                        result.extend(
+                            Severity::Warning,
+                            CheckCode::PageserverLayerMismatch,
+                            check_pageserver_layers(
+                                pageserver_layers,
+                                &index_part.layer_metadata,
+                                &s3_layers,
+                            ),
+                        );
+                    }
+
+                    // The listing answers for most layers without any further requests: only
+                    // apparent misses are checked individually, because the layer may have been
+                    // uploaded (along with the index) after the timeline was listed.
+                    let mut layers = Vec::with_capacity(index_part.layer_metadata.len());
+                    for (layer, metadata) in index_part.layer_metadata {
+                        if key_filter.is_some_and(|filter| !filter.overlaps(&layer)) {
+                            // Still counted as referenced, so that it is not taken for an orphan
+                            tenant_objects.check_ref(id.timeline_id, &layer, &metadata);
+                            result.layers_outside_key_filter += 1;
+                            continue;
+                        }
+                        let unlisted_key =
+                            if tenant_objects.check_ref(id.timeline_id, &layer, &metadata) {
+                                None
+                            } else {
+                                Some(layer_object_key(s3_root, id, &layer, &metadata))
+                            };
+                        layers.push((layer, metadata, unlisted_key));
+                    }
+                    let mut unlisted_exist = head_objects_batch(
+                        s3_client,
+                        s3_root.bucket_name(),
+                        layers.iter().filter_map(|(_, _, key)| key.clone()),
+                        options.concurrency,
+                        &options.retry_policy,
+                    )
+                    .await;
+
+                    // The tag and content checks go over the layers again
+                    let keep_layers = options.report_layers
+                        || options.deletion_tag.is_some()
+                        || options.deep_scrub;
+                    for (layer, metadata, unlisted_key) in layers {
+                        let head_result =
+                            unlisted_key.and_then(|key| unlisted_exist.remove(&key));
+                        let present = match head_result {
+                            None => true,
+                            Some(Ok(exists)) => {
+                                if exists {
+                                    info!(
+                                        "Layer {layer}{} was not listed but exists: likely uploaded during the scan",
+                                        metadata.generation.get_suffix()
+                                    );
+                                }
+                                exists
+                            }
+                            Some(Err(e)) => {
+                                result.error(CheckCode::LayerExistenceUnknown, format!(
+                                    "Failed to check existence of layer {layer}{}: {e:#}",
+                                    metadata.generation.get_suffix()
+                                ));
+                                false
+                            }
+                        };
+                        if present {
+                            result.reconciliation.referenced_present += 1;
+                        } else {
+                            result.reconciliation.referenced_missing += 1;
+                            if !is_l0(&layer) {
+                                result.missing_non_l0_layers += 1;
+                            }
+                            result.error(CheckCode::MissingLayer, format!(
+                                "index_part.json contains a layer {}{} (shard {}) that is not present in remote storage",
+                                layer,
+                                metadata.generation.get_suffix(),
+                                metadata.shard
+                            ));
+
+                            // The object may be where its key says another shard owns it: then
+                            // the index and the object keys disagree about the layer's shard
+                            let listed_shards = tenant_objects.listed_shards(
+                                id.timeline_id,
+                                &layer,
+                                metadata.generation,
+                            );
+                            if !listed_shards.is_empty() {
+                                result.error(CheckCode::LayerShardMismatch, format!(
+                                    "index_part.json says layer {}{} belongs to shard {}, but it is only found under shard(s) {}",
+                                    layer,
+                                    metadata.generation.get_suffix(),
+                                    metadata.shard,
+                                    itertools::join(&listed_shards, ", ")
+                                ))
+                            }
+                        }
+
+                        if keep_layers {
+                            let listed_size =
+                                tenant_objects.listed_size(id.timeline_id, &layer, &metadata);
+                            result.layers.push(LayerReport {
+                                name: layer,
+                                metadata,
+                                present,
+                                listed_size,
+                            });
+                        }
+                    }
+
+                    info!(
+                        "Layers referenced and present: {}, referenced but missing: {}, present but unreferenced: {}",
+                        result.reconciliation.referenced_present,
+                        result.reconciliation.referenced_missing,
+                        result.reconciliation.present_unreferenced
+                    );
+
+                    if let Some(deletion_tag) = &options.deletion_tag {
+                        let (tagged, failures) = check_deletion_tags(
+                            s3_client,
+                            s3_root,
+                            id,
+                            &result.layers,
+                            deletion_tag,
+                            options,
+                        )
+                        .await;
+                        result.extend(Severity::Error, CheckCode::LayerTaggedForDeletion, tagged);
+                        result.extend(Severity::Warning, CheckCode::LayerTagsUnknown, failures);
+                    }
+
+                    if options.deep_scrub {
+                        let (mismatches, skipped) =
+                            deep_scrub_layers(s3_client, s3_root, id, &result.layers, options)
+                                .await;
+                        result.extend(Severity::Error, CheckCode::LayerContentMismatch, mismatches);
+                        if skipped > 0 {
+                            result.warning(CheckCode::DeepScrubBudgetSpent, format!(
+                                "Deep scrub skipped {skipped} layers: the budget of {} bytes is spent",
+                                options.deep_scrub_budget.limit().unwrap_or_default()
+                            ));
+                        }
+                    }
+                }
+                BlobDataParseResult::Relic => {}
+                BlobDataParseResult::Orphan {
+                    prefix,
+                    object_count,
+                } => result.error(
+                    CheckCode::MissingIndex,
+                    format!(
+                        "Timeline prefix {prefix} contains {object_count} objects but no index_part.json"
+                    ),
+                ),
+                BlobDataParseResult::Incorrect(parse_errors) => result.extend(
+                    Severity::Error,
+                    CheckCode::ParseError,
+                    parse_errors
+                        .into_iter()
+                        .map(|error| format!("parse error: {error}"))
+                        .collect(),
+                ),
+                BlobDataParseResult::ListingFailed(error) => result.error(
+                    CheckCode::ListingFailed,
+                    format!("Failed to list the timeline's objects: {error}"),
+                ),
+            }
+        }
+        None => match console_deleted {
+            None => result.error(
+                CheckCode::NoData,
+                "Timeline has no data on S3 at all".to_string(),
+            ),
+            Some(true) => result.warning(
+                CheckCode::NoData,
+                "Timeline has no data on S3 at all, as expected for a branch deleted in the console"
+                    .to_string(),
+            ),
+            Some(false) => result.error(
+                CheckCode::NoData,
+                "Timeline has no data on S3 at all, but its branch is live in the console: data loss"
+                    .to_string(),
+            ),
+        },
+    }
+}
+
+/// A preserved initdb archive is what a deleted timeline is recovered from.  Check that a timeline
+/// the policy says should have one does, and if asked, that the archive is a complete zstd stream.
+async fn check_preserved_initdb_archive(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    id: &TenantShardTimelineId,
+    archive: Option<&ListingObject>,
+    policy: &PreservedInitdbPolicy,
+    retry_policy: &RetryPolicy,
+) -> Vec<String> {
+    let Some(archive) = archive else {
+        return if (policy.should_have_archive)(id) {
+            vec!["Timeline should have a preserved initdb archive, but none was listed".to_string()]
+        } else {
+            Vec::new()
+        };
+    };
+    if !policy.verify_integrity {
+        return Vec::new();
+    }
+    if archive.size == 0 {
+        return vec![format!("Preserved initdb archive {} is empty", archive.key)];
+    }
+
+    let bytes = match download_object_with_retries(
+        s3_client,
+        bucket_name,
+        &archive.key,
+        None,
+        retry_policy,
+    )
+    .await
+    {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return vec![format!(
+                "Failed to download preserved initdb archive {}: {e:#}",
+                archive.key
+            )]
+        }
+    };
+    // Decompressing the whole stream checks every frame, not just the header
+    let mut decoder = ZstdDecoder::new(bytes.as_slice());
+    match tokio::io::copy(&mut decoder, &mut tokio::io::sink()).await {
+        Ok(_) => Vec::new(),
+        Err(e) => vec![format!(
+            "Preserved initdb archive {} does not decompress: {e}",
+            archive.key
+        )],
+    }
+}
+
+/// The checks of a timeline's index that need nothing but the index itself: no object is listed
+/// or downloaded, so these can run on indices dumped from elsewhere, e.g. from CI, given the
+/// timeline shard the index belongs to.  The checks of the index against the objects in storage
+/// build on these.
+pub fn check_index_self_consistency(
+    id: &TenantShardTimelineId,
+    index_part: &IndexPart,
+    options: &ScrubOptions,
+) -> TimelineAnalysis {
+    let mut result = TimelineAnalysis::new(*id, options);
+
+    if !IndexPart::KNOWN_VERSIONS.contains(&index_part.get_version()) {
+        result.error(
+            CheckCode::UnknownIndexVersion,
+            format!("index_part.json version: {}", index_part.get_version()),
+        )
+    }
+
+    // The `index_version_window` newest known versions are considered current: anything
+    // older is reported, so that we can track progress of version migrations.  A window of 0
+    // still counts the latest version as current.
+    let index_version_window = std::cmp::max(options.index_version_window, 1);
+    if !IndexPart::KNOWN_VERSIONS
+        .iter()
+        .rev()
+        .take(index_version_window)
+        .any(|v| *v == index_part.get_version())
+    {
+        result.push(
+            options.old_index_version_severity,
+            CheckCode::OldIndexVersion,
+            format!(
+                "index_part.json version is not among the {} latest: {}",
+                index_version_window,
+                index_part.get_version()
+            ),
+        )
+    }
+
+    // Timeline deletion marks the index, deletes the layers, deletes the index and
+    // finally leaves the initdb archive(s) behind.  Anything in between means that a
+    // deletion stopped part way, and must be resumed.
+    if let Some(deleted_at) = index_part.deleted_at {
+        result.warning(CheckCode::IncompleteDeletion, format!(
+            "index_part.json is marked deleted at {deleted_at}, but the timeline deletion did not complete and needs to be resumed"
+        ))
+    }
+
+    if index_part.metadata.disk_consistent_lsn() != index_part.get_disk_consistent_lsn() {
+        result.error(
+            CheckCode::DiskConsistentLsnMismatch,
+            format!(
+                "Mismatching disk_consistent_lsn in TimelineMetadata ({}) and in the index_part ({})",
+                index_part.metadata.disk_consistent_lsn(),
+                index_part.get_disk_consistent_lsn(),
+            ),
+        )
+    }
+
+    result.extend(
+        Severity::Error,
+        CheckCode::IndexIdentityMismatch,
+        check_index_identity(id, index_part),
+    );
+    if let Some(stripe_size) = options.shard_stripe_size {
+        result.extend(
+            Severity::Error,
+            CheckCode::LayerOutsideStripes,
+            check_layer_stripes(id, index_part, stripe_size),
+        );
+    }
+    // Each split leaves the layers written before it under the ancestor shard's prefix, so a
+    // timeline references layers of a few shards: far more than its split history explains slows
+    // its reads down.
+    if let Some(max_shards) = options.max_layer_shards {
+        let shards: HashSet<ShardIndex> = index_part
+            .layer_metadata
+            .values()
+            .map(|metadata| metadata.shard)
+            .collect();
+        if shards.len() > max_shards {
+            result.warning(
+                CheckCode::LayerShardFanOut,
+                format!(
+                    "index_part.json references layers of {} distinct shards, more than the maximum of {max_shards}",
+                    shards.len()
+                ),
+            );
+        }
+    }
+    if options.check_layer_name_round_trip {
+        result.extend(
+            Severity::Warning,
+            CheckCode::LayerNameRoundTrip,
+            check_layer_name_round_trip(index_part.layer_metadata.keys()),
+        );
+    }
+    let by_key_range = layers_by_key_range(index_part.layer_metadata.keys());
+    result.extend(
+        Severity::Warning,
+        CheckCode::DuplicatedLayerData,
+        check_layer_footprints(&by_key_range),
+    );
+    result.extend(
+        Severity::Warning,
+        CheckCode::ImageWithinDelta,
+        check_images_within_deltas(&by_key_range),
+    );
+    result.extend(
+        Severity::Error,
+        CheckCode::DuplicateImageLayer,
+        check_duplicate_images(index_part.layer_metadata.keys()),
+    );
+    if let Some(partition_size) = options.image_partition_size {
+        result.extend(
+            Severity::Warning,
+            CheckCode::MisalignedImageLayer,
+            check_image_layer_alignment(index_part.layer_metadata.keys(), partition_size),
+        );
+    }
+    if let Some(min_timeline_size) = options.full_keyspace_min_timeline_size {
+        result.extend(
+            Severity::Warning,
+            CheckCode::FullKeyspaceLayer,
+            check_full_keyspace_layers(&index_part.layer_metadata, min_timeline_size),
+        );
+    }
+    if let Some(max_depth) = options.max_l0_stack_depth {
+        let depth = l0_stack_depth(index_part.layer_metadata.keys());
+        if depth > max_depth {
+            result.warning(
+                CheckCode::DeepL0Stack,
+                format!(
+                    "Reads may visit up to {depth} L0 layers, more than the maximum of {max_depth}"
+                ),
+            );
+        }
+    }
+    let violations = check_valid_layermap(index_part.layer_metadata.keys());
+    result.layer_map_valid = violations.is_empty();
+    result.layer_map_violations = violations.len();
+    result.extend(Severity::Error, CheckCode::InvalidLayerMap, violations);
+    if let Some(gc_horizon) = options.gc_horizon {
+        result.extend(
+            Severity::Error,
+            CheckCode::GcHorizonGap,
+            check_gc_horizon_coverage(index_part.layer_metadata.keys(), gc_horizon),
+        );
+    }
+    result.extend(
+        Severity::Error,
+        CheckCode::LayerStackGap,
+        check_layer_stacks(
+            index_part.layer_metadata.keys(),
+            index_part.metadata.disk_consistent_lsn(),
+            index_part.metadata.ancestor_timeline().is_some(),
+        ),
+    );
+
+    if index_part.layer_metadata.is_empty() {
+        match index_part.metadata.ancestor_timeline() {
+            // A branch point is an LSN at which the ancestor has data, which LSN 0 never is: this
+            // combination is what a corrupted index has been seen to look like
+            Some(ancestor_timeline) if index_part.metadata.ancestor_lsn() == Lsn(0) => {
+                result.error(
+                    CheckCode::ZeroAncestorLsn,
+                    format!(
+                        "index_part.json has no layers, and a branch point of LSN 0 on its ancestor {ancestor_timeline}"
+                    ),
+                )
+            }
+            // not an error, can happen for branches with zero writes, but notice that
+            _ => info!("index_part.json has no layers"),
+        }
+    }
+
+    // A child timeline is consistent at least up to its branch point from the
+    // moment it is created: a zero disk_consistent_lsn is what a failed branch
+    // creation leaves behind.
+    if let Some(ancestor_timeline) = index_part.metadata.ancestor_timeline() {
+        if index_part.metadata.disk_consistent_lsn() == Lsn(0) {
+            result.error(CheckCode::ZeroDiskConsistentLsn, format!(
+                "Child timeline of {ancestor_timeline} (branched at {}) has a disk_consistent_lsn of 0",
+                index_part.metadata.ancestor_lsn()
+            ));
+        }
+    }
+
+    result.extend(
+        Severity::Info,
+        CheckCode::NoOwnLayers,
+        check_own_layers(index_part),
+    );
+
+    for (layer, metadata) in &index_part.layer_metadata {
+        if metadata.file_size == 0 {
+            result.error(
+                CheckCode::ZeroSizeLayer,
+                format!(
+                    "index_part.json contains a layer {} that has 0 size in its layer metadata",
+                    layer,
+                ),
+            )
+        }
+        if let Some(max_layer_size) = options.max_layer_size {
+            if metadata.file_size > max_layer_size {
+                result.warning(CheckCode::OversizedLayer, format!(
+                    "index_part.json contains a layer {layer} of {} bytes, more than the maximum of {max_layer_size}",
+                    metadata.file_size
+                ))
+            }
+        }
+    }
+
+    result
+}
+
+/// Each generation's index should be consistent at least as far as the previous generation's: a
+/// disk_consistent_lsn that goes backwards means that a pageserver started from an older state than
+/// its predecessor had published, rolling the timeline back.
+fn check_index_history(history: &[IndexHistoryEntry]) -> Vec<String> {
+    history
+        .windows(2)
+        .filter(|pair| pair[1].disk_consistent_lsn < pair[0].disk_consistent_lsn)
+        .map(|pair| {
+            let (prev, next) = (pair[0], pair[1]);
+            format!(
+                "disk_consistent_lsn went back from {} in generation {:?} to {} in generation {:?}",
+                prev.disk_consistent_lsn,
+                prev.generation,
+                next.disk_consistent_lsn,
+                next.generation
+            )
+        })
+        .collect()
+}
+
+/// Compaction replaces many small layers with fewer large ones, so from one generation to the
+/// next the layer count can fall a long way, but the bytes of layers referenced do not fall with
+/// it.  Both falling by more than `max_drop_percent` looks like layers dropped from the index
+/// wholesale, e.g. by an accidental mass delete, rather than rewritten.
+fn check_layer_count_history(history: &[IndexHistoryEntry], max_drop_percent: u32) -> Vec<String> {
+    // Whether `next` is less than `prev` by more than the allowed percentage of `prev`
+    let dropped = |prev: u64, next: u64| {
+        (prev.saturating_sub(next) as u128) * 100 > prev as u128 * max_drop_percent as u128
+    };
+    history
+        .windows(2)
+        .filter(|pair| {
+            let (prev, next) = (pair[0], pair[1]);
+            dropped(prev.layer_count as u64, next.layer_count as u64)
+                && dropped(prev.layer_bytes, next.layer_bytes)
+        })
+        .map(|pair| {
+            let (prev, next) = (pair[0], pair[1]);
+            format!(
+                "Layer count dropped from {} in generation {:?} to {} in generation {:?}, with layer bytes down from {} to {} and index_part.json from {} to {} bytes",
+                prev.layer_count,
+                prev.generation,
+                next.layer_count,
+                next.generation,
+                prev.layer_bytes,
+                next.layer_bytes,
+                prev.index_bytes,
+                next.index_bytes
+            )
+        })
+        .collect()
+}
+
+/// A layer that the bucket's lifecycle tooling tagged for deletion while an index still
+/// references it is about to be lost.  Returns the tagged layers, and the layers whose tags could
+/// not be got.
+async fn check_deletion_tags(
+    s3_client: &StorageClient,
+    s3_root: &RootTarget,
+    id: &TenantShardTimelineId,
+    layers: &[LayerReport],
+    deletion_tag: &ObjectTag,
+    options: &ScrubOptions,
+) -> (Vec<String>, Vec<String>) {
+    let keys = layers
+        .iter()
+        .filter(|layer| layer.present)
+        .map(|layer| layer_object_key(s3_root, id, &layer.name, &layer.metadata));
+    let tags = get_object_tags_batch(
+        s3_client,
+        s3_root.bucket_name(),
+        keys,
+        options.concurrency,
+        &options.retry_policy,
+    )
+    .await;
+
+    let mut tagged = Vec::new();
+    let mut failures = Vec::new();
+    for (key, tags) in tags {
+        match tags {
+            Ok(tags) if tags.get(&deletion_tag.key) == Some(&deletion_tag.value) => {
+                tagged.push(format!(
+                    "Layer object {key} is referenced by index_part.json, but tagged {deletion_tag} for deletion"
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => failures.push(format!("Failed to get the tags of layer object {key}: {e:#}")),
+        }
+    }
+    tagged.sort();
+    failures.sort();
+    (tagged, failures)
+}
+
+/// Download the summary block of every present layer, and check it against what the layer's name
+/// and the index say about it: the lightweight checks trust the listing.  Only the first block of
+/// each layer is downloaded, and charged to the deep scrub byte budget: layers are checked while
+/// it lasts.
+///
+/// Returns the mismatches found, and how many layers were skipped for lack of budget.
+async fn deep_scrub_layers(
+    s3_client: &StorageClient,
+    s3_root: &RootTarget,
+    id: &TenantShardTimelineId,
+    layers: &[LayerReport],
+    options: &ScrubOptions,
+) -> (Vec<String>, usize) {
+    let mut skipped = 0;
+    let mut to_download = Vec::new();
+    for layer in layers.iter().filter(|layer| layer.present) {
+        if options
+            .deep_scrub_budget
+            .try_spend(std::cmp::min(layer.metadata.file_size, PAGE_SZ as u64))
+        {
+            to_download.push(layer);
+        } else {
+            skipped += 1;
+        }
+    }
+
+    let mismatches = futures_util::stream::iter(to_download)
+        .map(|layer| async move {
+            let key = layer_object_key(s3_root, id, &layer.name, &layer.metadata);
+            match download_object_prefix_with_retries(
+                s3_client,
+                s3_root.bucket_name(),
+                &key,
+                PAGE_SZ as u64,
+                &options.retry_policy,
+            )
+            .await
+            {
+                Ok((summary_block, object_size)) => {
+                    // The listing's size, where there is one, is what the other checks went by
+                    let Some(size) = layer.listed_size.or(object_size) else {
+                        return Some(format!("Layer {key}: its size is unknown"));
+                    };
+                    check_layer_content(id, &layer.name, &layer.metadata, size, &summary_block)
+                        .err()
+                        .map(|e| format!("Layer {key}: {e}"))
+                }
+                Err(e) => Some(format!("Failed to download layer {key}: {e:#}")),
+            }
+        })
+        .buffer_unordered(options.concurrency)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+
+    (mismatches, skipped)
+}
+
+/// A layer's summary header repeats the tenant, timeline and ranges that its name encodes: a
+/// mismatch means the object holds some other layer's data, or garbage.  `bytes` is the start of
+/// the layer's object, at least its summary block, and `object_size` the size of the whole object.
+fn check_layer_content(
+    id: &TenantShardTimelineId,
+    layer: &LayerName,
+    metadata: &LayerFileMetadata,
+    object_size: u64,
+    bytes: &[u8],
+) -> Result<(), String> {
+    if object_size != metadata.file_size {
+        return Err(format!(
+            "object is {object_size} bytes, but index_part.json says {}",
+            metadata.file_size
+        ));
+    }
+
+    let (magic_ok, tenant_id, timeline_id, ranges_ok) = match layer {
+        LayerName::Delta(delta) => {
+            let summary = delta_layer::Summary::des_prefix(bytes)
+                .map_err(|e| format!("unreadable summary: {e}"))?;
+            (
+                summary.magic == DELTA_FILE_MAGIC,
+                summary.tenant_id,
+                summary.timeline_id,
+                summary.key_range == delta.key_range && summary.lsn_range == delta.lsn_range,
+            )
+        }
+        LayerName::Image(image) => {
+            let summary = image_layer::Summary::des_prefix(bytes)
+                .map_err(|e| format!("unreadable summary: {e}"))?;
+            (
+                summary.magic == IMAGE_FILE_MAGIC,
+                summary.tenant_id,
+                summary.timeline_id,
+                summary.key_range == image.key_range && summary.lsn == image.lsn,
+            )
+        }
+    };
+
+    if !magic_ok {
+        Err("summary has the wrong magic for its kind of layer".to_string())
+    } else if tenant_id != id.tenant_shard_id.tenant_id || timeline_id != id.timeline_id {
+        Err(format!(
+            "summary belongs to tenant {tenant_id} timeline {timeline_id}"
+        ))
+    } else if !ranges_ok {
+        Err("summary key or LSN range differs from the layer name".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// The path, relative to the pageserver's remote storage root, at which a layer referenced by a
+/// timeline's index is expected: under the prefix of the shard that owns the layer, which may be
+/// an ancestor shard, and suffixed with the generation that wrote it.  This is the path logic of
+/// the pageserver itself, for tooling that must agree with the scrubber about where layers live.
+pub fn expected_layer_path(
+    id: &TenantShardTimelineId,
+    layer: &LayerName,
+    metadata: &LayerFileMetadata,
+) -> RemotePath {
+    remote_layer_path(
+        &id.tenant_shard_id.tenant_id,
+        &id.timeline_id,
+        metadata.shard,
+        layer,
+        metadata.generation,
+    )
+}
+
+/// The object key of a layer referenced by a timeline's index.
+fn layer_object_key(
+    s3_root: &RootTarget,
+    id: &TenantShardTimelineId,
+    layer: &LayerName,
+    metadata: &LayerFileMetadata,
+) -> String {
+    // The layer lives under the prefix of the shard that owns it, which may be an ancestor shard
+    let owner = TenantShardTimelineId::new(
+        TenantShardId {
+            tenant_id: id.tenant_shard_id.tenant_id,
+            shard_number: metadata.shard.shard_number,
+            shard_count: metadata.shard.shard_count,
+        },
+        id.timeline_id,
+    );
+    format!(
+        "{}{}{}",
+        s3_root.timeline_root(&owner).prefix_in_bucket,
+        layer,
+        metadata.generation.get_suffix()
+    )
+}
+
+/// An index_part does not record the tenant shard or timeline it belongs to, but it does carry
+/// some hints (layer shard ownership, ancestor timeline) that must agree with the prefix it was
+/// found under.  A mismatch suggests that the index was uploaded to the wrong prefix.
+fn check_index_identity(id: &TenantShardTimelineId, index_part: &IndexPart) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if index_part.metadata.ancestor_timeline() == Some(id.timeline_id) {
+        errors.push(format!(
+            "index_part.json found under timeline {} names that same timeline as its ancestor",
+            id.timeline_id
+        ));
+    }
+
+    // Layers may be inherited from ancestor shards with a lower shard count, but never from
+    // a shard with a higher count, nor from a sibling shard with the same count.
+    let shard_index = id.tenant_shard_id.to_index();
+    let mut foreign_layers = index_part.layer_metadata.iter().filter(|(_, metadata)| {
+        if metadata.shard.shard_count == shard_index.shard_count {
+            metadata.shard != shard_index
+        } else {
+            metadata.shard.shard_count > shard_index.shard_count
+        }
+    });
+    if let Some((layer, metadata)) = foreign_layers.next() {
+        errors.push(format!(
+            "index_part.json found under shard {} references {} layer(s) owned by other shards, e.g. {} (shard {})",
+            shard_index,
+            foreign_layers.count() + 1,
+            layer,
+            metadata.shard
+        ));
+    }
+
+    errors
+}
+
+/// An index published at generation G can only reference layers that generations up to G wrote.
+/// A referenced layer of a newer generation that is present in storage is a torn publish: the
+/// layer landed, but the index chosen is not the one that references it, so a newer index may
+/// exist that the scan failed to choose.  Layers of other shards are not listed here, so are left
+/// out.
+fn check_layers_newer_than_index(
+    index_part: &IndexPart,
+    index_part_generation: Generation,
+    shard_index: ShardIndex,
+    s3_layers: &HashMap<(LayerName, Generation), u64>,
+) -> Vec<String> {
+    let mut errors: Vec<String> = index_part
+        .layer_metadata
+        .iter()
+        .filter(|(layer, metadata)| {
+            metadata.shard == shard_index
+                && metadata.generation > index_part_generation
+                && s3_layers.contains_key(&((*layer).clone(), metadata.generation))
+        })
+        .map(|(layer, metadata)| {
+            format!(
+                "index_part.json of generation {index_part_generation:?} references layer {layer}{} of newer generation {:?}, which is present in storage",
+                metadata.generation.get_suffix(),
+                metadata.generation
+            )
+        })
+        .collect();
+    errors.sort();
+    errors
+}
+
+/// The generation suffix of a layer's object key and the generation the index records for that
+/// layer are two accounts of the same thing.  A layer that the index references at one
+/// generation, but that storage only has under others, is one they disagree about: the index may
+/// be pointing at a layer that was never uploaded under its generation, or the object may have
+/// been written by a generation that should not have.  Layers of other shards are not listed
+/// here, so are left out.
+fn check_layer_generations(
+    index_part: &IndexPart,
+    shard_index: ShardIndex,
+    s3_layers: &HashMap<(LayerName, Generation), u64>,
+) -> Vec<String> {
+    let mut listed_generations: HashMap<&LayerName, Vec<Generation>> = HashMap::new();
+    for (layer, generation) in s3_layers.keys() {
+        listed_generations
+            .entry(layer)
+            .or_default()
+            .push(*generation);
+    }
+
+    let mut errors = Vec::new();
+    for (layer, metadata) in &index_part.layer_metadata {
+        if metadata.shard != shard_index {
+            continue;
+        }
+        let Some(generations) = listed_generations.get_mut(layer) else {
+            continue;
+        };
+        if generations.contains(&metadata.generation) {
+            continue;
+        }
+        generations.sort();
+        errors.push(format!(
+            "index_part.json references layer {layer} at generation {:?}, but its objects in storage are of generation(s) {generations:?}",
+            metadata.generation
+        ));
+    }
+    errors.sort();
+    errors
+}
+
+/// Reconcile the layers a pageserver reports having for a timeline with its index and with the
+/// objects in storage: each of the three can drift from the others without noticing.  A layer
+/// only the pageserver has was never uploaded, or was lost from storage; one in the index that the
+/// pageserver lacks was forgotten by it.
+fn check_pageserver_layers(
+    pageserver_layers: &HashSet<(LayerName, Generation)>,
+    layer_metadata: &HashMap<LayerName, LayerFileMetadata>,
+    s3_layers: &HashMap<(LayerName, Generation), u64>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (layer, generation) in pageserver_layers {
+        let referenced = layer_metadata
+            .get(layer)
+            .is_some_and(|metadata| metadata.generation == *generation);
+        if referenced {
+            continue;
+        }
+        if s3_layers.contains_key(&(layer.clone(), *generation)) {
+            errors.push(format!(
+                "Pageserver has layer {layer}{}, which is in storage but not in index_part.json",
+                generation.get_suffix()
+            ));
+        } else {
+            errors.push(format!(
+                "Pageserver has layer {layer}{}, which is in neither index_part.json nor storage",
+                generation.get_suffix()
+            ));
+        }
+    }
+    for (layer, metadata) in layer_metadata {
+        if !pageserver_layers.contains(&(layer.clone(), metadata.generation)) {
+            errors.push(format!(
+                "index_part.json references layer {layer}{} (shard {}), which the pageserver does not have",
+                metadata.generation.get_suffix(),
+                metadata.shard
+            ));
+        }
+    }
+    errors
+}
+
+/// Remote paths are built from the string form of layer names, so a name that does not parse back
+/// to itself would send the checks looking for its layer under the wrong key.
+fn check_layer_name_round_trip<'a>(layers: impl Iterator<Item = &'a LayerName>) -> Vec<String> {
+    layers
+        .filter_map(|layer| {
+            let name = layer.to_string();
+            match name.parse::<LayerName>() {
+                Ok(parsed) if &parsed == layer => None,
+                Ok(parsed) => Some(format!(
+                    "Layer name {name} parses back to a different layer, {parsed}"
+                )),
+                Err(e) => Some(format!("Layer name {name} does not parse back: {e}")),
+            }
+        })
+        .collect()
+}
+
+/// Every layer an index references takes at least `min_bytes_per_layer` to serialize, so an index
+/// smaller than that allows for is suspect, whatever its body parsed as: this has been seen of
+/// truncated indices.  A heuristic, so only ever a warning.
+fn check_index_size(
+    index_part_size: u64,
+    layer_count: usize,
+    min_bytes_per_layer: u64,
+) -> Vec<String> {
+    let expected_size = layer_count as u64 * min_bytes_per_layer;
+    if index_part_size < expected_size {
+        vec![format!(
+            "index_part.json is {index_part_size} bytes, too small for the {layer_count} layers it references (at least {expected_size} bytes): it may be truncated"
+        )]
+    } else {
+        Vec::new()
+    }
+}
+
+/// How far past its branch point a child timeline's disk_consistent_lsn must be for it to be
+/// expected to have written layers of its own: a WAL segment
+const OWN_LAYERS_MIN_LSN_ADVANCE: u64 = 16 * 1024 * 1024;
+
+/// A child timeline reads everything up to its branch point from its ancestor, so only layers
+/// with data above the branch point are its own.  One without any is a pure pointer at its
+/// ancestor: valid, but if its disk_consistent_lsn is well past the branch point, it should have
+/// written something.
+fn check_own_layers(index_part: &IndexPart) -> Vec<String> {
+    let Some(ancestor_timeline) = index_part.metadata.ancestor_timeline() else {
+        return Vec::new();
+    };
+    let ancestor_lsn = index_part.metadata.ancestor_lsn();
+    let disk_consistent_lsn = index_part.metadata.disk_consistent_lsn();
+    if disk_consistent_lsn.0 < ancestor_lsn.0.saturating_add(OWN_LAYERS_MIN_LSN_ADVANCE) {
+        return Vec::new();
+    }
+
+    let has_own_layer = index_part.layer_metadata.keys().any(|layer| match layer {
+        // The end of a delta's LSN range is exclusive
+        LayerName::Delta(delta) => delta.lsn_range.end.0 > ancestor_lsn.0 + 1,
+        LayerName::Image(image) => image.lsn > ancestor_lsn,
+    });
+    if has_own_layer {
+        Vec::new()
+    } else {
+        vec![format!(
+            "Child timeline of {ancestor_timeline} has no layers of its own (branched at {ancestor_lsn}, disk_consistent_lsn {disk_consistent_lsn})"
+        )]
+    }
+}
+
+/// How many keys to sample from a layer's key range before giving it the benefit of the doubt
+const MAX_STRIPE_SAMPLES: usize = 1024;
+
+/// A sharded layer written by this shard should hold at least one key that the shard owns:
+/// sample one key per stripe across each layer's key range, looking for one.
+fn check_layer_stripes(
+    id: &TenantShardTimelineId,
+    index_part: &IndexPart,
+    stripe_size: ShardStripeSize,
+) -> Vec<String> {
+    // Unsharded tenants have no stripes to check
+    if id.tenant_shard_id.shard_count.count() < 2 {
+        return Vec::new();
+    }
+    let shard_identity = match ShardIdentity::new(
+        id.tenant_shard_id.shard_number,
+        id.tenant_shard_id.shard_count,
+        stripe_size,
+    ) {
+        Ok(shard_identity) => shard_identity,
+        Err(e) => return vec![format!("Cannot check layer stripes of shard {id}: {e}")],
+    };
+    let shard_index = id.tenant_shard_id.to_index();
+
+    let mut errors = Vec::new();
+    for (layer, metadata) in &index_part.layer_metadata {
+        if metadata.shard != shard_index {
+            // Inherited from an ancestor shard, which had different stripes
+            continue;
+        }
 
-    /// Healthy-but-noteworthy, like old-versioned structures that are readable but
-    /// worth reporting for awareness that we must not remove that old version decoding
-    /// yet.
-    pub(crate) warnings: Vec<String>,
+        let key_range = match layer {
+            LayerName::Delta(delta) => &delta.key_range,
+            LayerName::Image(image) => &image.key_range,
+        };
+        let mut key = key_range.start;
+        let mut samples = 0;
+        let mut covers_own_key = false;
+        while key < key_range.end {
+            if samples == MAX_STRIPE_SAMPLES || !shard_identity.is_key_disposable(&key) {
+                covers_own_key = true;
+                break;
+            }
+            samples += 1;
+            // Step to the start of the next stripe
+            key = key.add(stripe_size.0 - key.field6 % stripe_size.0);
+        }
 
-    /// Keys not referenced in metadata: candidates for removal, but NOT NECESSARILY: beware
-    /// of races between reading the metadata and reading the objects.
-    pub(crate) garbage_keys: Vec<String>,
+        if !covers_own_key {
+            errors.push(format!(
+                "Layer {layer} owned by shard {shard_index} covers no keys in that shard's stripes"
+            ));
+        }
+    }
+
+    errors
 }
 
-impl TimelineAnalysis {
-    fn new() -> Self {
-        Self {
-            errors: Vec::new(),
-            warnings: Vec::new(),
-            garbage_keys: Vec::new(),
+/// Delta layers whose key ranges overlap must either have identical LSN ranges, or LSN ranges that
+/// do not overlap at all: anything else cannot be a result of flushing and compaction, and leaves
+/// reads unable to tell which layer holds the newer version of a key.
+fn check_valid_layermap<'a>(layers: impl Iterator<Item = &'a LayerName>) -> Vec<String> {
+    // Group by LSN range, so that the many layers of one compaction are compared only once
+    let mut by_lsn_range: HashMap<&Range<Lsn>, Vec<&DeltaLayerName>> = HashMap::new();
+    for layer in layers {
+        if let LayerName::Delta(delta) = layer {
+            by_lsn_range
+                .entry(&delta.lsn_range)
+                .or_default()
+                .push(delta);
+        }
+    }
+    let mut lsn_ranges = by_lsn_range.keys().copied().collect::<Vec<_>>();
+    lsn_ranges.sort_by_key(|lsn_range| (lsn_range.start, lsn_range.end));
+
+    let mut violations = Vec::new();
+    for (i, lsn_range) in lsn_ranges.iter().enumerate() {
+        for other_lsn_range in &lsn_ranges[i + 1..] {
+            if other_lsn_range.start >= lsn_range.end {
+                break;
+            }
+            for delta in &by_lsn_range[lsn_range] {
+                for other in &by_lsn_range[other_lsn_range] {
+                    if delta.key_range.start < other.key_range.end
+                        && other.key_range.start < delta.key_range.end
+                    {
+                        violations.push(format!(
+                            "Layer map is invalid: delta layers {delta} and {other} overlap in both key and LSN ranges"
+                        ));
+                    }
+                }
+            }
         }
     }
+
+    violations
 }
 
-pub(crate) fn branch_cleanup_and_check_errors(
-    id: &TenantShardTimelineId,
-    tenant_objects: &mut TenantObjectListing,
-    s3_active_branch: Option<&BranchData>,
-    console_branch: Option<BranchData>,
-    s3_data: Option<S3TimelineBlobData>,
-) -> TimelineAnalysis {
-    let mut result = TimelineAnalysis::new();
+/// The delta and image layers over each key range
+type LayersByKeyRange<'a> =
+    HashMap<&'a Range<Key>, (Vec<&'a DeltaLayerName>, Vec<&'a ImageLayerName>)>;
 
-    info!("Checking timeline {id}");
+fn layers_by_key_range<'a>(layers: impl Iterator<Item = &'a LayerName>) -> LayersByKeyRange<'a> {
+    let mut by_key_range: LayersByKeyRange = HashMap::new();
+    for layer in layers {
+        match layer {
+            LayerName::Delta(delta) => by_key_range
+                .entry(&delta.key_range)
+                .or_default()
+                .0
+                .push(delta),
+            LayerName::Image(image) => by_key_range
+                .entry(&image.key_range)
+                .or_default()
+                .1
+                .push(image),
+        }
+    }
+    by_key_range
+}
 
-    if let Some(s3_active_branch) = s3_active_branch {
-        info!(
-            "Checking console status for timeline for branch {:?}/{:?}",
-            s3_active_branch.project_id, s3_active_branch.id
-        );
-        match console_branch {
-            Some(_) => {result.errors.push(format!("Timeline has deleted branch data in the console (id = {:?}, project_id = {:?}), recheck whether it got removed during the check",
-                s3_active_branch.id, s3_active_branch.project_id))
-            },
-            None => {
-                result.errors.push(format!("Timeline has no branch data in the console (id = {:?}, project_id = {:?}), recheck whether it got removed during the check",
-            s3_active_branch.id, s3_active_branch.project_id))
+/// A delta layer and an image layer over the same key range, where the image was taken at the
+/// top of the delta's LSN range, store the same data twice: this is likely a compaction bug.
+fn check_layer_footprints(by_key_range: &LayersByKeyRange) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (deltas, images) in by_key_range.values() {
+        for delta in deltas {
+            for image in images {
+                if image.lsn_as_range().end == delta.lsn_range.end {
+                    warnings.push(format!(
+                        "Delta layer {delta} and image layer {image} cover the same key range up to the same LSN"
+                    ));
+                }
             }
-        };
+        }
     }
 
-    match s3_data {
-        Some(s3_data) => {
-            result.garbage_keys.extend(s3_data.keys_to_remove);
+    warnings
+}
 
-            match s3_data.blob_data {
-                BlobDataParseResult::Parsed {
-                    index_part,
-                    index_part_generation: _index_part_generation,
-                    s3_layers: _s3_layers,
-                } => {
-                    if !IndexPart::KNOWN_VERSIONS.contains(&index_part.get_version()) {
-                        result.errors.push(format!(
-                            "index_part.json version: {}",
-                            index_part.get_version()
-                        ))
-                    }
+/// An image layer taken part way up the LSN range of a delta layer over the same key range is
+/// valid, since reads below the image still need the delta, but the delta's records above the
+/// image are redundant with it: worth a look at whether compaction does unnecessary work.  The
+/// image at the top of the delta's range is left to [`check_layer_footprints`].
+fn check_images_within_deltas(by_key_range: &LayersByKeyRange) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (deltas, images) in by_key_range.values() {
+        for delta in deltas {
+            for image in images {
+                if delta.lsn_range.contains(&image.lsn)
+                    && image.lsn_as_range().end != delta.lsn_range.end
+                {
+                    warnings.push(format!(
+                        "Image layer {image} at LSN {} falls within the LSN range {}..{} of delta layer {delta}, over the same key range {}..{}",
+                        image.lsn,
+                        delta.lsn_range.start,
+                        delta.lsn_range.end,
+                        delta.key_range.start,
+                        delta.key_range.end
+                    ));
+                }
+            }
+        }
+    }
 
-                    if &index_part.get_version() != IndexPart::KNOWN_VERSIONS.last().unwrap() {
-                        result.warnings.push(format!(
-                            "index_part.json version is not latest: {}",
-                            index_part.get_version()
-                        ))
-                    }
+    warnings
+}
 
-                    if index_part.metadata.disk_consistent_lsn()
-                        != index_part.get_disk_consistent_lsn()
-                    {
-                        result.errors.push(format!(
-                            "Mismatching disk_consistent_lsn in TimelineMetadata ({}) and in the index_part ({})",
-                            index_part.metadata.disk_consistent_lsn(),
-                            index_part.get_disk_consistent_lsn(),
-                        ))
-                    }
+/// Image layers over the same keys at different LSNs are normal, the newer superseding the older,
+/// but two at the same LSN over overlapping keys store the keys they share twice.  Two at the same
+/// LSN over the exact same key range would have the same name, so are only ever seen here as one
+/// layer: [`duplicate_layer_entries`] finds those in the raw index.
+fn check_duplicate_images<'a>(layers: impl Iterator<Item = &'a LayerName>) -> Vec<String> {
+    let mut by_lsn: HashMap<Lsn, Vec<&ImageLayerName>> = HashMap::new();
+    for layer in layers {
+        if let LayerName::Image(image) = layer {
+            by_lsn.entry(image.lsn).or_default().push(image);
+        }
+    }
 
-                    if index_part.layer_metadata.is_empty() {
-                        // not an error, can happen for branches with zero writes, but notice that
-                        info!("index_part.json has no layers");
-                    }
+    let mut errors = Vec::new();
+    for images in by_lsn.values_mut() {
+        images.sort_by_key(|image| image.key_range.start);
+        for (i, image) in images.iter().enumerate() {
+            for other in &images[i + 1..] {
+                if other.key_range.start >= image.key_range.end {
+                    break;
+                }
+                errors.push(format!(
+                    "Image layers {image} and {other} at the same LSN overlap in key range: the keys they share are stored twice"
+                ));
+            }
+        }
+    }
+    errors.sort();
+    errors
+}
 
-                    for (layer, metadata) in index_part.layer_metadata {
-                        if metadata.file_size == 0 {
-                            result.errors.push(format!(
-                                "index_part.json contains a layer {} that has 0 size in its layer metadata", layer,
-                            ))
-                        }
+/// Image layers are generated per partition of the key space, and partitions start and end on
+/// block numbers (the last field of a key) that are multiples of the partition size.  An image
+/// layer with a boundary anywhere else, other than at either end of the key space, suggests a
+/// compaction bug.
+fn check_image_layer_alignment<'a>(
+    layers: impl Iterator<Item = &'a LayerName>,
+    partition_size: u32,
+) -> Vec<String> {
+    if partition_size == 0 {
+        return Vec::new();
+    }
+    let aligned =
+        |key: &Key| *key == Key::MIN || *key == Key::MAX || key.field6 % partition_size == 0;
 
-                        if !tenant_objects.check_ref(id.timeline_id, &layer, &metadata) {
-                            // FIXME: this will emit false positives if an index was
-                            // uploaded concurrently with our scan.  To make this check
-                            // correct, we need to try sending a HEAD request for the
-                            // layer we think is missing.
-                            result.errors.push(format!(
-                                "index_part.json contains a layer {}{} (shard {}) that is not present in remote storage",
-                                layer,
-                                metadata.generation.get_suffix(),
-                                metadata.shard
-                            ))
-                        }
-                    }
-                }
-                BlobDataParseResult::Relic => {}
-                BlobDataParseResult::Incorrect(parse_errors) => result.errors.extend(
-                    parse_errors
-                        .into_iter()
-                        .map(|error| format!("parse error: {error}")),
-                ),
+    let mut warnings = Vec::new();
+    for layer in layers {
+        if let LayerName::Image(image) = layer {
+            if !aligned(&image.key_range.start) || !aligned(&image.key_range.end) {
+                warnings.push(format!(
+                    "Image layer {image} has a key range not aligned to partitions of {partition_size} blocks"
+                ));
             }
         }
-        None => result
-            .errors
-            .push("Timeline has no data on S3 at all".to_string()),
     }
 
-    if result.errors.is_empty() {
-        info!("No check errors found");
+    warnings
+}
+
+/// Only a timeline small enough to fit in a single partition should have an image layer spanning
+/// the whole key space: on a larger timeline, one means that compaction failed to partition.  L0
+/// delta layers span the whole key space by design, and their names cannot tell them apart from
+/// the output of a failed compaction, so only image layers are checked.
+fn check_full_keyspace_layers(
+    layer_metadata: &HashMap<LayerName, LayerFileMetadata>,
+    min_timeline_size: u64,
+) -> Vec<String> {
+    let timeline_size: u64 = layer_metadata.values().map(|m| m.file_size).sum();
+    if timeline_size <= min_timeline_size {
+        return Vec::new();
+    }
+
+    let mut warnings = Vec::new();
+    for layer in layer_metadata.keys() {
+        if let LayerName::Image(image) = layer {
+            if image.key_range == (Key::MIN..Key::MAX) {
+                warnings.push(format!(
+                    "Image layer {image} spans the whole key space, on a timeline of {timeline_size} bytes of layers"
+                ));
+            }
+        }
+    }
+
+    warnings
+}
+
+/// How many L0 delta layers a read may have to visit, at worst.  Every L0 spans the whole key
+/// space, but a read stops at the newest image layer covering its key, so at each key only the L0
+/// layers above that image count.  This is the maximum over all keys: the number of L0 layers if
+/// any key has no image layer at all, and otherwise the most L0 layers above the newest image of
+/// any key.  That is a more precise read amplification signal than the L0 count alone.
+pub(crate) fn l0_stack_depth<'a>(layers: impl Iterator<Item = &'a LayerName> + Clone) -> usize {
+    let l0_ends: Vec<Lsn> = layers
+        .clone()
+        .filter_map(|layer| match layer {
+            LayerName::Delta(delta) if delta.key_range == (Key::MIN..Key::MAX) => {
+                Some(delta.lsn_range.end)
+            }
+            _ => None,
+        })
+        .collect();
+    let depth_above = |lsn: Lsn| l0_ends.iter().filter(|end| **end > lsn).count();
+
+    // The start and end of each image layer's key range, with its LSN and whether it starts there
+    let mut boundaries: Vec<(Key, bool, Lsn)> = layers
+        .filter_map(|layer| match layer {
+            LayerName::Image(image) => Some(image),
+            LayerName::Delta(_) => None,
+        })
+        .flat_map(|image| {
+            [
+                (image.key_range.start, true, image.lsn),
+                (image.key_range.end, false, image.lsn),
+            ]
+        })
+        .collect();
+    if boundaries.is_empty() {
+        return l0_ends.len();
+    }
+    boundaries.sort_by_key(|(key, _, _)| *key);
+
+    // Reads of keys below the first image layer visit every L0
+    let mut max_depth = if boundaries[0].0 > Key::MIN {
+        l0_ends.len()
     } else {
-        warn!("Timeline metadata errors: {0:?}", result.errors);
+        0
+    };
+
+    // Sweep the boundaries in key order, tracking the LSNs of the image layers covering the keys
+    // from each boundary to the next
+    let mut covering: BTreeMap<Lsn, usize> = BTreeMap::new();
+    let mut boundaries = boundaries.into_iter().peekable();
+    while let Some((key, is_start, lsn)) = boundaries.next() {
+        if is_start {
+            *covering.entry(lsn).or_default() += 1;
+        } else if let Some(count) = covering.get_mut(&lsn) {
+            *count -= 1;
+            if *count == 0 {
+                covering.remove(&lsn);
+            }
+        }
+        if boundaries.peek().is_some_and(|(next, _, _)| *next == key) {
+            continue;
+        }
+        match covering.keys().next_back() {
+            Some(newest) => max_depth = std::cmp::max(max_depth, depth_above(*newest)),
+            // A gap between image layers, or the keys above the last of them
+            None if key < Key::MAX => max_depth = l0_ends.len(),
+            None => {}
+        }
     }
 
-    if !result.warnings.is_empty() {
-        warn!("Timeline metadata warnings: {0:?}", result.warnings);
+    max_depth
+}
+
+/// GC may drop a delta layer once an image layer at or below the GC horizon covers its keys.  A
+/// delta layer that lies entirely below the horizon therefore must have its keys covered by such
+/// an image layer: otherwise the history that a read at the horizon would need is gone.
+fn check_gc_horizon_coverage<'a>(
+    layers: impl Iterator<Item = &'a LayerName> + Clone,
+    gc_horizon: Lsn,
+) -> Vec<String> {
+    let mut image_key_ranges = layers
+        .clone()
+        .filter_map(|layer| match layer {
+            LayerName::Image(image) if image.lsn <= gc_horizon => Some(&image.key_range),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    image_key_ranges.sort_by_key(|key_range| key_range.start);
+
+    let mut errors = Vec::new();
+    for layer in layers {
+        let LayerName::Delta(delta) = layer else {
+            continue;
+        };
+        if delta.lsn_range.end > gc_horizon {
+            continue;
+        }
+
+        // Sweep the sorted image key ranges, reporting any gaps within the delta's key range
+        let mut covered_until = delta.key_range.start;
+        for key_range in &image_key_ranges {
+            if covered_until >= delta.key_range.end || key_range.start >= delta.key_range.end {
+                break;
+            }
+            if key_range.start > covered_until {
+                errors.push(format!(
+                    "Key range {}..{} of delta layer {delta} is unreadable at GC horizon {gc_horizon}: no image layer covers it",
+                    covered_until, key_range.start
+                ));
+            }
+            covered_until = std::cmp::max(covered_until, key_range.end);
+        }
+        if covered_until < delta.key_range.end {
+            errors.push(format!(
+                "Key range {}..{} of delta layer {delta} is unreadable at GC horizon {gc_horizon}: no image layer covers it",
+                covered_until, delta.key_range.end
+            ));
+        }
     }
 
-    if !result.garbage_keys.is_empty() {
-        error!(
-            "The following keys should be removed from S3: {0:?}",
-            result.garbage_keys
-        )
+    errors
+}
+
+/// A listed layer whose LSNs all lie above disk_consistent_lsn, referenced or not, holds nothing
+/// of the timeline's consistent state: either it is left over from an aborted write, or it is in
+/// flight from a writer that has not published its index yet.
+fn check_layers_beyond_consistent_lsn<'a>(
+    layers: impl Iterator<Item = &'a (LayerName, Generation)>,
+    disk_consistent_lsn: Lsn,
+) -> Vec<String> {
+    let mut warnings: Vec<String> = layers
+        .filter(|(layer, _)| beyond_consistent_lsn(layer, disk_consistent_lsn))
+        .map(|(layer, generation)| {
+            let lsns = match layer {
+                LayerName::Delta(delta) => {
+                    format!("{}..{}", delta.lsn_range.start, delta.lsn_range.end)
+                }
+                LayerName::Image(image) => image.lsn.to_string(),
+            };
+            format!(
+                "Layer {layer}{} at LSN {lsns} is beyond disk_consistent_lsn {disk_consistent_lsn}",
+                generation.get_suffix()
+            )
+        })
+        .collect();
+    warnings.sort();
+    warnings
+}
+
+/// Whether all of a layer's LSNs lie above disk_consistent_lsn.  A delta starting at
+/// disk_consistent_lsn holds only WAL written after it.
+fn beyond_consistent_lsn(layer: &LayerName, disk_consistent_lsn: Lsn) -> bool {
+    match layer {
+        LayerName::Delta(delta) => delta.lsn_range.start >= disk_consistent_lsn,
+        LayerName::Image(image) => image.lsn > disk_consistent_lsn,
     }
+}
 
-    result
+/// Reading a key at disk_consistent_lsn starts from the newest image layer of the key at or below
+/// that LSN, and applies the delta layers above it.  Any LSN range within that chain of deltas
+/// that no delta layer covers leaves the key unreadable.  LSNs above the newest delta are no gap:
+/// nothing was written to the key since, and reads find the layers below.
+///
+/// Without an image, the chain goes down to the oldest delta, where the key's history starts.  On
+/// a timeline without an ancestor to fall through to, that must be where the timeline's history
+/// starts too: a chain starting any later has lost its beginning.  The keyspace is split at every
+/// layer boundary, and each piece checked.
+fn check_layer_stacks<'a>(
+    layers: impl Iterator<Item = &'a LayerName>,
+    disk_consistent_lsn: Lsn,
+    has_ancestor: bool,
+) -> Vec<String> {
+    // Layers beyond disk_consistent_lsn are discarded on load, so they cannot fill any gaps
+    let mut layers = layers
+        .filter(|layer| !beyond_consistent_lsn(layer, disk_consistent_lsn))
+        .map(|layer| match layer {
+            LayerName::Image(image) => (&image.key_range, layer),
+            LayerName::Delta(delta) => (&delta.key_range, layer),
+        })
+        .collect::<Vec<_>>();
+    layers.sort_by_key(|(key_range, _)| key_range.start);
+
+    // The lowest LSN a delta may start at with nothing below it
+    let history_start = layers
+        .iter()
+        .map(|(_, layer)| match layer {
+            LayerName::Image(image) => image.lsn + 1,
+            LayerName::Delta(delta) => delta.lsn_range.start,
+        })
+        .min();
+
+    let mut boundaries = layers
+        .iter()
+        .flat_map(|(key_range, _)| [key_range.start, key_range.end])
+        .collect::<Vec<_>>();
+    boundaries.sort();
+    boundaries.dedup();
+
+    // Gaps, merged across adjacent pieces of the keyspace that miss the same LSN range
+    let mut gaps: Vec<(Range<Key>, Range<Lsn>)> = Vec::new();
+    let mut next_layer = 0;
+    let mut active: Vec<(&Range<Key>, &LayerName)> = Vec::new();
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        active.retain(|(key_range, _)| key_range.end > start);
+        while next_layer < layers.len() && layers[next_layer].0.start <= start {
+            active.push(layers[next_layer]);
+            next_layer += 1;
+        }
+
+        let newest_image = active
+            .iter()
+            .filter_map(|(_, layer)| match layer {
+                LayerName::Image(image) => Some(image.lsn),
+                LayerName::Delta(_) => None,
+            })
+            .max();
+        let mut delta_lsn_ranges = active
+            .iter()
+            .filter_map(|(_, layer)| match layer {
+                LayerName::Delta(delta) => Some(&delta.lsn_range),
+                LayerName::Image(_) => None,
+            })
+            .collect::<Vec<_>>();
+        delta_lsn_ranges.sort_by_key(|lsn_range| lsn_range.start);
+
+        // Images alone need no deltas, and ranges no layer covers are not this check's concern
+        let Some(oldest_delta) = delta_lsn_ranges.first() else {
+            continue;
+        };
+        let mut window_gaps = Vec::new();
+        let mut covered_until = match (newest_image, history_start) {
+            (Some(image_lsn), _) => image_lsn + 1,
+            (None, Some(history_start)) => {
+                if !has_ancestor && oldest_delta.start > history_start {
+                    window_gaps.push(history_start..oldest_delta.start);
+                }
+                oldest_delta.start
+            }
+            (None, None) => oldest_delta.start,
+        };
+        for lsn_range in delta_lsn_ranges {
+            if lsn_range.end <= covered_until {
+                continue;
+            }
+            if lsn_range.start > covered_until {
+                window_gaps.push(covered_until..lsn_range.start);
+            }
+            covered_until = lsn_range.end;
+        }
+
+        for lsn_gap in window_gaps {
+            match gaps.last_mut() {
+                Some((key_range, last_gap)) if key_range.end == start && *last_gap == lsn_gap => {
+                    key_range.end = end;
+                }
+                _ => gaps.push((start..end, lsn_gap)),
+            }
+        }
+    }
+
+    gaps.into_iter()
+        .map(|(key_range, lsn_gap)| {
+            format!(
+                "Key range {}..{} is unreadable at disk_consistent_lsn {disk_consistent_lsn}: no layer covers LSNs {}..{}",
+                key_range.start, key_range.end, lsn_gap.start, lsn_gap.end
+            )
+        })
+        .collect()
 }
 
 #[derive(Default)]
 pub(crate) struct LayerRef {
     ref_count: usize,
+    /// Size according to the first index that referenced this layer
+    file_size: u64,
+    /// Size according to the listing
+    listed_size: u64,
+}
+
+/// The layers listed under one shard's prefixes, across all of a tenant's timelines
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub(crate) struct ShardTotals {
+    pub(crate) layer_count: usize,
+    pub(crate) layer_bytes: u64,
 }
 
 /// Top-level index of objects in a tenant.  This may be used by any shard-timeline within
@@ -171,6 +2441,13 @@ pub(crate) struct LayerRef {
 #[derive(Default)]
 pub(crate) struct TenantObjectListing {
     shard_timelines: HashMap<(ShardIndex, TimelineId), HashMap<(LayerName, Generation), LayerRef>>,
+    /// The newest generation of any index_part listed in each timeline shard, whether or not it
+    /// was chosen
+    newest_index_generations: HashMap<(ShardIndex, TimelineId), Generation>,
+    /// Timeline shards that exist, but whose layers are not in the listing, because the options
+    /// excluded them or their listing failed or could not be parsed: they still count as present
+    /// for [`Self::check_shard_timelines`], which would otherwise report them missing
+    unlisted_shard_timelines: HashSet<(ShardIndex, TimelineId)>,
 }
 
 impl TenantObjectListing {
@@ -179,7 +2456,7 @@ impl TenantObjectListing {
     pub(crate) fn push(
         &mut self,
         ttid: TenantShardTimelineId,
-        layers: HashSet<(LayerName, Generation)>,
+        layers: HashMap<(LayerName, Generation), u64>,
     ) {
         let shard_index = ShardIndex::new(
             ttid.tenant_shard_id.shard_number,
@@ -189,7 +2466,15 @@ impl TenantObjectListing {
             (shard_index, ttid.timeline_id),
             layers
                 .into_iter()
-                .map(|l| (l, LayerRef::default()))
+                .map(|(l, listed_size)| {
+                    (
+                        l,
+                        LayerRef {
+                            listed_size,
+                            ..Default::default()
+                        },
+                    )
+                })
                 .collect(),
         );
 
@@ -199,6 +2484,51 @@ impl TenantObjectListing {
         );
     }
 
+    /// Note a timeline shard whose layers are not pushed, so that it is not taken for missing
+    pub(crate) fn push_unlisted(&mut self, ttid: TenantShardTimelineId) {
+        self.unlisted_shard_timelines
+            .insert((ttid.tenant_shard_id.to_index(), ttid.timeline_id));
+    }
+
+    /// Remove a timeline's layers, in all shards, from the listing: call once every index that
+    /// could reference them has been checked, to query the orphans, referenced bytes and
+    /// generations among them with the methods below.
+    pub(crate) fn take_timeline(&mut self, timeline_id: TimelineId) -> TenantObjectListing {
+        let keys: Vec<(ShardIndex, TimelineId)> = self
+            .shard_timelines
+            .keys()
+            .filter(|(_, t)| *t == timeline_id)
+            .copied()
+            .collect();
+        let (newest_index_generations, others) = std::mem::take(&mut self.newest_index_generations)
+            .into_iter()
+            .partition(|((_, t), _)| *t == timeline_id);
+        self.newest_index_generations = others;
+        TenantObjectListing {
+            shard_timelines: keys
+                .into_iter()
+                .filter_map(|key| self.shard_timelines.remove_entry(&key))
+                .collect(),
+            newest_index_generations,
+            unlisted_shard_timelines: HashSet::new(),
+        }
+    }
+
+    /// Record the generations of the index_parts listed in a timeline shard, including ones that
+    /// were not chosen or that could not be parsed.
+    pub(crate) fn push_index_generations(
+        &mut self,
+        ttid: TenantShardTimelineId,
+        generations: &[Generation],
+    ) {
+        let Some(newest) = generations.iter().copied().max() else {
+            return;
+        };
+        let key = (ttid.tenant_shard_id.to_index(), ttid.timeline_id);
+        let entry = self.newest_index_generations.entry(key).or_insert(newest);
+        *entry = (*entry).max(newest);
+    }
+
     /// Having loaded a timeline index, check if a layer referenced by the index exists.  If it does,
     /// the layer's refcount will be incremented.  Later, after calling this for all references in all indices
     /// in a tenant, orphan layers may be detected by their zero refcounts.
@@ -214,48 +2544,501 @@ impl TenantObjectListing {
             return false;
         };
 
-        let Some(layer_ref) = shard_tl.get_mut(&(layer_file.clone(), metadata.generation)) else {
-            return false;
-        };
+        let Some(layer_ref) = shard_tl.get_mut(&(layer_file.clone(), metadata.generation)) else {
+            return false;
+        };
+
+        if layer_ref.ref_count == 0 {
+            layer_ref.file_size = metadata.file_size;
+        }
+        layer_ref.ref_count += 1;
+
+        true
+    }
+
+    /// The layers listed in a timeline shard, with their listed sizes
+    pub(crate) fn listed_layers(
+        &self,
+        ttid: &TenantShardTimelineId,
+    ) -> HashMap<(LayerName, Generation), u64> {
+        self.shard_timelines
+            .get(&(ttid.tenant_shard_id.to_index(), ttid.timeline_id))
+            .map(|layers| {
+                layers
+                    .iter()
+                    .map(|(layer, layer_ref)| (layer.clone(), layer_ref.listed_size))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The size that the listing gave for a layer referenced by an index, if it was listed
+    pub(crate) fn listed_size(
+        &self,
+        timeline_id: TimelineId,
+        layer_file: &LayerName,
+        metadata: &LayerFileMetadata,
+    ) -> Option<u64> {
+        self.shard_timelines
+            .get(&(metadata.shard, timeline_id))?
+            .get(&(layer_file.clone(), metadata.generation))
+            .map(|layer_ref| layer_ref.listed_size)
+    }
+
+    /// The shards under whose prefixes a timeline's layer is listed with this generation, whatever
+    /// shard an index says owns it.
+    pub(crate) fn listed_shards(
+        &self,
+        timeline_id: TimelineId,
+        layer_file: &LayerName,
+        generation: Generation,
+    ) -> Vec<ShardIndex> {
+        let key = (layer_file.clone(), generation);
+        let mut shards: Vec<ShardIndex> = self
+            .shard_timelines
+            .iter()
+            .filter(|((_, t), layers)| *t == timeline_id && layers.contains_key(&key))
+            .map(|((shard_index, _), _)| *shard_index)
+            .collect();
+        shards.sort();
+        shards
+    }
+
+    /// After a shard split, every shard of the tenant hosts the same set of timelines.  Shards of
+    /// a lower count than the latest are remnants of earlier splits, and are not considered.
+    pub(crate) fn check_shard_timelines(&self) -> Vec<String> {
+        let shard_timelines = || {
+            self.shard_timelines
+                .keys()
+                .chain(self.unlisted_shard_timelines.iter())
+        };
+        let Some(shard_count) = shard_timelines()
+            .map(|(shard_index, _)| shard_index.shard_count)
+            .max()
+        else {
+            return Vec::new();
+        };
+        if shard_count.count() < 2 {
+            return Vec::new();
+        }
+
+        let mut timeline_shards: HashMap<TimelineId, HashSet<ShardNumber>> = HashMap::new();
+        for (shard_index, timeline_id) in shard_timelines() {
+            if shard_index.shard_count == shard_count {
+                timeline_shards
+                    .entry(*timeline_id)
+                    .or_default()
+                    .insert(shard_index.shard_number);
+            }
+        }
+
+        let mut errors = Vec::new();
+        for (timeline_id, shards) in timeline_shards {
+            let missing: Vec<String> = (0..shard_count.count())
+                .map(ShardNumber)
+                .filter(|shard_number| !shards.contains(shard_number))
+                .map(|shard_number| ShardIndex::new(shard_number, shard_count).to_string())
+                .collect();
+            if !missing.is_empty() {
+                errors.push(format!(
+                    "Timeline {timeline_id} is missing from shards {}",
+                    missing.join(", ")
+                ));
+            }
+        }
+        errors
+    }
+
+    /// Check every shard with timelines in storage against the shard count the tenant is known to
+    /// have, such as from the control plane.  Unlike [`Self::check_shard_timelines`], this reports
+    /// the shards left behind by earlier splits too: finding those is what it is for.
+    ///
+    /// The child shards of a split keep referencing the layers their parent wrote until they have
+    /// rewritten them, so a parent's timeline prefix that `referenced` says the indices of the
+    /// expected shards still reference is not a remnant yet, and not reported.
+    pub(crate) fn check_expected_shard_count(
+        &self,
+        expected: ShardCount,
+        referenced: &HashSet<(ShardIndex, TimelineId)>,
+    ) -> Vec<String> {
+        let mut unexpected: BTreeMap<ShardIndex, usize> = BTreeMap::new();
+        for shard_timeline @ (shard_index, _) in self.shard_timelines.keys() {
+            // Unsharded tenants may have either a literal count of zero or of one
+            if shard_index.shard_count.count() != expected.count()
+                && !referenced.contains(shard_timeline)
+            {
+                *unexpected.entry(*shard_index).or_default() += 1;
+            }
+        }
+        unexpected
+            .into_iter()
+            .map(|(shard_index, timeline_count)| {
+                format!(
+                    "Shard {shard_index} has {timeline_count} timeline(s) in storage, but the tenant is expected to have {} shard(s)",
+                    expected.count()
+                )
+            })
+            .collect()
+    }
+
+    /// A layer of generation G is written by the pageserver attached with generation G, which
+    /// then publishes an index at G to reference it, under the same timeline shard's prefix.  A
+    /// referenced layer whose generation is newer than every index of its timeline shard was
+    /// therefore written by a generation that never published an index: a torn upload, or an
+    /// index that went missing since.  Comparing against the newest index in the whole tenant
+    /// instead would let one busy shard or timeline hide the torn uploads of all the others.
+    ///
+    /// The indices of a parent shard may be gone after a split while its children still
+    /// reference its layers: those are compared against the newest index of any of the
+    /// timeline's shards.
+    ///
+    /// Call after calling `check_ref` for all indices in the tenant.
+    pub(crate) fn check_referenced_generations(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (shard_timeline @ (shard_index, timeline_id), layers) in &self.shard_timelines {
+            let newest_index_generation = self
+                .newest_index_generations
+                .get(shard_timeline)
+                .copied()
+                .or_else(|| {
+                    self.newest_index_generations
+                        .iter()
+                        .filter(|((_, t), _)| t == timeline_id)
+                        .map(|(_, generation)| *generation)
+                        .max()
+                });
+            let Some(newest_index_generation) = newest_index_generation else {
+                continue;
+            };
+            for ((layer_name, generation), layer_ref) in layers {
+                if layer_ref.ref_count > 0 && *generation > newest_index_generation {
+                    errors.push(format!(
+                        "Layer {layer_name} of timeline {timeline_id} shard {shard_index} is \
+                        referenced at generation {generation:?}, newer than any index of the \
+                        timeline shard (newest: {newest_index_generation:?})"
+                    ));
+                }
+            }
+        }
+        errors.sort();
+        errors
+    }
+
+    /// How the listed layers are spread over the shards of a sharded tenant, to spot a shard that
+    /// carries far more than its peers after a skewed split.  As in `check_shard_timelines`,
+    /// shards of a lower count than the latest are remnants of earlier splits, and left out.
+    pub(crate) fn shard_totals(&self) -> BTreeMap<ShardIndex, ShardTotals> {
+        let Some(shard_count) = self
+            .shard_timelines
+            .keys()
+            .map(|(shard_index, _)| shard_index.shard_count)
+            .max()
+        else {
+            return BTreeMap::new();
+        };
+        if shard_count.count() < 2 {
+            return BTreeMap::new();
+        }
+
+        let mut totals: BTreeMap<ShardIndex, ShardTotals> = BTreeMap::new();
+        for ((shard_index, _), layers) in &self.shard_timelines {
+            if shard_index.shard_count != shard_count {
+                continue;
+            }
+            let shard_totals = totals.entry(*shard_index).or_default();
+            shard_totals.layer_count += layers.len();
+            shard_totals.layer_bytes += layers
+                .values()
+                .map(|layer_ref| layer_ref.listed_size)
+                .sum::<u64>();
+        }
+        totals
+    }
+
+    /// Total size of the distinct layers referenced by the indices checked so far.
+    pub(crate) fn referenced_bytes(&self) -> u64 {
+        self.shard_timelines
+            .values()
+            .flat_map(|layers| layers.values())
+            .filter(|layer_ref| layer_ref.ref_count > 0)
+            .map(|layer_ref| layer_ref.file_size)
+            .sum()
+    }
+
+    /// Yield the layers with zero refcounts.  This is lazy, so that callers handling tenants
+    /// with vast numbers of orphans may process them in batches without collecting them all.
+    /// The last item of each is the layer's listed size.
+    pub(crate) fn iter_orphans(
+        &self,
+    ) -> impl Iterator<Item = (ShardIndex, TimelineId, &LayerName, Generation, u64)> + '_ {
+        self.shard_timelines
+            .iter()
+            .flat_map(|((shard_index, timeline_id), layers)| {
+                layers
+                    .iter()
+                    .filter(|(_, layer_ref)| layer_ref.ref_count == 0)
+                    .map(move |((layer_file, generation), layer_ref)| {
+                        (
+                            *shard_index,
+                            *timeline_id,
+                            layer_file,
+                            *generation,
+                            layer_ref.listed_size,
+                        )
+                    })
+            })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct S3TimelineBlobData {
+    pub(crate) blob_data: BlobDataParseResult,
+    pub(crate) keys_to_remove: Vec<String>,
+    /// Total size of the layer objects listed under the timeline prefix
+    pub(crate) listed_layer_bytes: u64,
+    /// Total size of the objects that are garbage whatever any index says: index_parts other than
+    /// the one chosen, and keys that are not recognizable timeline objects.
+    pub(crate) garbage_bytes: u64,
+    /// The initdb archive that timeline deletion preserves, if it was listed
+    pub(crate) initdb_preserved_archive: Option<ListingObject>,
+    /// Keys that look like layers to a [`LayerNameRecognizer`], but that this build cannot parse
+    pub(crate) unrecognized_layer_keys: Vec<String>,
+    /// Noncurrent versions of layer and index objects, if versions were listed: reclaimable
+    /// garbage, whatever any index says
+    pub(crate) noncurrent_versions: Vec<NoncurrentVersion>,
+    /// Keys that parse to the same layer as a key listed before them, which can only happen if
+    /// the two keys differ in the casing of their hex digits
+    pub(crate) duplicate_layer_keys: Vec<String>,
+    /// The generations of all the index_parts listed, not just of the one chosen
+    pub(crate) index_generations: Vec<Generation>,
+    /// Where the chosen index_part departs from the schema, if one was given
+    pub(crate) index_schema_violations: Vec<String>,
+    /// Layers with more than one entry in the chosen index_part's raw JSON, of which parsing kept
+    /// only the last, if they were looked for
+    pub(crate) duplicate_index_entries: Vec<String>,
+    /// If the index history was checked, what every index_part that could be read says about the
+    /// timeline, in generation order
+    pub(crate) index_history: Vec<IndexHistoryEntry>,
+    /// How many bytes the chosen index_part was, if it was downloaded
+    pub(crate) index_part_size: Option<u64>,
+}
+
+impl S3TimelineBlobData {
+    /// The result for a timeline whose listing failed part way.  Whatever was listed before the
+    /// failure is dropped: an incomplete listing would make present objects look missing.
+    pub(crate) fn listing_failed(error: anyhow::Error) -> Self {
+        Self {
+            blob_data: BlobDataParseResult::ListingFailed(format!("{error:#}")),
+            keys_to_remove: Vec::new(),
+            listed_layer_bytes: 0,
+            garbage_bytes: 0,
+            initdb_preserved_archive: None,
+            unrecognized_layer_keys: Vec::new(),
+            noncurrent_versions: Vec::new(),
+            duplicate_layer_keys: Vec::new(),
+            index_generations: Vec::new(),
+            index_schema_violations: Vec::new(),
+            duplicate_index_entries: Vec::new(),
+            index_history: Vec::new(),
+            index_part_size: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum BlobDataParseResult {
+    Parsed {
+        index_part: Box<IndexPart>,
+        index_part_generation: Generation,
+        /// Every layer listed, with its listed size
+        s3_layers: HashMap<(LayerName, Generation), u64>,
+    },
+    /// The remains of a deleted Timeline (i.e. an initdb archive only)
+    Relic,
+    /// Layer objects under a timeline prefix that has no index_part.json or initdb archive: a
+    /// timeline we know nothing about, such as one whose creation was abandoned part way.
+    Orphan {
+        prefix: String,
+        object_count: usize,
+    },
+    Incorrect(Vec<ParseError>),
+    /// The timeline's objects could not be listed, even after retries: nothing is known about it
+    ListingFailed(String),
+}
+
+/// The layers with more than one entry in an index_part's `layer_metadata`.  Parsing into a map
+/// keeps the last entry of each without complaint, so they can only be found in the raw JSON.
+/// A body that does not parse is left for the full parse to report.
+fn duplicate_layer_entries(index_part_bytes: &[u8]) -> Vec<String> {
+    struct DuplicateKeys(Vec<String>);
 
-        layer_ref.ref_count += 1;
+    impl<'de> Deserialize<'de> for DuplicateKeys {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct Visitor;
+            impl<'de> serde::de::Visitor<'de> for Visitor {
+                type Value = DuplicateKeys;
 
-        true
-    }
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("a map")
+                }
 
-    pub(crate) fn get_orphans(&self) -> Vec<(ShardIndex, TimelineId, LayerName, Generation)> {
-        let mut result = Vec::new();
-        for ((shard_index, timeline_id), layers) in &self.shard_timelines {
-            for ((layer_file, generation), layer_ref) in layers {
-                if layer_ref.ref_count == 0 {
-                    result.push((*shard_index, *timeline_id, layer_file.clone(), *generation))
+                fn visit_map<A: serde::de::MapAccess<'de>>(
+                    self,
+                    mut map: A,
+                ) -> Result<Self::Value, A::Error> {
+                    let mut seen = HashSet::new();
+                    let mut duplicates = Vec::new();
+                    while let Some(key) = map.next_key::<String>()? {
+                        map.next_value::<serde::de::IgnoredAny>()?;
+                        if !seen.insert(key.clone()) && !duplicates.contains(&key) {
+                            duplicates.push(key);
+                        }
+                    }
+                    Ok(DuplicateKeys(duplicates))
                 }
             }
+            deserializer.deserialize_map(Visitor)
         }
+    }
+
+    #[derive(Deserialize)]
+    struct RawIndexPart {
+        layer_metadata: DuplicateKeys,
+    }
 
-        result
+    match serde_json::from_slice::<RawIndexPart>(index_part_bytes) {
+        Ok(raw) => raw.layer_metadata.0,
+        Err(_) => Vec::new(),
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct S3TimelineBlobData {
-    pub(crate) blob_data: BlobDataParseResult,
-    pub(crate) keys_to_remove: Vec<String>,
+/// What one generation's index_part says about the timeline, for comparing generations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct IndexHistoryEntry {
+    pub(crate) generation: Generation,
+    pub(crate) disk_consistent_lsn: Lsn,
+    pub(crate) layer_count: usize,
+    pub(crate) layer_bytes: u64,
+    pub(crate) index_bytes: u64,
+}
+
+impl IndexHistoryEntry {
+    fn new(generation: Generation, index_part: &IndexPart, index_bytes: u64) -> Self {
+        Self {
+            generation,
+            disk_consistent_lsn: index_part.metadata.disk_consistent_lsn(),
+            layer_count: index_part.layer_metadata.len(),
+            layer_bytes: index_part
+                .layer_metadata
+                .values()
+                .map(|m| m.file_size)
+                .sum(),
+            index_bytes,
+        }
+    }
+}
+
+/// Download the index_parts of generations other than the chosen one, for what they say about
+/// the timeline.  Indices that cannot be downloaded or parsed are left out of the history:
+/// only the chosen index's problems are reported.
+async fn load_index_history<'a>(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    index_keys: impl Iterator<Item = &'a (String, Generation)>,
+    options: &ScrubOptions,
+) -> Vec<IndexHistoryEntry> {
+    let mut history = Vec::new();
+    for (key, generation) in index_keys {
+        let bytes = match download_object_with_retries(
+            s3_client,
+            bucket_name,
+            key,
+            Some(options.max_index_size),
+            &options.retry_policy,
+        )
+        .await
+        {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Leaving index {key} out of the index history: {e:#}");
+                continue;
+            }
+        };
+        match serde_json::from_slice::<IndexPart>(&bytes) {
+            Ok(index_part) => history.push(IndexHistoryEntry::new(
+                *generation,
+                &index_part,
+                bytes.len() as u64,
+            )),
+            Err(e) => warn!("Leaving index {key} out of the index history: {e}"),
+        }
+    }
+    history
+}
+
+/// What kind of problem stopped a timeline's listing from being parsed: storage layout problems
+/// (stray keys, missing index) are told apart from index content problems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ParseErrorCategory {
+    /// An object whose key is neither an index, an initdb archive nor a layer
+    StrayKey,
+    /// No usable index_part.json
+    MissingIndex,
+    /// An index_part.json whose body could not be decoded
+    MalformedIndex,
+    /// An index_part.json larger than the scrubber accepts
+    OversizedIndex,
+    /// An index_part.json with nothing in it, as an interrupted upload can leave
+    EmptyIndex,
+    /// An index_part.json that could not be downloaded in time
+    IndexDownloadTimeout,
+    /// An index_part.json whose download failed after the run's retry budget was spent
+    RetryBudgetExhausted,
+    /// A gap in the parsing logic
+    Unexpected,
+}
+
+impl ParseErrorCategory {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::StrayKey => "stray-key",
+            Self::MissingIndex => "missing-index",
+            Self::MalformedIndex => "malformed-index",
+            Self::OversizedIndex => "oversized-index",
+            Self::EmptyIndex => "empty-index",
+            Self::IndexDownloadTimeout => "index-download-timeout",
+            Self::RetryBudgetExhausted => "retry-budget-exhausted",
+            Self::Unexpected => "unexpected",
+        }
+    }
 }
 
 #[derive(Debug)]
-pub(crate) enum BlobDataParseResult {
-    Parsed {
-        index_part: Box<IndexPart>,
-        index_part_generation: Generation,
-        s3_layers: HashSet<(LayerName, Generation)>,
-    },
-    /// The remains of a deleted Timeline (i.e. an initdb archive only)
-    Relic,
-    Incorrect(Vec<String>),
+pub(crate) struct ParseError {
+    pub(crate) category: ParseErrorCategory,
+    message: String,
+}
+
+impl ParseError {
+    fn new(category: ParseErrorCategory, message: String) -> Self {
+        Self { category, message }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.category.as_str())
+    }
+}
+
+/// An index body without any content: no bytes but whitespace, or an empty JSON object
+fn is_empty_index(body: &[u8]) -> bool {
+    let significant = || body.iter().filter(|b| !b.is_ascii_whitespace());
+    significant().next().is_none() || significant().eq(b"{}".iter())
 }
 
-fn parse_layer_object_name(name: &str) -> Result<(LayerName, Generation), String> {
+pub(crate) fn parse_layer_object_name(name: &str) -> Result<(LayerName, Generation), String> {
     match name.rsplit_once('-') {
         // FIXME: this is gross, just use a regex?
         Some((layer_filename, gen)) if gen.len() == 8 => {
@@ -268,131 +3051,1124 @@ fn parse_layer_object_name(name: &str) -> Result<(LayerName, Generation), String
     }
 }
 
+/// Strip a timeline prefix from a listed key.  Keys may come back with or without a leading slash
+/// independently of how the prefix was written (e.g. an empty `prefix_in_bucket` gives every
+/// derived prefix a leading slash, which the keys of a local directory never have), so a leading
+/// slash on either side is not significant.
+fn strip_timeline_prefix<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    key.strip_prefix(prefix).or_else(|| {
+        key.trim_start_matches('/')
+            .strip_prefix(prefix.trim_start_matches('/'))
+    })
+}
+
+/// Choose the index_part to read out of those listed under a timeline: the one of the target
+/// generation if there is one, or else the one with the highest generation.  Two index_parts should
+/// never share a generation, but if they do, the tie is broken on the key, so that repeated runs
+/// over the same listing choose the same index, whatever order the listing comes in.  The listing
+/// does not carry modification times, so they cannot be used for this.
+fn choose_index_part<'a>(
+    index_part_generations: &[(&'a ObjectIdentifier, Generation)],
+    target_generation: Option<Generation>,
+) -> Option<(&'a ObjectIdentifier, Generation)> {
+    let mut index_part_generations = index_part_generations.iter().copied();
+    match target_generation {
+        Some(target) => index_part_generations
+            .filter(|i| i.1 == target)
+            .max_by(|a, b| a.0.key().cmp(b.0.key())),
+        None => index_part_generations
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.key().cmp(b.0.key()))),
+    }
+}
+
+#[tracing::instrument(skip_all, fields(
+    tenant_id = %id.tenant_shard_id.tenant_id,
+    shard_id = %id.tenant_shard_id.shard_slug(),
+    timeline_id = %id.timeline_id,
+    object_count = tracing::field::Empty,
+))]
 pub(crate) async fn list_timeline_blobs(
-    s3_client: &Client,
+    s3_client: &StorageClient,
     id: TenantShardTimelineId,
     s3_root: &RootTarget,
+    options: &ScrubOptions,
 ) -> anyhow::Result<S3TimelineBlobData> {
-    let mut s3_layers = HashSet::new();
+    let target_generation = options.target_generation;
+
+    let mut s3_layers = HashMap::new();
 
     let mut errors = Vec::new();
     let mut keys_to_remove = Vec::new();
 
     let mut timeline_dir_target = s3_root.timeline_root(&id);
+
+    // A timeline prefix should not have subprefixes.  If asked to look for them, objects under
+    // them are reported once per subprefix rather than once per object.
+    let mut subprefixes: HashMap<String, usize> = HashMap::new();
+    if options.detect_subprefixes {
+        let mut prefixes = std::pin::pin!(stream_listing(
+            s3_client,
+            &timeline_dir_target,
+            options.retry_policy.clone()
+        ));
+        while let Some(prefix) = prefixes.next().await {
+            match prefix {
+                Ok(prefix) => subprefixes.insert(prefix.key().to_string(), 0),
+                Err(e) => return Ok(S3TimelineBlobData::listing_failed(e)),
+            };
+        }
+    }
+
     timeline_dir_target.delimiter = String::new();
 
     let mut index_parts: Vec<ObjectIdentifier> = Vec::new();
     let mut initdb_archive: bool = false;
+    let mut initdb_preserved_archive: Option<ListingObject> = None;
+    let mut unrecognized_layer_keys = Vec::new();
+    let mut duplicate_layer_keys = Vec::new();
+    let mut object_count: usize = 0;
+    let mut index_part_listed_bytes: u64 = 0;
+    let mut listed_layer_bytes: u64 = 0;
+    let mut unknown_key_bytes: u64 = 0;
 
-    let mut stream = std::pin::pin!(stream_listing(s3_client, &timeline_dir_target));
+    let mut stream = std::pin::pin!(stream_listing_with_sizes(
+        s3_client,
+        &timeline_dir_target,
+        options.retry_policy.clone()
+    ));
     while let Some(obj) = stream.next().await {
-        let obj = obj?;
+        let (obj, size) = match obj {
+            Ok(obj) => obj,
+            Err(e) => return Ok(S3TimelineBlobData::listing_failed(e)),
+        };
+        object_count += 1;
+        if let Some(progress) = &options.listing_progress {
+            if progress.interval > 0 && object_count % progress.interval == 0 {
+                (progress.callback)(&id, object_count);
+            }
+        }
         let key = obj.key();
 
-        let blob_name = key.strip_prefix(&timeline_dir_target.prefix_in_bucket);
+        if let Some((_, subprefix_object_count)) = subprefixes
+            .iter_mut()
+            .find(|(subprefix, _)| key.starts_with(subprefix.as_str()))
+        {
+            *subprefix_object_count += 1;
+            if options.treat_as_garbage(s3_root, key, size) {
+                keys_to_remove.push(key.to_string());
+                unknown_key_bytes += size;
+            }
+            continue;
+        }
+
+        let blob_name = strip_timeline_prefix(key, &timeline_dir_target.prefix_in_bucket);
         match blob_name {
             Some(name) if name.starts_with("index_part.json") => {
                 tracing::info!("Index key {key}");
+                index_part_listed_bytes += size;
                 index_parts.push(obj)
             }
             Some("initdb.tar.zst") => {
                 tracing::info!("initdb archive {key}");
                 initdb_archive = true;
             }
+            Some("initdb-preserved.tar.zst") => {
+                tracing::info!("preserved initdb archive {key}");
+                initdb_preserved_archive = Some(ListingObject {
+                    key: key.to_string(),
+                    size,
+                });
+            }
             Some(maybe_layer_name) => match parse_layer_object_name(maybe_layer_name) {
                 Ok((new_layer, gen)) => {
                     tracing::info!("Parsed layer key: {} {:?}", new_layer, gen);
-                    s3_layers.insert((new_layer, gen));
+                    // Layer name parsing ignores the casing of hex digits, so distinct keys
+                    // normally parse to distinct layers
+                    if s3_layers.insert((new_layer, gen), size).is_some() {
+                        duplicate_layer_keys.push(key.to_string());
+                    }
+                    listed_layer_bytes += size;
+                }
+                Err(_)
+                    if options
+                        .layer_name_recognizers
+                        .iter()
+                        .any(|recognizer| recognizer.recognizes(maybe_layer_name)) =>
+                {
+                    // Most likely a layer written by a newer pageserver: it must not be mistaken
+                    // for garbage.
+                    tracing::info!("Recognized but unparseable layer key {key}");
+                    unrecognized_layer_keys.push(key.to_string());
                 }
                 Err(e) => {
                     tracing::info!("Error parsing key {maybe_layer_name}");
-                    errors.push(
+                    errors.push(ParseError::new(
+                        ParseErrorCategory::StrayKey,
                         format!("S3 list response got an object with key {key} that is not a layer name: {e}"),
-                    );
-                    keys_to_remove.push(key.to_string());
+                    ));
+                    if options.treat_as_garbage(s3_root, key, size) {
+                        keys_to_remove.push(key.to_string());
+                        unknown_key_bytes += size;
+                    }
                 }
             },
             None => {
                 tracing::info!("Peculiar key {}", key);
-                errors.push(format!("S3 list response got an object with odd key {key}"));
-                keys_to_remove.push(key.to_string());
+                errors.push(ParseError::new(
+                    ParseErrorCategory::StrayKey,
+                    format!("S3 list response got an object with odd key {key}"),
+                ));
+                if options.treat_as_garbage(s3_root, key, size) {
+                    keys_to_remove.push(key.to_string());
+                    unknown_key_bytes += size;
+                }
+            }
+        }
+    }
+
+    // In a versioned bucket, deleting or overwriting an object leaves its old versions behind
+    let mut noncurrent_versions = Vec::new();
+    if options.list_versions {
+        noncurrent_versions =
+            match list_noncurrent_versions(s3_client, &timeline_dir_target, &options.retry_policy)
+                .await
+            {
+                Ok(versions) => versions,
+                Err(e) => {
+                    return Ok(S3TimelineBlobData::listing_failed(
+                        e.context("listing object versions"),
+                    ))
+                }
+            };
+        noncurrent_versions.retain(|version| {
+            match strip_timeline_prefix(&version.key, &timeline_dir_target.prefix_in_bucket) {
+                Some(name) => {
+                    name.starts_with("index_part.json") || parse_layer_object_name(name).is_ok()
+                }
+                None => false,
             }
+        });
+        for version in &noncurrent_versions {
+            tracing::info!(
+                "Noncurrent version {} of {} ({} bytes)",
+                version.version_id,
+                version.key,
+                version.size
+            );
         }
     }
 
-    if index_parts.is_empty() && s3_layers.is_empty() && initdb_archive {
+    tracing::Span::current().record("object_count", object_count);
+
+    for (subprefix, subprefix_object_count) in subprefixes {
+        errors.push(ParseError::new(
+            ParseErrorCategory::StrayKey,
+            format!("Timeline prefix has an unexpected subprefix {subprefix} with {subprefix_object_count} objects"),
+        ));
+    }
+
+    if index_parts.is_empty()
+        && s3_layers.is_empty()
+        && (initdb_archive || initdb_preserved_archive.is_some())
+    {
         tracing::info!(
             "Timeline is empty apart from initdb archive: expected post-deletion state."
         );
         return Ok(S3TimelineBlobData {
             blob_data: BlobDataParseResult::Relic,
             keys_to_remove: Vec::new(),
+            listed_layer_bytes: 0,
+            garbage_bytes: 0,
+            initdb_preserved_archive,
+            unrecognized_layer_keys,
+            noncurrent_versions,
+            duplicate_layer_keys,
+            index_generations: Vec::new(),
+            index_schema_violations: Vec::new(),
+            duplicate_index_entries: Vec::new(),
+            index_history: Vec::new(),
+            index_part_size: None,
+        });
+    }
+
+    if index_parts.is_empty()
+        && !initdb_archive
+        && initdb_preserved_archive.is_none()
+        && !s3_layers.is_empty()
+        && errors.is_empty()
+    {
+        tracing::info!("Timeline prefix contains {object_count} layer objects but no index");
+        return Ok(S3TimelineBlobData {
+            blob_data: BlobDataParseResult::Orphan {
+                prefix: timeline_dir_target.prefix_in_bucket.clone(),
+                object_count,
+            },
+            keys_to_remove,
+            listed_layer_bytes,
+            garbage_bytes: unknown_key_bytes,
+            initdb_preserved_archive,
+            unrecognized_layer_keys,
+            noncurrent_versions,
+            duplicate_layer_keys,
+            index_generations: Vec::new(),
+            index_schema_violations: Vec::new(),
+            duplicate_index_entries: Vec::new(),
+            index_history: Vec::new(),
+            index_part_size: None,
         });
     }
 
-    // Choose the index_part with the highest generation
-    let (index_part_object, index_part_generation) = match index_parts
+    let index_part_count = index_parts.len();
+
+    // Choose the index_part with the highest generation, unless the caller asked for a specific one
+    let index_part_generations: Vec<(&ObjectIdentifier, Generation)> = index_parts
         .iter()
         .filter_map(|k| {
             let key = k.key();
             // Stripping the index key to the last part, because RemotePath doesn't
             // like absolute paths, and depending on prefix_in_bucket it's possible
-            // for the keys we read back to start with a slash.
-            let basename = key.rsplit_once('/').unwrap().1;
+            // for the keys we read back to start with a slash.  A key without any
+            // slash is its own basename.
+            let basename = key.rsplit('/').next().unwrap_or(key);
             parse_remote_index_path(RemotePath::from_string(basename).unwrap()).map(|g| (k, g))
         })
-        .max_by_key(|i| i.1)
-        .map(|(k, g)| (k.clone(), g))
-    {
+        .collect();
+    let index_generations = index_part_generations.iter().map(|(_, g)| *g).collect();
+    let history_keys: Vec<(String, Generation)> = index_part_generations
+        .iter()
+        .map(|(k, g)| (k.key().to_string(), *g))
+        .collect();
+    let chosen_index_part =
+        choose_index_part(&index_part_generations, target_generation).map(|(k, g)| (k.clone(), g));
+    let (index_part_object, index_part_generation) = match chosen_index_part {
         Some((key, gen)) => (Some(key), gen),
-        None => {
+        None => match target_generation {
+            // The requested generation has no index: do not fall back to some other index
+            Some(target) if !target.is_none() => (None, target),
             // Legacy/missing case: one or zero index parts, which did not have a generation
-            (index_parts.pop(), Generation::none())
-        }
+            _ => (index_parts.pop(), Generation::none()),
+        },
     };
 
     if index_part_object.is_none() {
-        errors.push("S3 list response got no index_part.json file".to_string());
+        let message = match target_generation {
+            Some(target) => {
+                format!("S3 list response got no index_part.json file for generation {target:?}")
+            }
+            None => "S3 list response got no index_part.json file".to_string(),
+        };
+        errors.push(ParseError::new(ParseErrorCategory::MissingIndex, message));
     }
 
+    let mut index_part_downloaded_bytes = None;
     if let Some(index_part_object_key) = index_part_object.as_ref().map(|object| object.key()) {
-        let index_part_bytes = download_object_with_retries(
-            s3_client,
-            &timeline_dir_target.bucket_name,
-            index_part_object_key,
+        let download = tokio::time::timeout(
+            options.index_download_timeout,
+            download_object_with_retries(
+                s3_client,
+                &timeline_dir_target.bucket_name,
+                index_part_object_key,
+                Some(options.max_index_size),
+                &options.retry_policy,
+            ),
         )
-        .await
-        .context("index_part.json download")?;
-
-        match serde_json::from_slice(&index_part_bytes) {
-            Ok(index_part) => {
-                return Ok(S3TimelineBlobData {
-                    blob_data: BlobDataParseResult::Parsed {
-                        index_part: Box::new(index_part),
-                        index_part_generation,
-                        s3_layers,
-                    },
-                    keys_to_remove,
-                })
-            }
-            Err(index_parse_error) => errors.push(format!(
-                "index_part.json body parsing error: {index_parse_error}"
+        .await;
+        match download {
+            Err(_elapsed) => errors.push(ParseError::new(
+                ParseErrorCategory::IndexDownloadTimeout,
+                format!(
+                    "index_part.json not downloaded within {:?}",
+                    options.index_download_timeout
+                ),
+            )),
+            // The bucket's contents are not trusted: an index too large to be real is reported
+            // rather than read into memory
+            Ok(Err(e)) if e.is::<ObjectTooLarge>() => errors.push(ParseError::new(
+                ParseErrorCategory::OversizedIndex,
+                format!("index_part.json not downloaded: {e}"),
             )),
+            // Once the budget is spent, every timeline's download fails the same way: the
+            // scan goes on, to report on the timelines that need no retries
+            Ok(Err(e)) if e.is::<RetryBudgetExhausted>() => errors.push(ParseError::new(
+                ParseErrorCategory::RetryBudgetExhausted,
+                format!("index_part.json not downloaded: {e:#}"),
+            )),
+            Ok(Err(e)) => return Err(e.context("index_part.json download")),
+            // Reported before parsing, whose error would only obscure the cause
+            Ok(Ok(index_part_bytes)) if is_empty_index(&index_part_bytes) => {
+                errors.push(ParseError::new(
+                    ParseErrorCategory::EmptyIndex,
+                    format!(
+                        "index_part.json is empty ({} bytes): likely an interrupted upload",
+                        index_part_bytes.len()
+                    ),
+                ))
+            }
+            Ok(Ok(index_part_bytes)) => {
+                index_part_downloaded_bytes = Some(index_part_bytes.len());
+
+                // serde accepts values that no pageserver would write, so a schema can be
+                // stricter.  A body that is not JSON at all is left for the parse below to report.
+                let index_schema_violations = match &options.index_schema {
+                    Some(schema) => {
+                        match serde_json::from_slice::<serde_json::Value>(&index_part_bytes) {
+                            Ok(value) => json_schema::validate(schema, &value),
+                            Err(_) => Vec::new(),
+                        }
+                    }
+                    None => Vec::new(),
+                };
+
+                match serde_json::from_slice::<IndexPart>(&index_part_bytes) {
+                    Ok(index_part) => {
+                        let index_history = if options.check_index_history {
+                            let mut history = load_index_history(
+                                s3_client,
+                                &timeline_dir_target.bucket_name,
+                                history_keys
+                                    .iter()
+                                    .filter(|(key, _)| key != index_part_object_key),
+                                options,
+                            )
+                            .await;
+                            history.push(IndexHistoryEntry::new(
+                                index_part_generation,
+                                &index_part,
+                                index_part_bytes.len() as u64,
+                            ));
+                            history.sort();
+                            history
+                        } else {
+                            Vec::new()
+                        };
+
+                        return Ok(S3TimelineBlobData {
+                            blob_data: BlobDataParseResult::Parsed {
+                                index_part: Box::new(index_part),
+                                index_part_generation,
+                                s3_layers,
+                            },
+                            keys_to_remove,
+                            listed_layer_bytes,
+                            garbage_bytes: unknown_key_bytes
+                                + index_part_listed_bytes
+                                    .saturating_sub(index_part_bytes.len() as u64),
+                            initdb_preserved_archive,
+                            unrecognized_layer_keys,
+                            noncurrent_versions,
+                            duplicate_layer_keys,
+                            index_generations,
+                            index_schema_violations,
+                            duplicate_index_entries: if options.check_duplicate_index_entries {
+                                duplicate_layer_entries(&index_part_bytes)
+                            } else {
+                                Vec::new()
+                            },
+                            index_history,
+                            index_part_size: Some(index_part_bytes.len() as u64),
+                        });
+                    }
+                    Err(index_parse_error) => errors.push(ParseError::new(
+                        ParseErrorCategory::MalformedIndex,
+                        format!("index_part.json body parsing error: {index_parse_error}"),
+                    )),
+                }
+            }
         }
     } else {
-        errors.push(format!(
-            "Index part object {index_part_object:?} has no key"
+        errors.push(ParseError::new(
+            ParseErrorCategory::MissingIndex,
+            format!("Index part object {index_part_object:?} has no key"),
         ));
     }
 
     if errors.is_empty() {
-        errors.push(
-            "Unexpected: no errors did not lead to a successfully parsed blob return".to_string(),
-        );
+        // Include everything we know about the listing, so that if this logic gap is ever hit
+        // there is something to debug it with.
+        errors.push(ParseError::new(
+            ParseErrorCategory::Unexpected,
+            format!(
+                "Unexpected: no errors did not lead to a successfully parsed blob return \
+                (objects listed: {object_count}, layers: {}, index keys: {index_part_count}, \
+                chosen index: {:?}, generation: {index_part_generation:?}, \
+                index bytes downloaded: {index_part_downloaded_bytes:?}, initdb archive: {initdb_archive})",
+                s3_layers.len(),
+                index_part_object.as_ref().map(|object| object.key()),
+            ),
+        ));
     }
 
     Ok(S3TimelineBlobData {
         blob_data: BlobDataParseResult::Incorrect(errors),
         keys_to_remove,
+        listed_layer_bytes,
+        garbage_bytes: unknown_key_bytes,
+        initdb_preserved_archive,
+        unrecognized_layer_keys,
+        noncurrent_versions,
+        duplicate_layer_keys,
+        index_generations,
+        index_schema_violations: Vec::new(),
+        duplicate_index_entries: Vec::new(),
+        index_history: Vec::new(),
+        index_part_size: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use camino_tempfile::Utf8TempDir;
+    use pageserver::tenant::metadata::TimelineMetadata;
+    use utils::id::TenantId;
+
+    use super::*;
+    use crate::S3Target;
+
+    fn key_range(keys: Range<i128>) -> Range<Key> {
+        Key::from_i128(keys.start)..Key::from_i128(keys.end)
+    }
+
+    fn delta(keys: Range<i128>, lsns: Range<u64>) -> LayerName {
+        LayerName::Delta(DeltaLayerName {
+            key_range: key_range(keys),
+            lsn_range: Lsn(lsns.start)..Lsn(lsns.end),
+        })
+    }
+
+    fn image(keys: Range<i128>, lsn: u64) -> LayerName {
+        LayerName::Image(ImageLayerName {
+            key_range: key_range(keys),
+            lsn: Lsn(lsn),
+        })
+    }
+
+    /// An index of the given layers, as a pageserver would write it
+    fn test_index(
+        disk_consistent_lsn: Lsn,
+        layers: &[(LayerName, LayerFileMetadata)],
+    ) -> IndexPart {
+        let metadata =
+            TimelineMetadata::new(disk_consistent_lsn, None, None, Lsn(0), Lsn(0), Lsn(0), 16);
+        test_index_with_metadata(metadata, layers)
+    }
+
+    /// An index of the given layers, for a child timeline branched at `ancestor_lsn`
+    fn test_child_index(
+        disk_consistent_lsn: Lsn,
+        ancestor_lsn: Lsn,
+        layers: &[(LayerName, LayerFileMetadata)],
+    ) -> IndexPart {
+        let metadata = TimelineMetadata::new(
+            disk_consistent_lsn,
+            None,
+            Some(TimelineId::generate()),
+            ancestor_lsn,
+            Lsn(0),
+            Lsn(0),
+            16,
+        );
+        test_index_with_metadata(metadata, layers)
+    }
+
+    fn test_index_with_metadata(
+        metadata: TimelineMetadata,
+        layers: &[(LayerName, LayerFileMetadata)],
+    ) -> IndexPart {
+        let disk_consistent_lsn = metadata.disk_consistent_lsn();
+        let layer_metadata: HashMap<_, _> = layers.iter().cloned().collect();
+        serde_json::from_value(serde_json::json!({
+            "version": 6,
+            "layer_metadata": layer_metadata,
+            "disk_consistent_lsn": disk_consistent_lsn,
+            "metadata_bytes": metadata,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn layer_stacks_without_recent_writes() {
+        // Nothing written since the image, or since the last delta: reads fall through to them
+        let layers = [image(0..100, 0x10)];
+        assert!(check_layer_stacks(layers.iter(), Lsn(0x100), false).is_empty());
+        let layers = [image(0..100, 0x10), delta(0..100, 0x11..0x20)];
+        assert!(check_layer_stacks(layers.iter(), Lsn(0x100), false).is_empty());
+    }
+
+    #[test]
+    fn layer_stacks_gap_in_delta_chain() {
+        let layers = [
+            image(0..100, 0x10),
+            delta(0..100, 0x11..0x20),
+            delta(0..50, 0x20..0x30),
+            delta(0..100, 0x40..0x50),
+        ];
+        let gaps = check_layer_stacks(layers.iter(), Lsn(0x60), false);
+        assert_eq!(gaps.len(), 2, "{gaps:?}");
+        assert!(gaps[0].contains("0/30..0/40"), "{gaps:?}");
+        assert!(gaps[1].contains("0/20..0/40"), "{gaps:?}");
+    }
+
+    #[test]
+    fn layer_stacks_gap_above_image() {
+        let layers = [image(0..100, 0x10), delta(0..100, 0x30..0x40)];
+        let gaps = check_layer_stacks(layers.iter(), Lsn(0x40), false);
+        assert_eq!(gaps.len(), 1, "{gaps:?}");
+        assert!(gaps[0].contains("0/11..0/30"), "{gaps:?}");
+    }
+
+    #[test]
+    fn layer_stacks_history_cut_off() {
+        // Keys 100..200 have no image, but their deltas go back to where the timeline starts
+        let layers = [
+            image(0..100, 0x10),
+            delta(100..200, 0x11..0x20),
+            delta(100..200, 0x20..0x40),
+        ];
+        assert!(check_layer_stacks(layers.iter(), Lsn(0x40), false).is_empty());
+
+        // Here they start later than the timeline does
+        let layers = [image(0..100, 0x10), delta(100..200, 0x30..0x40)];
+        let gaps = check_layer_stacks(layers.iter(), Lsn(0x40), false);
+        assert_eq!(gaps.len(), 1, "{gaps:?}");
+        assert!(gaps[0].contains("0/11..0/30"), "{gaps:?}");
+
+        // On a branch, reads below the oldest delta go to the ancestor
+        assert!(check_layer_stacks(layers.iter(), Lsn(0x40), true).is_empty());
+    }
+
+    #[test]
+    fn layer_stacks_ignore_layers_beyond_consistent_lsn() {
+        // The delta starting at disk_consistent_lsn is discarded on load, so it leaves no gap
+        let layers = [image(0..100, 0x10), delta(0..100, 0x30..0x40)];
+        assert!(check_layer_stacks(layers.iter(), Lsn(0x30), false).is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_timeline_blobs_from_local_directory() {
+        let id = TenantShardTimelineId::new(
+            TenantShardId::unsharded(TenantId::generate()),
+            TimelineId::generate(),
+        );
+        let generation = Generation::new(3);
+        let layer = delta(0..100, 0x10..0x20);
+        let metadata = LayerFileMetadata::new(5, generation, ShardIndex::unsharded());
+        let index_part = test_index(Lsn(0x20), &[(layer.clone(), metadata)]);
+
+        // A prefix_in_bucket that is empty makes every target's prefix start with a slash
+        for prefix_in_bucket in ["pageserver/v1", ""] {
+            let root = Utf8TempDir::new().unwrap();
+            let s3_root = RootTarget::Pageserver(S3Target {
+                bucket_name: String::new(),
+                prefix_in_bucket: prefix_in_bucket.to_string(),
+                delimiter: "/".to_string(),
+                page_size: None,
+            });
+            let timeline_dir = root.path().join(
+                s3_root
+                    .timeline_root(&id)
+                    .prefix_in_bucket
+                    .trim_start_matches('/'),
+            );
+            std::fs::create_dir_all(&timeline_dir).unwrap();
+            for (name, content) in [
+                ("index_part.json-00000002".to_string(), b"{}".to_vec()),
+                (
+                    format!("index_part.json{}", generation.get_suffix()),
+                    index_part.to_s3_bytes().unwrap(),
+                ),
+                (
+                    format!("{layer}{}", generation.get_suffix()),
+                    b"layer".to_vec(),
+                ),
+            ] {
+                std::fs::write(timeline_dir.join(name), content).unwrap();
+            }
+
+            let s3_client = StorageClient::LocalFs(root.path().to_path_buf());
+            let data = list_timeline_blobs(&s3_client, id, &s3_root, &ScrubOptions::default())
+                .await
+                .unwrap();
+            let BlobDataParseResult::Parsed {
+                index_part: listed_index_part,
+                index_part_generation,
+                s3_layers,
+            } = data.blob_data
+            else {
+                panic!("timeline not parsed with prefix {prefix_in_bucket:?}");
+            };
+            assert_eq!(*listed_index_part, index_part);
+            assert_eq!(index_part_generation, generation);
+            assert_eq!(s3_layers, HashMap::from([((layer.clone(), generation), 5)]));
+            assert_eq!(data.listed_layer_bytes, 5);
+            // The older index is garbage
+            assert_eq!(data.garbage_bytes, 2);
+        }
+    }
+
+    #[test]
+    fn index_part_choice_ignores_listing_order() {
+        // Hex digits in either case parse to the same generation
+        let keys = [
+            "index_part.json-00000001",
+            "index_part.json-0000000a",
+            "index_part.json-0000000A",
+            "index_part.json-00000002",
+        ];
+        let listed: Vec<(ObjectIdentifier, Generation)> = keys
+            .iter()
+            .map(|key| {
+                let object = ObjectIdentifier::builder().key(*key).build().unwrap();
+                let generation = parse_remote_index_path(RemotePath::from_string(key).unwrap());
+                (object, generation.unwrap())
+            })
+            .collect();
+        let chosen_key = |order: &[usize], target: Option<Generation>| {
+            let listing: Vec<_> = order
+                .iter()
+                .map(|i| (&listed[*i].0, listed[*i].1))
+                .collect();
+            choose_index_part(&listing, target).map(|(object, generation)| {
+                assert!(keys.contains(&object.key()));
+                (object.key().to_string(), generation)
+            })
+        };
+
+        let orders = [[0, 1, 2, 3], [3, 2, 1, 0], [2, 0, 3, 1], [1, 3, 0, 2]];
+        for order in &orders {
+            assert_eq!(
+                chosen_key(order, None),
+                Some(("index_part.json-0000000a".to_string(), Generation::new(10)))
+            );
+            assert_eq!(
+                chosen_key(order, Some(Generation::new(10))),
+                Some(("index_part.json-0000000a".to_string(), Generation::new(10)))
+            );
+            assert_eq!(
+                chosen_key(order, Some(Generation::new(2))),
+                Some(("index_part.json-00000002".to_string(), Generation::new(2)))
+            );
+            assert_eq!(chosen_key(order, Some(Generation::new(3))), None);
+        }
+    }
+
+    #[test]
+    fn expected_shard_count_spares_parents_still_referenced() {
+        let tenant_id = TenantId::generate();
+        let timeline_id = TimelineId::generate();
+        let ttid = |number: u8, count: u8| {
+            TenantShardTimelineId::new(
+                TenantShardId {
+                    tenant_id,
+                    shard_number: ShardNumber(number),
+                    shard_count: ShardCount::new(count),
+                },
+                timeline_id,
+            )
+        };
+
+        // Split from one shard to two, then from two to four
+        let mut listing = TenantObjectListing::default();
+        for (number, count) in [(0, 0), (0, 2), (1, 2), (0, 4), (1, 4), (2, 4), (3, 4)] {
+            listing.push(ttid(number, count), HashMap::new());
+        }
+
+        let expected = ShardCount::new(4);
+        let errors = listing.check_expected_shard_count(expected, &HashSet::new());
+        assert_eq!(errors.len(), 3);
+
+        // The children of the second split still reference layers of the first shard of two
+        let referenced = HashSet::from([(ttid(0, 2).tenant_shard_id.to_index(), timeline_id)]);
+        let errors = listing.check_expected_shard_count(expected, &referenced);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| !e.starts_with("Shard 0002 ")));
+    }
+
+    #[test]
+    fn own_layers_of_child_timelines() {
+        let metadata = LayerFileMetadata::new(5, Generation::new(1), ShardIndex::unsharded());
+        let branched_at = Lsn(0x1000);
+        let well_past = Lsn(0x1000 + OWN_LAYERS_MIN_LSN_ADVANCE);
+
+        // Layers at or below the branch point hold only what the ancestor already has
+        let inherited = [
+            (image(0..10, 0x1000), metadata.clone()),
+            (delta(0..10, 0x800..0x1001), metadata.clone()),
+        ];
+        let index_part = test_child_index(well_past, branched_at, &inherited);
+        assert_eq!(check_own_layers(&index_part).len(), 1);
+        let index_part = test_child_index(well_past, branched_at, &[]);
+        assert_eq!(check_own_layers(&index_part).len(), 1);
+
+        // Not far enough past the branch point to be expected to have written anything
+        let index_part = test_child_index(Lsn(0x2000), branched_at, &inherited);
+        assert!(check_own_layers(&index_part).is_empty());
+
+        let index_part = test_child_index(
+            well_past,
+            branched_at,
+            &[(delta(0..10, 0x1000..0x1002), metadata.clone())],
+        );
+        assert!(check_own_layers(&index_part).is_empty());
+        let index_part = test_child_index(
+            well_past,
+            branched_at,
+            &[(image(0..10, 0x1001), metadata.clone())],
+        );
+        assert!(check_own_layers(&index_part).is_empty());
+
+        // Only child timelines have an ancestor to point at
+        let index_part = test_index(well_past, &inherited);
+        assert!(check_own_layers(&index_part).is_empty());
+    }
+
+    #[test]
+    fn referenced_generations_per_timeline_shard() {
+        let tenant_id = TenantId::generate();
+        let timeline_id = TimelineId::generate();
+        let ttid = |number: u8| {
+            TenantShardTimelineId::new(
+                TenantShardId {
+                    tenant_id,
+                    shard_number: ShardNumber(number),
+                    shard_count: ShardCount::new(2),
+                },
+                timeline_id,
+            )
+        };
+        let layer = image(0..10, 0x10);
+
+        let mut listing = TenantObjectListing::default();
+        for number in [0, 1] {
+            listing.push(
+                ttid(number),
+                HashMap::from([((layer.clone(), Generation::new(3)), 5)]),
+            );
+            let metadata = LayerFileMetadata::new(
+                5,
+                Generation::new(3),
+                ttid(number).tenant_shard_id.to_index(),
+            );
+            assert!(listing.check_ref(timeline_id, &layer, &metadata));
+        }
+        // Shard 0 published an index at the layer's generation, but shard 1 never did, whatever
+        // shard 0 went on to do
+        listing.push_index_generations(ttid(0), &[Generation::new(3), Generation::new(5)]);
+        listing.push_index_generations(ttid(1), &[Generation::new(2)]);
+
+        let errors = listing
+            .take_timeline(timeline_id)
+            .check_referenced_generations();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("shard 0102"));
+        assert!(listing.newest_index_generations.is_empty());
+    }
+
+    #[test]
+    fn l0_stack_depth_over_keys() {
+        // Key::MAX does not survive a round trip through an i128
+        let l0 = |lsns: Range<u64>| {
+            LayerName::Delta(DeltaLayerName {
+                key_range: Key::MIN..Key::MAX,
+                lsn_range: Lsn(lsns.start)..Lsn(lsns.end),
+            })
+        };
+        let image_of = |key_range: Range<Key>, lsn: u64| {
+            LayerName::Image(ImageLayerName {
+                key_range,
+                lsn: Lsn(lsn),
+            })
+        };
+        let key = Key::from_i128;
+        let l0s = [l0(0x10..0x20), l0(0x20..0x30)];
+        let depth = |layers: &[LayerName]| l0_stack_depth(layers.iter());
+
+        // Without images, every read visits every L0
+        assert_eq!(depth(&l0s), 2);
+
+        // An image across the whole key space at 0x20 hides the L0 below it
+        let mut layers = l0s.to_vec();
+        layers.push(image_of(Key::MIN..Key::MAX, 0x20));
+        assert_eq!(depth(&layers), 1);
+        layers.push(image_of(Key::MIN..Key::MAX, 0x30));
+        assert_eq!(depth(&layers), 0);
+
+        // Keys that no image covers, below, between or above images, visit every L0
+        for images in [
+            vec![
+                image_of(Key::MIN..key(100), 0x30),
+                image_of(key(200)..Key::MAX, 0x30),
+            ],
+            vec![image_of(key(100)..Key::MAX, 0x30)],
+            vec![image_of(Key::MIN..key(100), 0x30)],
+        ] {
+            let mut layers = l0s.to_vec();
+            layers.extend(images);
+            assert_eq!(depth(&layers), 2);
+        }
+
+        // Deltas that are not L0 do not count
+        let layers = [delta(0..10, 0x10..0x20), image_of(Key::MIN..Key::MAX, 0x5)];
+        assert_eq!(depth(&layers), 0);
+    }
+
+    #[test]
+    fn index_self_consistency() {
+        let id = TenantShardTimelineId::new(
+            TenantShardId::unsharded(TenantId::generate()),
+            TimelineId::generate(),
+        );
+        let options = ScrubOptions::default();
+        let metadata = |file_size| {
+            LayerFileMetadata::new(file_size, Generation::new(1), ShardIndex::unsharded())
+        };
+        let codes = |analysis: &TimelineAnalysis| -> Vec<CheckCode> {
+            analysis.errors.iter().map(|finding| finding.code).collect()
+        };
+
+        let index_part = test_index(
+            Lsn(0x30),
+            &[
+                (image(0..10, 0x10), metadata(5)),
+                (delta(0..10, 0x10..0x30), metadata(5)),
+            ],
+        );
+        let analysis = check_index_self_consistency(&id, &index_part, &options);
+        assert!(analysis.is_healthy(), "{:?}", codes(&analysis));
+        assert!(analysis.layer_map_valid);
+
+        let index_part = test_index(Lsn(0x30), &[(image(0..10, 0x10), metadata(0))]);
+        let analysis = check_index_self_consistency(&id, &index_part, &options);
+        assert_eq!(codes(&analysis), vec![CheckCode::ZeroSizeLayer]);
+
+        // The same image twice, under two names
+        let index_part = test_index(
+            Lsn(0x30),
+            &[
+                (image(0..10, 0x10), metadata(5)),
+                (image(0..5, 0x10), metadata(5)),
+                (image(5..10, 0x10), metadata(5)),
+            ],
+        );
+        let analysis = check_index_self_consistency(&id, &index_part, &options);
+        assert!(codes(&analysis).contains(&CheckCode::DuplicateImageLayer));
+
+        // An index naming its own timeline as its ancestor is under the wrong prefix
+        let metadata = TimelineMetadata::new(
+            Lsn(0x30),
+            None,
+            Some(id.timeline_id),
+            Lsn(0x10),
+            Lsn(0),
+            Lsn(0),
+            16,
+        );
+        let index_part = test_index_with_metadata(metadata, &[]);
+        let analysis = check_index_self_consistency(&id, &index_part, &options);
+        assert!(codes(&analysis).contains(&CheckCode::IndexIdentityMismatch));
+    }
+
+    #[test]
+    fn gc_horizon_coverage_of_deltas_below_it() {
+        let gc_horizon = Lsn(0x30);
+
+        let layers = [
+            delta(0..100, 0x10..0x20),
+            image(0..50, 0x20),
+            image(60..100, 0x20),
+        ];
+        let errors = check_gc_horizon_coverage(layers.iter(), gc_horizon);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(errors[0].contains(&format!("{}..{}", Key::from_i128(50), Key::from_i128(60))));
+
+        let layers = [
+            delta(0..100, 0x10..0x20),
+            image(0..60, 0x20),
+            image(50..100, 0x30),
+        ];
+        assert!(check_gc_horizon_coverage(layers.iter(), gc_horizon).is_empty());
+
+        // Images above the horizon may not be there for a read at it
+        let layers = [delta(0..100, 0x10..0x20), image(0..100, 0x40)];
+        let errors = check_gc_horizon_coverage(layers.iter(), gc_horizon);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(errors[0].contains(&format!("{}..{}", Key::from_i128(0), Key::from_i128(100))));
+
+        // Deltas reaching above the horizon are still needed for reads above it
+        let layers = [delta(0..100, 0x10..0x40)];
+        assert!(check_gc_horizon_coverage(layers.iter(), gc_horizon).is_empty());
+    }
+
+    #[test]
+    fn layer_stripes_of_sharded_layers() {
+        let tenant_id = TenantId::generate();
+        let timeline_id = TimelineId::generate();
+        let stripe_size = ShardStripeSize(8);
+        let shard = |number: u8| {
+            TenantShardTimelineId::new(
+                TenantShardId {
+                    tenant_id,
+                    shard_number: ShardNumber(number),
+                    shard_count: ShardCount::new(2),
+                },
+                timeline_id,
+            )
+        };
+        let rel_block_keys = |blocks: Range<u32>| {
+            let key = |block| Key {
+                field1: 0,
+                field2: 1663,
+                field3: 1,
+                field4: 1234,
+                field5: 0,
+                field6: block,
+            };
+            key(blocks.start)..key(blocks.end)
+        };
+        let index_of = |id: &TenantShardTimelineId, layer: LayerName| {
+            let metadata =
+                LayerFileMetadata::new(5, Generation::new(1), id.tenant_shard_id.to_index());
+            test_index(Lsn(0x30), &[(layer, metadata)])
+        };
+
+        // The blocks of one stripe belong to one shard: the other cannot have written them
+        let one_stripe = LayerName::Image(ImageLayerName {
+            key_range: rel_block_keys(0..8),
+            lsn: Lsn(0x10),
+        });
+        let errors = [shard(0), shard(1)].map(|id| {
+            check_layer_stripes(&id, &index_of(&id, one_stripe.clone()), stripe_size).len()
+        });
+        assert_eq!(errors.iter().sum::<usize>(), 1, "{errors:?}");
+
+        // Keys that are not relation blocks are stored on shard 0, and written by every shard
+        for id in [shard(0), shard(1)] {
+            let index_part = index_of(&id, image(0..10, 0x10));
+            assert!(check_layer_stripes(&id, &index_part, stripe_size).is_empty());
+        }
+
+        // Layers inherited from an ancestor shard had other stripes
+        let metadata = LayerFileMetadata::new(5, Generation::new(1), ShardIndex::unsharded());
+        let index_part = test_index(Lsn(0x30), &[(one_stripe.clone(), metadata)]);
+        for id in [shard(0), shard(1)] {
+            assert!(check_layer_stripes(&id, &index_part, stripe_size).is_empty());
+        }
+
+        // Unsharded tenants have no stripes
+        let id = TenantShardTimelineId::new(TenantShardId::unsharded(tenant_id), timeline_id);
+        assert!(check_layer_stripes(&id, &index_of(&id, one_stripe), stripe_size).is_empty());
+    }
+
+    #[test]
+    fn valid_layermap_needs_deltas_apart_in_keys_or_lsns() {
+        // Overlapping in both key and LSN ranges
+        let layers = [delta(0..10, 0x10..0x20), delta(5..15, 0x18..0x28)];
+        assert_eq!(check_valid_layermap(layers.iter()).len(), 1);
+
+        // The output of one compaction shares its LSN range
+        let layers = [delta(0..10, 0x10..0x20), delta(5..15, 0x10..0x20)];
+        assert!(check_valid_layermap(layers.iter()).is_empty());
+
+        // Stacked in LSN, or side by side in keys
+        let layers = [delta(0..10, 0x10..0x20), delta(0..10, 0x20..0x30)];
+        assert!(check_valid_layermap(layers.iter()).is_empty());
+        let layers = [delta(0..10, 0x10..0x20), delta(10..20, 0x18..0x28)];
+        assert!(check_valid_layermap(layers.iter()).is_empty());
+
+        // Image layers take no part
+        let layers = [delta(0..10, 0x10..0x20), image(0..10, 0x18)];
+        assert!(check_valid_layermap(layers.iter()).is_empty());
+    }
+
+    #[test]
+    fn index_size_against_layer_count() {
+        assert_eq!(check_index_size(99, 10, 10).len(), 1);
+        assert!(check_index_size(100, 10, 10).is_empty());
+        assert!(check_index_size(2, 0, 10).is_empty());
+    }
+
+    #[test]
+    fn duplicate_layer_entries_in_raw_index() {
+        let index_part_bytes =
+            br#"{"version": 6, "layer_metadata": {"a": {}, "b": {}, "a": {}, "a": {}}}"#;
+        assert_eq!(duplicate_layer_entries(index_part_bytes), vec!["a"]);
+
+        let index_part_bytes = br#"{"version": 6, "layer_metadata": {"a": {}, "b": {}}}"#;
+        assert!(duplicate_layer_entries(index_part_bytes).is_empty());
+
+        // Left for the full parse to report
+        assert!(duplicate_layer_entries(b"{\"layer_metadata\": ").is_empty());
+        assert!(duplicate_layer_entries(b"{}").is_empty());
+    }
+
+    #[test]
+    fn duplicate_images_at_one_lsn() {
+        let layers = [image(0..10, 0x10), image(5..15, 0x10)];
+        assert_eq!(check_duplicate_images(layers.iter()).len(), 1);
+
+        // Newer images supersede older ones, and neighbours share no keys
+        let layers = [image(0..10, 0x10), image(5..15, 0x20)];
+        assert!(check_duplicate_images(layers.iter()).is_empty());
+        let layers = [image(0..10, 0x10), image(10..20, 0x10)];
+        assert!(check_duplicate_images(layers.iter()).is_empty());
+
+        // Deltas are left to the layer map check
+        let layers = [
+            delta(0..10, 0x10..0x20),
+            delta(0..10, 0x10..0x20),
+            image(0..10, 0x10),
+        ];
+        assert!(check_duplicate_images(layers.iter()).is_empty());
+    }
+
+    #[test]
+    fn empty_index_bodies() {
+        assert!(is_empty_index(b""));
+        assert!(is_empty_index(b" \n\t"));
+        assert!(is_empty_index(b"{}"));
+        assert!(is_empty_index(b" { \n } \n"));
+        assert!(!is_empty_index(b"[]"));
+        assert!(!is_empty_index(br#"{"version": 6}"#));
+    }
+
+    #[test]
+    fn timeline_fingerprint_of_referenced_layers() {
+        let metadata = |file_size, generation| {
+            LayerFileMetadata::new(
+                file_size,
+                Generation::new(generation),
+                ShardIndex::unsharded(),
+            )
+        };
+        let layers = [
+            (image(0..10, 0x10), metadata(5, 1)),
+            (delta(0..10, 0x10..0x30), metadata(7, 2)),
+        ];
+        let fingerprint = timeline_fingerprint(&test_index(Lsn(0x30), &layers));
+
+        // Neither the order of layers nor the disk consistent LSN matters
+        let mut reordered = layers.clone();
+        reordered.reverse();
+        assert_eq!(
+            timeline_fingerprint(&test_index(Lsn(0x40), &reordered)),
+            fingerprint
+        );
+
+        // The generations and sizes of the layers do
+        let mut regenerated = layers.clone();
+        regenerated[0].1 = metadata(5, 3);
+        assert_ne!(
+            timeline_fingerprint(&test_index(Lsn(0x30), &regenerated)),
+            fingerprint
+        );
+        let mut resized = layers.clone();
+        resized[1].1 = metadata(8, 2);
+        assert_ne!(
+            timeline_fingerprint(&test_index(Lsn(0x30), &resized)),
+            fingerprint
+        );
+        assert_ne!(
+            timeline_fingerprint(&test_index(Lsn(0x30), &layers[..1])),
+            fingerprint
+        );
+    }
+}
@@ -8,10 +8,7 @@ use std::{
 };
 
 use anyhow::Context;
-use aws_sdk_s3::{
-    types::{Delete, ObjectIdentifier},
-    Client,
-};
+use aws_sdk_s3::types::{Delete, ObjectIdentifier};
 use futures_util::TryStreamExt;
 use pageserver_api::shard::TenantShardId;
 use serde::{Deserialize, Serialize};
@@ -22,7 +19,8 @@ use crate::{
     cloud_admin_api::{CloudAdminApiClient, MaybeDeleted, ProjectData},
     init_remote,
     metadata_stream::{stream_listing, stream_tenant_timelines, stream_tenants},
-    BucketConfig, ConsoleConfig, NodeKind, RootTarget, TenantShardTimelineId, TraversingDepth,
+    BucketConfig, ConsoleConfig, NodeKind, RetryPolicy, RootTarget, ScrubOptions, StorageClient,
+    TenantShardTimelineId, TraversingDepth,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -166,7 +164,7 @@ async fn find_garbage_inner(
 
     // Enumerate Tenants in S3, and check if each one exists in Console
     tracing::info!("Finding all tenants in bucket {}...", bucket_config.bucket);
-    let tenants = stream_tenants(&s3_client, &target);
+    let tenants = stream_tenants(&s3_client, &target, RetryPolicy::default());
     let tenants_checked = tenants.map_ok(|t| {
         let api_client = cloud_admin_api_client.clone();
         let console_cache = console_cache.clone();
@@ -258,7 +256,8 @@ async fn find_garbage_inner(
 
     // Construct a stream of all timelines within active tenants
     let active_tenants = tokio_stream::iter(active_tenants.iter().map(Ok));
-    let timelines = active_tenants.map_ok(|t| stream_tenant_timelines(&s3_client, &target, *t));
+    let timelines = active_tenants
+        .map_ok(|t| stream_tenant_timelines(&s3_client, &target, *t, RetryPolicy::default()));
     let timelines = timelines.try_buffer_unordered(S3_CONCURRENCY);
     let timelines = timelines.try_flatten();
 
@@ -324,7 +323,7 @@ impl std::fmt::Display for PurgeMode {
 }
 
 pub async fn get_tenant_objects(
-    s3_client: &Arc<Client>,
+    s3_client: &Arc<StorageClient>,
     target: RootTarget,
     tenant_shard_id: TenantShardId,
 ) -> anyhow::Result<Vec<ObjectIdentifier>> {
@@ -338,12 +337,12 @@ pub async fn get_tenant_objects(
     // common prefixes.
     tenant_root.delimiter = String::new();
 
-    let key_stream = stream_listing(s3_client, &tenant_root);
+    let key_stream = stream_listing(s3_client, &tenant_root, RetryPolicy::default());
     key_stream.try_collect().await
 }
 
 pub async fn get_timeline_objects(
-    s3_client: &Arc<Client>,
+    s3_client: &Arc<StorageClient>,
     target: RootTarget,
     ttid: TenantShardTimelineId,
 ) -> anyhow::Result<Vec<ObjectIdentifier>> {
@@ -356,7 +355,7 @@ pub async fn get_timeline_objects(
     // Remove delimiter, so that object listing lists all keys in the prefix and not just
     // common prefixes.
     timeline_root.delimiter = String::new();
-    let key_stream = stream_listing(s3_client, &timeline_root);
+    let key_stream = stream_listing(s3_client, &timeline_root, RetryPolicy::default());
 
     key_stream.try_collect().await
 }
@@ -369,12 +368,13 @@ const MAX_KEYS_PER_DELETE: usize = 1000;
 /// MAX_KEYS_PER_DELETE keys are left.
 /// `num_deleted` returns number of deleted keys.
 async fn do_delete(
-    s3_client: &Arc<Client>,
+    s3_client: &Arc<StorageClient>,
     bucket_name: &str,
     keys: &mut Vec<ObjectIdentifier>,
     dry_run: bool,
     drain: bool,
     progress_tracker: &mut DeletionProgressTracker,
+    options: &ScrubOptions,
 ) -> anyhow::Result<()> {
     while (!keys.is_empty() && drain) || (keys.len() >= MAX_KEYS_PER_DELETE) {
         let request_keys =
@@ -386,7 +386,9 @@ async fn do_delete(
                 tracing::info!("  {k:?}");
             }
         } else {
+            options.check_mutation_allowed("delete objects")?;
             let delete_request = s3_client
+                .s3()?
                 .delete_objects()
                 .bucket(bucket_name)
                 .delete(Delete::builder().set_objects(Some(request_keys)).build()?);
@@ -422,6 +424,7 @@ pub async fn purge_garbage(
     input_path: String,
     mode: PurgeMode,
     dry_run: bool,
+    options: &ScrubOptions,
 ) -> anyhow::Result<()> {
     let list_bytes = tokio::fs::read(&input_path).await?;
     let garbage_list = serde_json::from_slice::<GarbageList>(&list_bytes)?;
@@ -493,6 +496,7 @@ pub async fn purge_garbage(
                 dry_run,
                 false,
                 &mut progress_tracker,
+                options,
             )
             .await?;
         }
@@ -505,6 +509,7 @@ pub async fn purge_garbage(
         dry_run,
         true,
         &mut progress_tracker,
+        options,
     )
     .await?;
 
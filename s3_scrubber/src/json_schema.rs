@@ -0,0 +1,197 @@
+//! Validation of JSON documents against a schema, for indices that serde accepts but that hold
+//! values no pageserver would write.
+//!
+//! Only the subset of JSON Schema that describes index_part.json is supported: `type`, `enum`,
+//! `minimum`, `maximum`, `properties`, `required`, `additionalProperties` and `items`.  Other
+//! keywords are ignored.
+
+use serde_json::Value;
+
+/// The schema of index_part.json as written by current pageservers, and by the older versions
+/// we may still find in a bucket.
+pub fn embedded_index_schema() -> Value {
+    serde_json::from_str(include_str!("index_part.schema.json"))
+        .expect("embedded index_part schema is valid JSON")
+}
+
+/// Check `value` against `schema`, returning a description of every violation, each prefixed by
+/// the JSON pointer of the offending value.
+pub(crate) fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut violations = Vec::new();
+    validate_at(schema, value, "", &mut violations);
+    violations
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+    let actual = type_name(value);
+    // Every integer is also a number
+    actual == expected || (expected == "number" && actual == "integer")
+}
+
+fn validate_at(schema: &Value, value: &Value, pointer: &str, violations: &mut Vec<String>) {
+    let Some(schema) = schema.as_object() else {
+        // `true`, `false` and other non-object schemas are not used by the index schema
+        return;
+    };
+    let at = if pointer.is_empty() { "/" } else { pointer };
+
+    let expected_types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(ts)) => ts.iter().filter_map(|t| t.as_str()).collect(),
+        _ => Vec::new(),
+    };
+    if !expected_types.is_empty() && !expected_types.iter().any(|t| type_matches(t, value)) {
+        violations.push(format!(
+            "{at}: expected {}, got {}",
+            expected_types.join(" or "),
+            type_name(value)
+        ));
+        // Checking the other keywords against a value of the wrong type only adds noise
+        return;
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            violations.push(format!("{at}: {value} is not one of the allowed values"));
+        }
+    }
+
+    if let Some(n) = value.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if n < minimum {
+                violations.push(format!("{at}: {value} is less than the minimum {minimum}"));
+            }
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+            if n > maximum {
+                violations.push(format!("{at}: {value} is more than the maximum {maximum}"));
+            }
+        }
+    }
+
+    if let Value::Object(fields) = value {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !fields.contains_key(name) {
+                    violations.push(format!("{at}: missing required field {name}"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, field) in fields {
+            let field_pointer = format!("{pointer}/{}", name.replace('~', "~0").replace('/', "~1"));
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(field_schema) => validate_at(field_schema, field, &field_pointer, violations),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        violations.push(format!("{at}: unexpected field {name}"))
+                    }
+                    Some(field_schema) => {
+                        validate_at(field_schema, field, &field_pointer, violations)
+                    }
+                    None => {}
+                },
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate_at(item_schema, item, &format!("{pointer}/{i}"), violations);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["version", "metadata"],
+            "properties": {
+                "version": {"type": "integer", "minimum": 1},
+                "kind": {"enum": ["a", "b"]},
+                "metadata": {
+                    "type": "object",
+                    "additionalProperties": {"type": "string"}
+                }
+            }
+        })
+    }
+
+    /// The violations in a document, in a fixed order
+    fn violations(document: Value) -> Vec<String> {
+        let mut violations = validate(&schema(), &document);
+        violations.sort();
+        violations
+    }
+
+    #[test]
+    fn valid_document() {
+        let document = json!({"version": 1, "kind": "a", "metadata": {"x/y": "z"}, "other": 0});
+        assert!(violations(document).is_empty());
+    }
+
+    #[test]
+    fn type_violations() {
+        assert_eq!(violations(json!([])), vec!["/: expected object, got array"]);
+        let document = json!({"version": 1.5, "metadata": {"x/y": 1}});
+        assert_eq!(
+            violations(document),
+            vec![
+                "/metadata/x~1y: expected string, got integer",
+                "/version: expected integer, got number",
+            ]
+        );
+    }
+
+    #[test]
+    fn required_violations() {
+        assert_eq!(
+            violations(json!({"kind": "b"})),
+            vec![
+                "/: missing required field metadata",
+                "/: missing required field version",
+            ]
+        );
+    }
+
+    #[test]
+    fn enum_and_range_violations() {
+        let document = json!({"version": 0, "kind": "c", "metadata": {}});
+        assert_eq!(
+            violations(document),
+            vec![
+                "/kind: \"c\" is not one of the allowed values",
+                "/version: 0 is less than the minimum 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn embedded_schema_accepts_a_current_index() {
+        let index = json!({
+            "version": 6,
+            "layer_metadata": {},
+            "disk_consistent_lsn": "0/16960E8",
+            "metadata_bytes": []
+        });
+        assert!(validate(&embedded_index_schema(), &index).is_empty());
+    }
+}
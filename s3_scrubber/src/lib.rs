@@ -1,24 +1,30 @@
 #![deny(unsafe_code)]
 #![deny(clippy::undocumented_unsafe_blocks)]
 pub mod checks;
+pub mod clock;
 pub mod cloud_admin_api;
 pub mod garbage;
+pub mod json_schema;
+pub mod local_fs;
 pub mod metadata_stream;
 pub mod scan_pageserver_metadata;
 pub mod scan_safekeeper_metadata;
 pub mod tenant_snapshot;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::Display;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
 use aws_config::environment::EnvironmentVariableCredentialsProvider;
 use aws_config::imds::credentials::ImdsCredentialsProvider;
 use aws_config::meta::credentials::CredentialsProviderChain;
 use aws_config::profile::ProfileFileCredentialsProvider;
-use aws_config::retry::RetryConfig;
 use aws_config::sso::SsoCredentialsProvider;
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::config::{AsyncSleep, Region, SharedAsyncSleep};
@@ -26,20 +32,640 @@ use aws_sdk_s3::{Client, Config};
 use aws_smithy_async::rt::sleep::TokioSleep;
 
 use camino::{Utf8Path, Utf8PathBuf};
+use checks::{parse_layer_object_name, ScoreWeights, Severity, SeverityPolicy};
 use clap::ValueEnum;
+use clock::{Clock, SystemClock};
+use futures::StreamExt;
+use pageserver::repository::Key;
+use pageserver::tenant::storage_layer::LayerName;
 use pageserver::tenant::TENANTS_SEGMENT_NAME;
-use pageserver_api::shard::TenantShardId;
+use pageserver_api::shard::{ShardCount, ShardStripeSize, TenantShardId};
+use remote_storage::RemotePath;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use tracing::error;
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use utils::fs_ext;
+use utils::generation::Generation;
 use utils::id::{TenantId, TimelineId};
+use utils::lsn::Lsn;
+use uuid::Uuid;
 
 const MAX_RETRIES: usize = 20;
 const CLOUD_ADMIN_API_TOKEN_ENV_VAR: &str = "CLOUD_ADMIN_API_TOKEN";
+/// Far larger than the index of any real timeline
+const DEFAULT_MAX_INDEX_SIZE: u64 = 64 * 1024 * 1024;
+/// Far less than any layer's entry in an index takes: a layer name alone is longer
+const DEFAULT_MIN_INDEX_BYTES_PER_LAYER: u64 = 64;
+
+/// Bounds the number of S3 requests in flight at once, whichever phase of a scrub makes them.
+/// Each phase has its own concurrency, and nested phases multiply: tenants listing timelines
+/// listing layers can together open far more connections than any one setting suggests.
+///
+/// The permits are shared by every clone of the governor.
+#[derive(Debug, Clone, Default)]
+pub struct RequestGovernor(Option<Arc<Semaphore>>);
+
+impl RequestGovernor {
+    /// A governor allowing at most `max_in_flight` requests at once
+    pub fn new(max_in_flight: usize) -> Self {
+        let semaphore = Semaphore::new(std::cmp::max(max_in_flight, 1));
+        Self(Some(Arc::new(semaphore)))
+    }
+
+    /// Wait for a permit to make one request: the request may proceed until the permit is
+    /// dropped.  Without a limit, returns immediately.
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.0 {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("request governor semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+}
+
+/// Bounds the retries of S3 requests across a whole run, whichever request they retry: once it is
+/// spent, failures are returned at once instead of being retried.  This caps the extra load that a
+/// scrub adds to a backend that is already failing widely.
+///
+/// The budget is shared by every clone.
+#[derive(Debug, Clone, Default)]
+pub struct RetryBudget(Option<Arc<RetryBudgetState>>);
+
+#[derive(Debug)]
+struct RetryBudgetState {
+    remaining: AtomicUsize,
+    /// Whether any retry was refused for want of budget
+    exhausted: AtomicBool,
+}
+
+impl RetryBudget {
+    /// A budget of `max_retries` retries in total
+    pub fn new(max_retries: usize) -> Self {
+        Self(Some(Arc::new(RetryBudgetState {
+            remaining: AtomicUsize::new(max_retries),
+            exhausted: AtomicBool::new(false),
+        })))
+    }
+
+    /// Take one retry from the budget, returning false if none is left.  Without a limit, always
+    /// returns true.
+    fn try_take(&self) -> bool {
+        let Some(state) = &self.0 else {
+            return true;
+        };
+        let taken = state
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| r.checked_sub(1))
+            .is_ok();
+        if !taken {
+            state.exhausted.store(true, Ordering::Relaxed);
+        }
+        taken
+    }
+
+    /// Whether a retry has been refused because the budget was spent
+    pub fn is_exhausted(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|state| state.exhausted.load(Ordering::Relaxed))
+    }
+}
+
+/// Retry policy for the scrubber's own retry loops around S3 requests (on top of the
+/// retries done inside the AWS SDK).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    /// Upper bound of the delay before the first retry: doubles with each attempt.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Limits the requests in flight across every request made with this config: not part of a
+    /// saved configuration.  A permit is held for one attempt, and released before backing off.
+    #[serde(skip)]
+    pub governor: RequestGovernor,
+    /// Limits the retries made with this config, in total: not part of a saved configuration
+    #[serde(skip)]
+    pub budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(20),
+            governor: RequestGovernor::default(),
+            budget: RetryBudget::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// "Full jitter" exponential backoff: sleep for a uniformly random duration up to the
+    /// exponentially growing cap.  Many tasks throttled at the same moment will then spread
+    /// their retries out, instead of retrying in lockstep and getting throttled again.
+    fn backoff_delay(&self, attempt: usize) -> Duration {
+        let multiplier = u32::try_from(attempt)
+            .ok()
+            .and_then(|a| 1u32.checked_shl(a))
+            .unwrap_or(u32::MAX);
+        let cap = std::cmp::min(self.base_delay.saturating_mul(multiplier), self.max_delay);
+        cap.mul_f64(rand::random::<f64>())
+    }
+
+    /// Wait before retrying, or fail with [`RetryBudgetExhausted`] if the retry budget is spent.
+    /// After the last attempt there is no retry to wait for, or to spend the budget on.
+    async fn backoff(&self, attempt: usize) -> anyhow::Result<()> {
+        if attempt + 1 >= self.max_retries {
+            return Ok(());
+        }
+        if !self.budget.try_take() {
+            return Err(RetryBudgetExhausted.into());
+        }
+        tokio::time::sleep(self.backoff_delay(attempt)).await;
+        Ok(())
+    }
+}
+
+/// Tuning knobs for a scrub, bundled so that the signatures of the checks stay stable as
+/// more knobs are added, and so that a scrub configuration can be saved and reloaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScrubOptions {
+    /// How many tenants or timelines to process in parallel.  We need to be mindful of
+    /// pageservers accessing the same per tenant prefixes, so use a lower setting than pageservers.
+    pub concurrency: usize,
+    /// Also carries the governor bounding S3 requests in flight across all phases combined
+    pub retry_policy: RetryPolicy,
+    /// How many of the newest index_part versions count as current
+    pub index_version_window: usize,
+    /// How to report indices whose version is older than `index_version_window`
+    pub old_index_version_severity: Severity,
+    /// Check timelines against their index_part.json from this generation, rather than the latest
+    pub target_generation: Option<Generation>,
+    /// Give up listing a single timeline, and separately checking it, after this long
+    pub timeline_timeout: Duration,
+    /// Give up downloading a timeline's index_part.json after this long, retries included
+    pub index_download_timeout: Duration,
+    /// If set, log the summary so far at most this often during a scan, for a live picture of a
+    /// long one
+    pub summary_interval: Option<Duration>,
+    /// If set, check that every timeline is readable at this LSN
+    pub gc_horizon: Option<Lsn>,
+    /// Refuse any mutation of remote storage, whatever else the options or arguments say
+    pub read_only: bool,
+    /// List each timeline with a delimiter first, to report unexpected subprefixes as a whole
+    pub detect_subprefixes: bool,
+    /// The tenant's shard stripe size, if known: needed to check that sharded layers cover keys
+    /// that belong to their shard
+    pub shard_stripe_size: Option<ShardStripeSize>,
+    /// Whether to stop at the first unhealthy timeline, or carry on and report on them all
+    pub failure_mode: FailureMode,
+    /// List object versions too, to quantify noncurrent versions in versioned buckets
+    pub list_versions: bool,
+    /// How many objects to ask for per listing request: larger pages mean fewer round trips on
+    /// huge timelines.  Clamped to the backend's maximum.
+    pub listing_page_size: Option<usize>,
+    /// Warn about layers larger than this many bytes: far beyond the target layer size, they
+    /// suggest a compaction bug or a pathological key
+    pub max_layer_size: Option<u64>,
+    /// Report an index_part.json larger than this many bytes, without downloading it
+    pub max_index_size: u64,
+    /// Warn about an index_part.json smaller than this many bytes for each layer it references,
+    /// as likely truncated: zero disables the check
+    pub min_index_bytes_per_layer: u64,
+    /// If set, warn about indices that do not match this JSON schema, such as the one from
+    /// [`json_schema::embedded_index_schema`]
+    pub index_schema: Option<serde_json::Value>,
+    /// Scan each index's raw JSON for layers with more than one entry, which parsing hides: an
+    /// extra pass over every index, so off by default
+    pub check_duplicate_index_entries: bool,
+    /// If set, warn about image layers whose key ranges do not start and end on multiples of
+    /// this many blocks
+    pub image_partition_size: Option<u32>,
+    /// If set, warn about image layers spanning the whole key space on timelines with more than
+    /// this many bytes of layers
+    pub full_keyspace_min_timeline_size: Option<u64>,
+    /// If set, warn about timelines where a read may visit more than this many L0 delta layers
+    pub max_l0_stack_depth: Option<usize>,
+    /// If set, warn about timelines whose index references layers of more than this many
+    /// distinct shards
+    pub max_layer_shards: Option<usize>,
+    /// Also download every index_part listed, not just the chosen one, to check that
+    /// disk_consistent_lsn never goes backwards from one generation to the next
+    pub check_index_history: bool,
+    /// If set, with `check_index_history`, warn about a generation whose index has lost more than
+    /// this percentage of the previous generation's layers, and of their bytes: more than
+    /// compaction would account for
+    pub max_layer_count_drop_percent: Option<u32>,
+    /// Warn about layer names in an index that do not parse back to the same layer from their
+    /// string form
+    pub check_layer_name_round_trip: bool,
+    /// Fail the scrub as a whole if any timeline's listing or index could not be parsed, whatever
+    /// else the findings say
+    pub fatal_on_unparseable: bool,
+    /// How findings are weighed into each timeline's health score
+    pub score_weights: ScoreWeights,
+    /// The severity to report kinds of finding at, in place of their checks' own
+    pub severity_policy: SeverityPolicy,
+    /// Download every referenced layer, and check its content against its name and the index
+    pub deep_scrub: bool,
+    /// How many bytes the deep scrub may download, across all timelines of a scrub
+    pub deep_scrub_budget: ByteBudget,
+    /// The shard counts that tenants are known to have, such as from the control plane: any shard
+    /// of such a tenant found in storage with another count is reported
+    pub expected_shard_counts: HashMap<TenantId, ShardCount>,
+    /// Timeline shards to skip entirely, such as known-bad ones already under investigation
+    pub excluded_timelines: HashSet<TenantShardTimelineId>,
+    /// Identifies this run in its findings, its summary and its logs, so that the output of
+    /// many runs can be aggregated and still told apart: not part of a saved configuration, as
+    /// every run gets a fresh one
+    #[serde(skip, default = "Uuid::new_v4")]
+    pub run_id: Uuid,
+    /// Where the checks get the current time from: not part of a saved configuration
+    #[serde(skip, default = "default_clock")]
+    pub clock: Arc<dyn Clock>,
+    /// Keep a report of every referenced layer in each timeline's analysis, such as to write
+    /// them out as CSV: not part of a saved configuration
+    #[serde(skip)]
+    pub report_layers: bool,
+    /// Called periodically while listing a timeline: not part of a saved configuration
+    #[serde(skip)]
+    pub listing_progress: Option<ListingProgress>,
+    /// Recognizers for layer name formats that this build cannot parse, such as formats introduced
+    /// by newer pageservers: not part of a saved configuration
+    #[serde(skip)]
+    pub layer_name_recognizers: Vec<Arc<dyn LayerNameRecognizer>>,
+    /// Lets the caller keep objects that the checks would otherwise report as garbage: not part of
+    /// a saved configuration
+    #[serde(skip)]
+    pub garbage_veto: Option<GarbageVeto>,
+    /// If set, check the preserved initdb archives that recovering deleted timelines relies on:
+    /// not part of a saved configuration
+    #[serde(skip)]
+    pub preserved_initdb: Option<PreservedInitdbPolicy>,
+    /// If set, check only the layers overlapping a range of keys, such as to chase one bad key
+    pub key_range_filter: Option<KeyRangeFilter>,
+    /// If set, get the tags of every referenced layer, and report those carrying this tag, which
+    /// the bucket's lifecycle tooling uses to mark objects for deletion.  This costs a request
+    /// per layer.
+    pub deletion_tag: Option<ObjectTag>,
+    /// Keep the analysis of every timeline scanned, not just of those with findings, so that the
+    /// saved findings double as an inventory of storage
+    pub include_healthy: bool,
+    /// If set, retain at most this many findings across the whole scan, and only count those
+    /// after, so that a badly broken bucket cannot exhaust memory
+    pub max_findings: Option<usize>,
+    /// If set, reconcile the layers that pageservers report having with the indices and objects
+    /// in storage: not part of a saved configuration
+    #[serde(skip)]
+    pub pageserver_layers: Option<Arc<PageserverLayers>>,
+}
+
+/// Narrows the layer checks of a scrub down to the layers whose key ranges overlap `keys`.  The
+/// other referenced layers are counted, but not checked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRangeFilter {
+    pub keys: Range<Key>,
+    /// Also run the structural checks of the layer map on the overlapping layers only, rather
+    /// than on every layer in the index
+    pub filter_layer_map_checks: bool,
+}
+
+impl KeyRangeFilter {
+    /// Parse a range of keys given as `<start>..<end>` in hex, or a single key in hex
+    pub fn parse_keys(s: &str) -> anyhow::Result<Range<Key>> {
+        match s.split_once("..") {
+            Some((start, end)) => Ok(start.parse()?..end.parse()?),
+            None => {
+                let key: Key = s.parse()?;
+                Ok(key..key.next())
+            }
+        }
+    }
+
+    pub(crate) fn overlaps(&self, layer: &LayerName) -> bool {
+        let key_range = match layer {
+            LayerName::Image(image) => &image.key_range,
+            LayerName::Delta(delta) => &delta.key_range,
+        };
+        key_range.start < self.keys.end && self.keys.start < key_range.end
+    }
+}
+
+/// A limit on the bytes downloaded, shared by every clone so that concurrent downloads draw on the
+/// same budget.  Only the limit is part of a saved configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Option<u64>", into = "Option<u64>")]
+pub struct ByteBudget {
+    limit: Option<u64>,
+    spent: Arc<AtomicU64>,
+}
+
+impl ByteBudget {
+    /// A budget of `limit` bytes, or an unlimited one
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            spent: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Spend `bytes` of the budget, unless that would go over the limit.
+    pub(crate) fn try_spend(&self, bytes: u64) -> bool {
+        let Some(limit) = self.limit else {
+            self.spent.fetch_add(bytes, Ordering::Relaxed);
+            return true;
+        };
+        self.spent
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |spent| {
+                spent.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .is_ok()
+    }
+}
+
+impl From<Option<u64>> for ByteBudget {
+    fn from(limit: Option<u64>) -> Self {
+        Self::new(limit)
+    }
+}
+
+impl From<ByteBudget> for Option<u64> {
+    fn from(budget: ByteBudget) -> Self {
+        budget.limit
+    }
+}
+
+/// A tag on an object in remote storage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectTag {
+    pub key: String,
+    pub value: String,
+}
+
+/// Parses `<key>=<value>`
+impl std::str::FromStr for ObjectTag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s.split_once('=').context("expected <key>=<value>")?;
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Display for ObjectTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// An object as listed in remote storage
+#[derive(Debug, Clone)]
+pub struct ListingObject {
+    pub key: String,
+    pub size: u64,
+}
+
+/// A callback consulted before any object is added to a timeline's garbage: returning false keeps
+/// the object.  This is the escape hatch for bucket-specific quirks that no pattern can express.
+/// It is given the object's path relative to the prefix scanned, like the pageserver's own remote
+/// paths, e.g. `tenants/<tenant shard id>/timelines/<timeline id>/<layer>`.
+#[derive(Clone)]
+pub struct GarbageVeto {
+    pub should_treat_as_garbage: Arc<dyn Fn(&RemotePath, &ListingObject) -> bool + Send + Sync>,
+}
+
+impl std::fmt::Debug for GarbageVeto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GarbageVeto").finish_non_exhaustive()
+    }
+}
+
+/// The layers that pageservers report having, by timeline shard, as names and generations.
+#[derive(Debug, Default)]
+pub struct PageserverLayers(HashMap<TenantShardTimelineId, HashSet<(LayerName, Generation)>>);
+
+impl PageserverLayers {
+    /// Parse a JSON object mapping each `<tenant shard id>/<timeline id>` to its layers' names as
+    /// in storage, generation suffix included.
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<Self> {
+        let raw: HashMap<String, Vec<String>> = serde_json::from_slice(bytes)?;
+        let mut timelines = HashMap::with_capacity(raw.len());
+        for (ttid, names) in raw {
+            let ttid = ttid
+                .parse::<TenantShardTimelineId>()
+                .with_context(|| format!("parsing timeline {ttid}"))?;
+            let layers: HashSet<_> = names
+                .iter()
+                .map(|name| {
+                    parse_layer_object_name(name)
+                        .map_err(|e| anyhow::anyhow!("parsing layer {name} of {ttid}: {e}"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+            timelines.insert(ttid, layers);
+        }
+        Ok(Self(timelines))
+    }
+
+    pub(crate) fn get(
+        &self,
+        ttid: &TenantShardTimelineId,
+    ) -> Option<&HashSet<(LayerName, Generation)>> {
+        self.0.get(ttid)
+    }
+}
+
+/// Decides which timelines must have a preserved initdb archive, such as those whose recovery
+/// depends on it, and whether the archives found are downloaded to check that they decompress.
+#[derive(Clone)]
+pub struct PreservedInitdbPolicy {
+    pub should_have_archive: Arc<dyn Fn(&TenantShardTimelineId) -> bool + Send + Sync>,
+    pub verify_integrity: bool,
+}
+
+impl std::fmt::Debug for PreservedInitdbPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PreservedInitdbPolicy")
+            .field("verify_integrity", &self.verify_integrity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Recognizes object names that are layers, even though [`LayerName`] cannot parse them.  Such
+/// objects are reported as layers this build does not understand, rather than as garbage, so that
+/// an old scrubber never proposes deleting layers written by a newer pageserver.
+///
+/// [`LayerName`]: pageserver::tenant::storage_layer::LayerName
+pub trait LayerNameRecognizer: std::fmt::Debug + Send + Sync {
+    /// `name` is the object name relative to its timeline prefix, including any generation suffix
+    fn recognizes(&self, name: &str) -> bool;
+}
+
+/// A callback invoked with the running count of objects listed, every `interval` objects, while
+/// listing a timeline's objects.  Listing a huge timeline can otherwise look like a hang.
+#[derive(Clone)]
+pub struct ListingProgress {
+    pub interval: usize,
+    pub callback: Arc<dyn Fn(&TenantShardTimelineId, usize) + Send + Sync>,
+}
+
+impl std::fmt::Debug for ListingProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ListingProgress")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScrubOptions {
+    /// Every mutation of remote storage must pass this check first.
+    pub(crate) fn check_mutation_allowed(&self, mutation: &str) -> anyhow::Result<()> {
+        if self.read_only {
+            anyhow::bail!("Refusing to {mutation}: this run is read-only");
+        }
+        Ok(())
+    }
+
+    /// Whether an object that the checks consider garbage may be reported as such.  An object
+    /// that the veto cannot be asked about, because its key does not make a remote path under
+    /// the root, is kept.
+    pub(crate) fn treat_as_garbage(&self, root: &RootTarget, key: &str, size: u64) -> bool {
+        let Some(veto) = &self.garbage_veto else {
+            return true;
+        };
+        // Keys may start with a slash, depending on prefix_in_bucket, but remote paths are
+        // relative to the root prefix
+        let root_prefix = root.prefix_in_bucket().trim_matches('/');
+        let key = key.trim_start_matches('/');
+        let relative = if root_prefix.is_empty() {
+            Some(key)
+        } else {
+            key.strip_prefix(root_prefix)
+                .and_then(|relative| relative.strip_prefix('/'))
+        };
+        let Some(Ok(path)) = relative.map(RemotePath::from_string) else {
+            return false;
+        };
+        let object = ListingObject {
+            key: key.to_string(),
+            size,
+        };
+        (veto.should_treat_as_garbage)(&path, &object)
+    }
+}
+
+fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 32,
+            retry_policy: RetryPolicy::default(),
+            index_version_window: 1,
+            old_index_version_severity: Severity::Warning,
+            target_generation: None,
+            timeline_timeout: Duration::from_secs(600),
+            index_download_timeout: Duration::from_secs(60),
+            summary_interval: None,
+            gc_horizon: None,
+            detect_subprefixes: false,
+            shard_stripe_size: None,
+            failure_mode: FailureMode::default(),
+            list_versions: false,
+            listing_page_size: None,
+            max_layer_size: None,
+            max_index_size: DEFAULT_MAX_INDEX_SIZE,
+            min_index_bytes_per_layer: DEFAULT_MIN_INDEX_BYTES_PER_LAYER,
+            index_schema: None,
+            check_duplicate_index_entries: false,
+            image_partition_size: None,
+            full_keyspace_min_timeline_size: None,
+            max_l0_stack_depth: None,
+            max_layer_shards: None,
+            check_index_history: false,
+            max_layer_count_drop_percent: None,
+            check_layer_name_round_trip: false,
+            fatal_on_unparseable: false,
+            score_weights: ScoreWeights::default(),
+            severity_policy: SeverityPolicy::default(),
+            deep_scrub: false,
+            deep_scrub_budget: ByteBudget::default(),
+            expected_shard_counts: HashMap::new(),
+            excluded_timelines: HashSet::new(),
+            read_only: false,
+            run_id: Uuid::new_v4(),
+            clock: default_clock(),
+            report_layers: false,
+            listing_progress: None,
+            layer_name_recognizers: Vec::new(),
+            garbage_veto: None,
+            preserved_initdb: None,
+            key_range_filter: None,
+            deletion_tag: None,
+            include_healthy: false,
+            max_findings: None,
+            pageserver_layers: None,
+        }
+    }
+}
+
+/// How a scan reacts to an unhealthy timeline
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureMode {
+    /// Carry on, so that the scan reports every finding
+    #[default]
+    CollectAll,
+    /// Stop at the first unhealthy timeline, e.g. to gate CI quickly
+    FailFast,
+}
+
+/// How to compress a scan's output file
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Write a scan's output to a file, compressed as requested.
+pub async fn write_output(
+    path: &Utf8Path,
+    compression: OutputCompression,
+    contents: &[u8],
+) -> anyhow::Result<()> {
+    let file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("creating {path}"))?;
+    let mut writer: Box<dyn AsyncWrite + Unpin> = match compression {
+        OutputCompression::None => Box::new(file),
+        OutputCompression::Gzip => Box::new(GzipEncoder::new(file)),
+        OutputCompression::Zstd => Box::new(ZstdEncoder::new(file)),
+    };
+    writer.write_all(contents).await?;
+    // Shutting down the writer finishes the compressed stream, as well as flushing it
+    writer.shutdown().await?;
+    Ok(())
+}
 
 #[derive(Debug, Clone)]
 pub struct S3Target {
@@ -49,8 +675,13 @@ pub struct S3Target {
     /// with extra parts.
     pub prefix_in_bucket: String,
     pub delimiter: String,
+    /// How many objects to ask for per listing request, if not the backend's default
+    pub page_size: Option<i32>,
 }
 
+/// S3 returns at most this many objects per listing request, however many are asked for
+const MAX_LISTING_PAGE_SIZE: usize = 1000;
+
 /// Convenience for referring to timelines within a particular shard: more ergonomic
 /// than using a 2-tuple.
 ///
@@ -59,7 +690,7 @@ pub struct S3Target {
 /// in the pageserver, as all timeline objects existing in the scope of a particular
 /// tenant: the scrubber is different in that it handles collections of data referring to many
 /// TenantShardTimelineIds in on place.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TenantShardTimelineId {
     tenant_shard_id: TenantShardId,
     timeline_id: TimelineId,
@@ -80,6 +711,21 @@ impl Display for TenantShardTimelineId {
     }
 }
 
+/// Parses the `Display` form, `<tenant shard id>/<timeline id>`
+impl std::str::FromStr for TenantShardTimelineId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tenant_shard_id, timeline_id) = s
+            .split_once('/')
+            .with_context(|| format!("expected <tenant shard id>/<timeline id>, got {s:?}"))?;
+        Ok(Self::new(
+            tenant_shard_id.parse().context("tenant shard id")?,
+            timeline_id.parse().context("timeline id")?,
+        ))
+    }
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TraversingDepth {
     Tenant,
@@ -169,6 +815,7 @@ impl RootTarget {
                 root.prefix_in_bucket
             ),
             delimiter: root.delimiter.clone(),
+            page_size: root.page_size,
         }
     }
 
@@ -197,6 +844,26 @@ impl RootTarget {
             Self::Safekeeper(root) => &root.delimiter,
         }
     }
+
+    /// The prefix everything is under, with the default for the node kind filled in if none was
+    /// configured
+    pub fn prefix_in_bucket(&self) -> &str {
+        match self {
+            Self::Pageserver(root) => &root.prefix_in_bucket,
+            Self::Safekeeper(root) => &root.prefix_in_bucket,
+        }
+    }
+
+    /// Set the listing page size of this and every target derived from it, clamped to what the
+    /// backend supports.
+    pub fn set_page_size(&mut self, page_size: Option<usize>) {
+        let root = match self {
+            Self::Pageserver(root) => root,
+            Self::Safekeeper(root) => root,
+        };
+        root.page_size =
+            page_size.map(|page_size| page_size.clamp(1, MAX_LISTING_PAGE_SIZE) as i32);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -205,18 +872,36 @@ pub struct BucketConfig {
     pub region: String,
     pub bucket: String,
     pub prefix_in_bucket: Option<String>,
+    /// A local directory that mirrors the bucket's layout, such as a downloaded snapshot, to scrub
+    /// instead of the bucket.  The region, bucket and profile are then ignored, and
+    /// `prefix_in_bucket` is relative to the directory.
+    #[serde(default)]
+    pub local_path: Option<String>,
+    /// The AWS credential profile to access the bucket with, from the shared config and
+    /// credentials files.  A profile with a `role_arn` assumes that role, so that buckets in other
+    /// accounts can be scrubbed in the same run.  If unset, the default credentials chain is used.
+    #[serde(default)]
+    pub profile: Option<String>,
 }
 
 impl BucketConfig {
     pub fn from_env() -> anyhow::Result<Self> {
-        let region = env::var("REGION").context("'REGION' param retrieval")?;
-        let bucket = env::var("BUCKET").context("'BUCKET' param retrieval")?;
+        let local_path = env::var("BUCKET_LOCAL_PATH").ok();
+        // A local directory needs no region or bucket
+        let required = |name: &str| match env::var(name) {
+            Err(_) if local_path.is_some() => Ok(String::new()),
+            value => value.with_context(|| format!("'{name}' param retrieval")),
+        };
+        let region = required("REGION")?;
+        let bucket = required("BUCKET")?;
         let prefix_in_bucket = env::var("BUCKET_PREFIX").ok();
 
         Ok(Self {
             region,
             bucket,
             prefix_in_bucket,
+            local_path,
+            profile: None,
         })
     }
 }
@@ -240,7 +925,8 @@ impl ConsoleConfig {
     }
 }
 
-pub fn init_logging(file_name: &str) -> WorkerGuard {
+/// Log to a file and to stderr, and if `otel` is set, export spans as OpenTelemetry traces too.
+pub fn init_logging(file_name: &str, otel: bool) -> WorkerGuard {
     let (file_writer, guard) =
         tracing_appender::non_blocking(tracing_appender::rolling::never("./logs/", file_name));
 
@@ -251,8 +937,13 @@ pub fn init_logging(file_name: &str) -> WorkerGuard {
     let stderr_logs = fmt::Layer::new()
         .with_target(false)
         .with_writer(std::io::stderr);
+    let otlp_layer = otel
+        .then(|| tracing_utils::init_tracing_without_runtime("s3_scrubber"))
+        .flatten()
+        .map(tracing_utils::OpenTelemetryLayer::new);
     tracing_subscriber::registry()
         .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(otlp_layer)
         .with(file_logs)
         .with(stderr_logs)
         .init();
@@ -260,8 +951,16 @@ pub fn init_logging(file_name: &str) -> WorkerGuard {
     guard
 }
 
-pub fn init_s3_client(bucket_region: Region) -> Client {
-    let credentials_provider = {
+pub fn init_s3_client(bucket_region: Region, profile: Option<&str>) -> Client {
+    let credentials_provider = if let Some(profile) = profile {
+        // A named profile may chain into `sts:AssumeRole` via its `role_arn` and `source_profile`
+        CredentialsProviderChain::first_try(
+            "profile",
+            ProfileFileCredentialsProvider::builder()
+                .profile_name(profile)
+                .build(),
+        )
+    } else {
         // uses "AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY"
         let chain = CredentialsProviderChain::first_try(
             "env",
@@ -301,7 +1000,7 @@ pub fn init_s3_client(bucket_region: Region) -> Client {
             BehaviorVersion::v2023_11_09(),
         )
         .region(bucket_region)
-        .retry_config(RetryConfig::adaptive().with_max_attempts(3))
+        .retry_config(aws_config::retry::RetryConfig::adaptive().with_max_attempts(3))
         .sleep_impl(SharedAsyncSleep::from(sleep_impl))
         .credentials_provider(credentials_provider);
 
@@ -312,13 +1011,39 @@ pub fn init_s3_client(bucket_region: Region) -> Client {
     Client::from_conf(builder.build())
 }
 
+/// What the scrubber reads a bucket through: S3, or a local directory that mirrors a bucket's
+/// layout, read with [`local_fs`].  Targets keep their S3 shape for a local directory, whose
+/// object keys are paths relative to it: their bucket names are ignored.
+pub enum StorageClient {
+    S3(Client),
+    LocalFs(Utf8PathBuf),
+}
+
+impl StorageClient {
+    /// The S3 client, for the operations that only S3 supports, such as anything to do with
+    /// object versions, tags or deletion.
+    pub(crate) fn s3(&self) -> anyhow::Result<&Client> {
+        match self {
+            Self::S3(s3_client) => Ok(s3_client),
+            Self::LocalFs(root) => {
+                anyhow::bail!("Not supported when scrubbing the local directory {root}")
+            }
+        }
+    }
+}
+
 fn init_remote(
     bucket_config: BucketConfig,
     node_kind: NodeKind,
-) -> anyhow::Result<(Arc<Client>, RootTarget)> {
-    let bucket_region = Region::new(bucket_config.region);
+) -> anyhow::Result<(Arc<StorageClient>, RootTarget)> {
     let delimiter = "/".to_string();
-    let s3_client = Arc::new(init_s3_client(bucket_region));
+    let s3_client = Arc::new(match bucket_config.local_path {
+        Some(local_path) => StorageClient::LocalFs(Utf8PathBuf::from(local_path)),
+        None => StorageClient::S3(init_s3_client(
+            Region::new(bucket_config.region),
+            bucket_config.profile.as_deref(),
+        )),
+    });
 
     let s3_root = match node_kind {
         NodeKind::Pageserver => RootTarget::Pageserver(S3Target {
@@ -327,53 +1052,303 @@ fn init_remote(
                 .prefix_in_bucket
                 .unwrap_or("pageserver/v1".to_string()),
             delimiter,
+            page_size: None,
         }),
         NodeKind::Safekeeper => RootTarget::Safekeeper(S3Target {
             bucket_name: bucket_config.bucket,
             prefix_in_bucket: bucket_config.prefix_in_bucket.unwrap_or("wal/".to_string()),
             delimiter,
+            page_size: None,
         }),
     };
 
     Ok((s3_client, s3_root))
 }
 
+/// List a page of objects, continuing either from a continuation token or, for resuming a listing
+/// whose token is no longer usable, after a given key.
 async fn list_objects_with_retries(
-    s3_client: &Client,
+    s3_client: &StorageClient,
     s3_target: &S3Target,
     continuation_token: Option<String>,
+    start_after: Option<String>,
+    retry_policy: &RetryPolicy,
 ) -> anyhow::Result<aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output> {
-    for _ in 0..MAX_RETRIES {
-        match s3_client
-            .list_objects_v2()
-            .bucket(&s3_target.bucket_name)
-            .prefix(&s3_target.prefix_in_bucket)
-            .delimiter(&s3_target.delimiter)
-            .set_continuation_token(continuation_token.clone())
-            .send()
-            .await
-        {
+    let s3_client = match s3_client {
+        StorageClient::S3(s3_client) => s3_client,
+        // A local listing is never truncated, so there is no continuation token to follow
+        StorageClient::LocalFs(root) => {
+            return local_fs::list_objects(root, s3_target, start_after.as_deref()).await
+        }
+    };
+    for attempt in 0..retry_policy.max_retries {
+        let result = {
+            let _permit = retry_policy.governor.acquire().await;
+            s3_client
+                .list_objects_v2()
+                .bucket(&s3_target.bucket_name)
+                .prefix(&s3_target.prefix_in_bucket)
+                .delimiter(&s3_target.delimiter)
+                .set_max_keys(s3_target.page_size)
+                .set_continuation_token(continuation_token.clone())
+                .set_start_after(start_after.clone())
+                .send()
+                .await
+        };
+        match result {
             Ok(response) => return Ok(response),
             Err(e) => {
                 error!(
                     "list_objects_v2 query failed: {e}, bucket_name={}, prefix={}, delimiter={}",
                     s3_target.bucket_name, s3_target.prefix_in_bucket, s3_target.delimiter
                 );
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                retry_policy.backoff(attempt).await?;
+            }
+        }
+    }
+
+    anyhow::bail!("Failed to list objects {} times", retry_policy.max_retries)
+}
+
+async fn list_object_versions_with_retries(
+    s3_client: &StorageClient,
+    s3_target: &S3Target,
+    key_marker: Option<String>,
+    version_id_marker: Option<String>,
+    retry_policy: &RetryPolicy,
+) -> anyhow::Result<aws_sdk_s3::operation::list_object_versions::ListObjectVersionsOutput> {
+    let s3_client = s3_client.s3()?;
+    for attempt in 0..retry_policy.max_retries {
+        let result = {
+            let _permit = retry_policy.governor.acquire().await;
+            s3_client
+                .list_object_versions()
+                .bucket(&s3_target.bucket_name)
+                .prefix(&s3_target.prefix_in_bucket)
+                .set_max_keys(s3_target.page_size)
+                .set_key_marker(key_marker.clone())
+                .set_version_id_marker(version_id_marker.clone())
+                .send()
+                .await
+        };
+        match result {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                error!(
+                    "list_object_versions query failed: {e}, bucket_name={}, prefix={}",
+                    s3_target.bucket_name, s3_target.prefix_in_bucket
+                );
+                retry_policy.backoff(attempt).await?;
             }
         }
     }
 
-    anyhow::bail!("Failed to list objects {MAX_RETRIES} times")
+    anyhow::bail!(
+        "Failed to list object versions {} times",
+        retry_policy.max_retries
+    )
 }
 
+/// An object version that is not the current one, in a bucket with versioning enabled.  S3 keeps,
+/// and bills for, these until a lifecycle rule or an explicit versioned delete removes them.
+#[derive(Debug, Clone)]
+pub(crate) struct NoncurrentVersion {
+    pub(crate) key: String,
+    pub(crate) version_id: String,
+    pub(crate) size: u64,
+}
+
+/// List the noncurrent versions of every object under a prefix.
+pub(crate) async fn list_noncurrent_versions(
+    s3_client: &StorageClient,
+    s3_target: &S3Target,
+    retry_policy: &RetryPolicy,
+) -> anyhow::Result<Vec<NoncurrentVersion>> {
+    let mut noncurrent = Vec::new();
+    let mut key_marker = None;
+    let mut version_id_marker = None;
+    loop {
+        let response = list_object_versions_with_retries(
+            s3_client,
+            s3_target,
+            key_marker,
+            version_id_marker,
+            retry_policy,
+        )
+        .await?;
+
+        for version in response.versions() {
+            if version.is_latest().unwrap_or(true) {
+                continue;
+            }
+            let (Some(key), Some(version_id)) = (version.key(), version.version_id()) else {
+                continue;
+            };
+            noncurrent.push(NoncurrentVersion {
+                key: key.to_string(),
+                version_id: version_id.to_string(),
+                size: version.size().unwrap_or(0) as u64,
+            });
+        }
+
+        if !response.is_truncated().unwrap_or(false) {
+            break;
+        }
+        key_marker = response.next_key_marker().map(str::to_string);
+        version_id_marker = response.next_version_id_marker().map(str::to_string);
+    }
+
+    Ok(noncurrent)
+}
+
+/// Check whether each of a set of objects exists.  S3 has no batched metadata query, so this fans
+/// out individual HEAD requests, at most `concurrency` at a time.
+pub(crate) async fn head_objects_batch(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    keys: impl Iterator<Item = String>,
+    concurrency: usize,
+    retry_policy: &RetryPolicy,
+) -> HashMap<String, anyhow::Result<bool>> {
+    futures::stream::iter(keys)
+        .map(|key| async move {
+            let exists =
+                object_exists_with_retries(s3_client, bucket_name, &key, retry_policy).await;
+            (key, exists)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await
+}
+
+/// Get the tags of many objects, with at most `concurrency` requests in flight at once.
+pub(crate) async fn get_object_tags_batch(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    keys: impl Iterator<Item = String>,
+    concurrency: usize,
+    retry_policy: &RetryPolicy,
+) -> HashMap<String, anyhow::Result<HashMap<String, String>>> {
+    futures::stream::iter(keys)
+        .map(|key| async move {
+            let tags =
+                get_object_tags_with_retries(s3_client, bucket_name, &key, retry_policy).await;
+            (key, tags)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await
+}
+
+async fn get_object_tags_with_retries(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    key: &str,
+    retry_policy: &RetryPolicy,
+) -> anyhow::Result<HashMap<String, String>> {
+    let s3_client = s3_client.s3()?;
+    for attempt in 0..retry_policy.max_retries {
+        let result = {
+            let _permit = retry_policy.governor.acquire().await;
+            s3_client
+                .get_object_tagging()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+        };
+        match result {
+            Ok(output) => {
+                return Ok(output
+                    .tag_set()
+                    .iter()
+                    .map(|tag| (tag.key().to_string(), tag.value().to_string()))
+                    .collect())
+            }
+            Err(e) => {
+                error!("Failed to get tags of object with key {key}: {e}");
+                retry_policy.backoff(attempt).await?;
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "Failed to get tags of object with key {key} {} times",
+        retry_policy.max_retries
+    )
+}
+
+/// Check whether an object exists, retrying errors other than the object not being found.
+async fn object_exists_with_retries(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    key: &str,
+    retry_policy: &RetryPolicy,
+) -> anyhow::Result<bool> {
+    let s3_client = match s3_client {
+        StorageClient::S3(s3_client) => s3_client,
+        StorageClient::LocalFs(root) => return local_fs::object_exists(root, key).await,
+    };
+    for attempt in 0..retry_policy.max_retries {
+        let result = {
+            let _permit = retry_policy.governor.acquire().await;
+            s3_client
+                .head_object()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+        };
+        match result {
+            Ok(_) => return Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(false),
+            Err(e) => {
+                error!("Failed to check existence of object with key {key}: {e}");
+                retry_policy.backoff(attempt).await?;
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "Failed to check existence of object with key {key} {} times",
+        retry_policy.max_retries
+    )
+}
+
+/// A request failed, and was not retried because the run's retry budget was spent
+#[derive(Debug, thiserror::Error)]
+#[error("retry budget exhausted, not retrying")]
+pub(crate) struct RetryBudgetExhausted;
+
+/// A download was abandoned because the object is larger than the caller accepts
+#[derive(Debug, thiserror::Error)]
+#[error("object {key} is larger than the limit of {limit} bytes")]
+pub(crate) struct ObjectTooLarge {
+    key: String,
+    limit: u64,
+}
+
+/// Download an object into memory.  If `max_bytes` is set, an object larger than that fails with
+/// [`ObjectTooLarge`], without retrying and without reading more than `max_bytes` of it.
 async fn download_object_with_retries(
-    s3_client: &Client,
+    s3_client: &StorageClient,
     bucket_name: &str,
     key: &str,
+    max_bytes: Option<u64>,
+    retry_policy: &RetryPolicy,
 ) -> anyhow::Result<Vec<u8>> {
-    for _ in 0..MAX_RETRIES {
+    let s3_client = match s3_client {
+        StorageClient::S3(s3_client) => s3_client,
+        StorageClient::LocalFs(root) => return local_fs::read_object(root, key, max_bytes).await,
+    };
+    let limit = max_bytes.unwrap_or(u64::MAX);
+    let too_large = || ObjectTooLarge {
+        key: key.to_string(),
+        limit,
+    };
+    for attempt in 0..retry_policy.max_retries {
         let mut body_buf = Vec::new();
+        let permit = retry_policy.governor.acquire().await;
         let response_stream = match s3_client
             .get_object()
             .bucket(bucket_name)
@@ -384,40 +1359,144 @@ async fn download_object_with_retries(
             Ok(response) => response,
             Err(e) => {
                 error!("Failed to download object for key {key}: {e}");
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                drop(permit);
+                retry_policy.backoff(attempt).await?;
                 continue;
             }
         };
 
+        // Refuse up front if the object says it is too large, and stop reading if it lies
+        if response_stream
+            .content_length()
+            .is_some_and(|length| u64::try_from(length).is_ok_and(|length| length > limit))
+        {
+            return Err(too_large().into());
+        }
         match response_stream
             .body
             .into_async_read()
+            .take(limit.saturating_add(1))
             .read_to_end(&mut body_buf)
             .await
         {
+            Ok(_) if body_buf.len() as u64 > limit => return Err(too_large().into()),
             Ok(bytes_read) => {
                 tracing::info!("Downloaded {bytes_read} bytes for object object with key {key}");
                 return Ok(body_buf);
             }
             Err(e) => {
                 error!("Failed to stream object body for key {key}: {e}");
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                drop(permit);
+                retry_policy.backoff(attempt).await?;
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "Failed to download objects with key {key} {} times",
+        retry_policy.max_retries
+    )
+}
+
+/// Download the first `len` bytes of an object, or all of it if it is smaller, with a ranged GET.
+/// Returns them with the size of the whole object, if known: reading no bytes makes no request,
+/// so tells nothing of the size.
+async fn download_object_prefix_with_retries(
+    s3_client: &StorageClient,
+    bucket_name: &str,
+    key: &str,
+    len: u64,
+    retry_policy: &RetryPolicy,
+) -> anyhow::Result<(Vec<u8>, Option<u64>)> {
+    let s3_client = match s3_client {
+        StorageClient::S3(s3_client) => s3_client,
+        StorageClient::LocalFs(root) => {
+            let (bytes, size) = local_fs::read_object_prefix(root, key, len).await?;
+            return Ok((bytes, Some(size)));
+        }
+    };
+    if len == 0 {
+        return Ok((Vec::new(), None));
+    }
+    for attempt in 0..retry_policy.max_retries {
+        let mut body_buf = Vec::new();
+        let permit = retry_policy.governor.acquire().await;
+        let response = match s3_client
+            .get_object()
+            .bucket(bucket_name)
+            .key(key)
+            .range(format!("bytes=0-{}", len.saturating_sub(1)))
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            // No range is satisfiable on an empty object, not even one starting at 0
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 416) => {
+                return Ok((Vec::new(), Some(0)));
+            }
+            Err(e) => {
+                error!("Failed to download the start of object for key {key}: {e}");
+                drop(permit);
+                retry_policy.backoff(attempt).await?;
+                continue;
+            }
+        };
+
+        // A ranged response gives the whole object's size after the slash of `bytes 0-N/SIZE`
+        let object_size = response
+            .content_range()
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, size)| size.parse::<u64>().ok())
+            .or_else(|| {
+                response
+                    .content_length()
+                    .and_then(|length| u64::try_from(length).ok())
+            });
+        let Some(object_size) = object_size else {
+            anyhow::bail!("Response for object {key} does not say how large it is");
+        };
+        match response
+            .body
+            .into_async_read()
+            .take(len)
+            .read_to_end(&mut body_buf)
+            .await
+        {
+            Ok(_) => return Ok((body_buf, Some(object_size))),
+            Err(e) => {
+                error!("Failed to stream object body for key {key}: {e}");
+                drop(permit);
+                retry_policy.backoff(attempt).await?;
             }
         }
     }
 
-    anyhow::bail!("Failed to download objects with key {key} {MAX_RETRIES} times")
+    anyhow::bail!(
+        "Failed to download the start of object with key {key} {} times",
+        retry_policy.max_retries
+    )
 }
 
 async fn download_object_to_file(
-    s3_client: &Client,
+    s3_client: &StorageClient,
     bucket_name: &str,
     key: &str,
     version_id: Option<&str>,
     local_path: &Utf8Path,
+    retry_policy: &RetryPolicy,
 ) -> anyhow::Result<()> {
+    let s3_client = match s3_client {
+        StorageClient::S3(s3_client) => s3_client,
+        StorageClient::LocalFs(root) => {
+            anyhow::ensure!(
+                version_id.is_none(),
+                "A local directory has no object versions"
+            );
+            return local_fs::copy_object_to_file(root, key, local_path).await;
+        }
+    };
     let tmp_path = Utf8PathBuf::from(format!("{local_path}.tmp"));
-    for _ in 0..MAX_RETRIES {
+    for attempt in 0..retry_policy.max_retries {
         tokio::fs::remove_file(&tmp_path)
             .await
             .or_else(fs_ext::ignore_not_found)?;
@@ -433,6 +1512,7 @@ async fn download_object_to_file(
             None => request,
         };
 
+        let permit = retry_policy.governor.acquire().await;
         let response_stream = match request.send().await {
             Ok(response) => response,
             Err(e) => {
@@ -440,7 +1520,8 @@ async fn download_object_to_file(
                     "Failed to download object for key {key} version {}: {e:#}",
                     version_id.unwrap_or("")
                 );
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                drop(permit);
+                retry_policy.backoff(attempt).await?;
                 continue;
             }
         };
@@ -448,10 +1529,98 @@ async fn download_object_to_file(
         let mut read_stream = response_stream.body.into_async_read();
 
         tokio::io::copy(&mut read_stream, &mut file).await?;
+        drop(permit);
 
         tokio::fs::rename(&tmp_path, local_path).await?;
         return Ok(());
     }
 
-    anyhow::bail!("Failed to download objects with key {key} {MAX_RETRIES} times")
+    anyhow::bail!(
+        "Failed to download objects with key {key} {} times",
+        retry_policy.max_retries
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_grows_up_to_the_cap() {
+        let retry_policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            ..Default::default()
+        };
+        for attempt in 0..4 {
+            for _ in 0..100 {
+                assert!(
+                    retry_policy.backoff_delay(attempt) <= Duration::from_millis(100 << attempt)
+                );
+            }
+        }
+        // Far past the cap, or past what a shift can represent
+        for attempt in [10, 31, 32, 1000, usize::MAX] {
+            assert!(retry_policy.backoff_delay(attempt) <= Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_budget_is_spent_only_on_retries() {
+        let retry_policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            budget: RetryBudget::new(1),
+            ..Default::default()
+        };
+
+        // No retry follows the last attempt, so it takes nothing from the budget
+        retry_policy.backoff(2).await.unwrap();
+        assert!(!retry_policy.budget.is_exhausted());
+
+        retry_policy.backoff(0).await.unwrap();
+        let e = retry_policy.backoff(1).await.unwrap_err();
+        assert!(e.is::<RetryBudgetExhausted>());
+        assert!(retry_policy.budget.is_exhausted());
+    }
+
+    #[test]
+    fn garbage_veto_sees_paths_relative_to_the_root() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = ScrubOptions {
+            garbage_veto: Some(GarbageVeto {
+                should_treat_as_garbage: Arc::new({
+                    let seen = seen.clone();
+                    move |path: &RemotePath, _: &ListingObject| {
+                        seen.lock().unwrap().push(path.to_string());
+                        true
+                    }
+                }),
+            }),
+            ..Default::default()
+        };
+        let root = |prefix: &str| {
+            RootTarget::Pageserver(S3Target {
+                bucket_name: "bucket".to_string(),
+                prefix_in_bucket: prefix.to_string(),
+                delimiter: "/".to_string(),
+                page_size: None,
+            })
+        };
+
+        let key = "pageserver/v1/tenants/t/timelines/l/garbage";
+        assert!(options.treat_as_garbage(&root("pageserver/v1"), key, 1));
+        assert!(options.treat_as_garbage(&root("pageserver/v1/"), key, 1));
+        assert!(options.treat_as_garbage(&root(""), "/tenants/t/timelines/l/garbage", 1));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["tenants/t/timelines/l/garbage"; 3]
+        );
+
+        // Keys outside the root are never handed to the veto, so are kept
+        assert!(!options.treat_as_garbage(&root("pageserver/v1"), "pageserver/v10/x", 1));
+        assert!(!options.treat_as_garbage(&root("pageserver/v1"), "other/x", 1));
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
 }
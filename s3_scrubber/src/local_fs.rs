@@ -0,0 +1,317 @@
+//! Reading a local directory that mirrors a bucket's layout as if it were the bucket, for offline
+//! analysis of a downloaded snapshot.
+//!
+//! Object keys are the paths of files relative to the directory, with `/` separators and no
+//! leading slash.  Listings follow S3's semantics rather than the filesystem's: a prefix need not
+//! end at a path separator, keys come in lexicographic order, and with a delimiter only the
+//! directories directly under the prefix are listed, as common prefixes ending in the delimiter.
+//! Empty directories hold no objects, so they are never listed.
+
+use std::io;
+
+use anyhow::Context;
+use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Output;
+use aws_sdk_s3::primitives::DateTime;
+use aws_sdk_s3::types::{CommonPrefix, Object};
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::io::AsyncReadExt;
+
+use crate::{ObjectTooLarge, S3Target};
+
+/// The file that holds an object
+fn object_path(root: &Utf8Path, key: &str) -> Utf8PathBuf {
+    root.join(key.trim_start_matches('/'))
+}
+
+/// List the objects under a target in a single page, after `start_after` if set.
+pub(crate) async fn list_objects(
+    root: &Utf8Path,
+    target: &S3Target,
+    start_after: Option<&str>,
+) -> anyhow::Result<ListObjectsV2Output> {
+    anyhow::ensure!(
+        target.delimiter.is_empty() || target.delimiter == "/",
+        "A local directory can only be listed with the path separator as delimiter, not {:?}",
+        target.delimiter
+    );
+    let recursive = target.delimiter.is_empty();
+
+    // Everything under the prefix is in the directory named by its part up to the last slash,
+    // whose entries must start with the rest of it.
+    let prefix = target.prefix_in_bucket.trim_start_matches('/');
+    let (dir_key, name_prefix) = match prefix.rsplit_once('/') {
+        Some((dir, name_prefix)) => (format!("{dir}/"), name_prefix),
+        None => (String::new(), prefix),
+    };
+
+    let mut keys = Vec::new();
+    let mut common_prefixes = Vec::new();
+    let mut dirs = vec![(dir_key, name_prefix)];
+    while let Some((dir_key, name_prefix)) = dirs.pop() {
+        let dir_path = object_path(root, &dir_key);
+        let mut entries = match tokio::fs::read_dir(&dir_path).await {
+            Ok(entries) => entries,
+            // Like a prefix without objects
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Reading directory {dir_path}")),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Reading directory {dir_path}"))?
+        {
+            let Ok(name) = entry.file_name().into_string() else {
+                anyhow::bail!("Directory {dir_path} has an entry whose name is not UTF-8");
+            };
+            if !name.starts_with(name_prefix) {
+                continue;
+            }
+            let key = format!("{dir_key}{name}");
+            let metadata = entry
+                .metadata()
+                .await
+                .with_context(|| format!("Reading metadata of {dir_path}/{name}"))?;
+            if metadata.is_dir() {
+                if recursive {
+                    dirs.push((format!("{key}/"), ""));
+                } else {
+                    common_prefixes.push(format!("{key}/"));
+                }
+            } else {
+                let modified = metadata.modified().ok().map(DateTime::from);
+                keys.push((key, metadata.len(), modified));
+            }
+        }
+    }
+
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+    common_prefixes.sort();
+    if let Some(start_after) = start_after {
+        keys.retain(|(key, _, _)| key.as_str() > start_after);
+        common_prefixes.retain(|prefix| prefix.as_str() > start_after);
+    }
+
+    let contents = keys
+        .into_iter()
+        .map(|(key, size, modified)| {
+            Object::builder()
+                .key(key)
+                .size(size as i64)
+                .set_last_modified(modified)
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let common_prefixes = common_prefixes
+        .into_iter()
+        .map(|prefix| CommonPrefix::builder().prefix(prefix).build())
+        .collect::<Vec<_>>();
+    Ok(ListObjectsV2Output::builder()
+        .key_count((contents.len() + common_prefixes.len()) as i32)
+        .set_contents(Some(contents))
+        .set_common_prefixes(Some(common_prefixes))
+        .is_truncated(false)
+        .build())
+}
+
+/// Whether an object exists
+pub(crate) async fn object_exists(root: &Utf8Path, key: &str) -> anyhow::Result<bool> {
+    let path = object_path(root, key);
+    match tokio::fs::metadata(&path).await {
+        Ok(metadata) => Ok(metadata.is_file()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Reading metadata of {path}")),
+    }
+}
+
+/// Read an object into memory, failing with [`ObjectTooLarge`] if it is larger than `max_bytes`.
+pub(crate) async fn read_object(
+    root: &Utf8Path,
+    key: &str,
+    max_bytes: Option<u64>,
+) -> anyhow::Result<Vec<u8>> {
+    let (bytes, size) = read_object_prefix(root, key, max_bytes.unwrap_or(u64::MAX)).await?;
+    if let Some(limit) = max_bytes.filter(|limit| size > *limit) {
+        return Err(ObjectTooLarge {
+            key: key.to_string(),
+            limit,
+        }
+        .into());
+    }
+    Ok(bytes)
+}
+
+/// Read the first `len` bytes of an object, or all of it if it is smaller.  Returns them with the
+/// size of the whole object.
+pub(crate) async fn read_object_prefix(
+    root: &Utf8Path,
+    key: &str,
+    len: u64,
+) -> anyhow::Result<(Vec<u8>, u64)> {
+    let path = object_path(root, key);
+    let file = tokio::fs::File::open(&path)
+        .await
+        .with_context(|| format!("Opening {path}"))?;
+    let size = file
+        .metadata()
+        .await
+        .with_context(|| format!("Reading metadata of {path}"))?
+        .len();
+    let mut bytes = Vec::new();
+    file.take(len)
+        .read_to_end(&mut bytes)
+        .await
+        .with_context(|| format!("Reading {path}"))?;
+    Ok((bytes, size))
+}
+
+/// Copy an object to a local file
+pub(crate) async fn copy_object_to_file(
+    root: &Utf8Path,
+    key: &str,
+    local_path: &Utf8Path,
+) -> anyhow::Result<()> {
+    let path = object_path(root, key);
+    tokio::fs::copy(&path, local_path)
+        .await
+        .with_context(|| format!("Copying {path} to {local_path}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino_tempfile::Utf8TempDir;
+
+    use super::*;
+
+    fn target(prefix: &str, delimiter: &str) -> S3Target {
+        S3Target {
+            bucket_name: "unused".to_string(),
+            prefix_in_bucket: prefix.to_string(),
+            delimiter: delimiter.to_string(),
+            page_size: None,
+        }
+    }
+
+    fn write_objects(root: &Utf8Path, keys: &[&str]) {
+        for key in keys {
+            let path = root.join(key);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, key.as_bytes()).unwrap();
+        }
+    }
+
+    fn listed_keys(listing: &ListObjectsV2Output) -> Vec<&str> {
+        listing.contents().iter().filter_map(|o| o.key()).collect()
+    }
+
+    fn listed_prefixes(listing: &ListObjectsV2Output) -> Vec<&str> {
+        listing
+            .common_prefixes()
+            .iter()
+            .filter_map(|p| p.prefix())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lists_every_key_under_a_prefix_in_order() {
+        let root = Utf8TempDir::new().unwrap();
+        write_objects(
+            root.path(),
+            &[
+                "v1/tenants/b/timelines/t/index_part.json",
+                "v1/tenants/a/timelines/t/layer",
+                "v1/tenants/a/timelines/t/index_part.json",
+                "v1/other",
+            ],
+        );
+
+        let listing = list_objects(root.path(), &target("v1/tenants/", ""), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            listed_keys(&listing),
+            vec![
+                "v1/tenants/a/timelines/t/index_part.json",
+                "v1/tenants/a/timelines/t/layer",
+                "v1/tenants/b/timelines/t/index_part.json",
+            ]
+        );
+        assert_eq!(listing.contents()[1].size(), Some(30));
+        assert!(listed_prefixes(&listing).is_empty());
+
+        let listing = list_objects(
+            root.path(),
+            &target("v1/tenants/", ""),
+            Some("v1/tenants/a/timelines/t/layer"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            listed_keys(&listing),
+            vec!["v1/tenants/b/timelines/t/index_part.json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_directories_as_common_prefixes_with_a_delimiter() {
+        let root = Utf8TempDir::new().unwrap();
+        write_objects(
+            root.path(),
+            &[
+                "tenants/1234-0102/timelines/t/index_part.json",
+                "tenants/1234-0002/timelines/t/index_part.json",
+                "tenants/5678/timelines/t/index_part.json",
+            ],
+        );
+
+        // Tenant shards are listed by a prefix that stops in the middle of a name
+        let listing = list_objects(root.path(), &target("tenants/1234", "/"), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            listed_prefixes(&listing),
+            vec!["tenants/1234-0002/", "tenants/1234-0102/"]
+        );
+        assert!(listed_keys(&listing).is_empty());
+
+        // Prefixes derived from an empty root prefix start with a slash
+        let listing = list_objects(root.path(), &target("/tenants/", "/"), None)
+            .await
+            .unwrap();
+        assert_eq!(listed_prefixes(&listing).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn missing_prefix_lists_nothing() {
+        let root = Utf8TempDir::new().unwrap();
+        let listing = list_objects(root.path(), &target("tenants/", "/"), None)
+            .await
+            .unwrap();
+        assert!(listed_keys(&listing).is_empty());
+        assert!(listed_prefixes(&listing).is_empty());
+        assert!(!object_exists(root.path(), "tenants/x").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reads_objects_within_limits() {
+        let root = Utf8TempDir::new().unwrap();
+        write_objects(root.path(), &["a/object"]);
+
+        assert!(object_exists(root.path(), "a/object").await.unwrap());
+        assert!(!object_exists(root.path(), "a").await.unwrap());
+        assert_eq!(
+            read_object(root.path(), "a/object", None).await.unwrap(),
+            b"a/object"
+        );
+        assert_eq!(
+            read_object_prefix(root.path(), "a/object", 3)
+                .await
+                .unwrap(),
+            (b"a/o".to_vec(), 8)
+        );
+        let e = read_object(root.path(), "a/object", Some(4))
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<ObjectTooLarge>().is_some());
+    }
+}
@@ -1,16 +1,28 @@
-use anyhow::bail;
-use camino::Utf8PathBuf;
-use pageserver_api::shard::TenantShardId;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use camino::{Utf8Path, Utf8PathBuf};
+use pageserver_api::key::Key;
+use pageserver_api::shard::{ShardCount, ShardStripeSize, TenantShardId};
+use s3_scrubber::checks::{CheckCode, Severity, SeverityPolicy};
 use s3_scrubber::garbage::{find_garbage, purge_garbage, PurgeMode};
+use s3_scrubber::json_schema::embedded_index_schema;
+use s3_scrubber::metadata_stream::dump_tenant_keys;
 use s3_scrubber::scan_pageserver_metadata::scan_metadata;
 use s3_scrubber::tenant_snapshot::SnapshotDownloader;
 use s3_scrubber::{
-    init_logging, scan_safekeeper_metadata::scan_safekeeper_metadata, BucketConfig, ConsoleConfig,
-    NodeKind, TraversingDepth,
+    init_logging, scan_safekeeper_metadata::scan_safekeeper_metadata, write_output, BucketConfig,
+    ByteBudget, ConsoleConfig, FailureMode, KeyRangeFilter, ListingProgress, NodeKind, ObjectTag,
+    OutputCompression, PageserverLayers, RequestGovernor, RetryBudget, RetryPolicy, ScrubOptions,
+    TenantShardTimelineId, TraversingDepth,
 };
 
-use clap::{Parser, Subcommand};
-use utils::id::TenantId;
+use clap::{Parser, Subcommand, ValueEnum};
+use utils::generation::Generation;
+use utils::id::{TenantId, TimelineId};
+use utils::lsn::Lsn;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,6 +33,14 @@ struct Cli {
 
     #[arg(short, long, default_value_t = false)]
     delete: bool,
+
+    /// Guarantee that nothing in remote storage is modified: any attempt fails the run
+    #[arg(long, default_value_t = false)]
+    read_only: bool,
+
+    /// Export spans as OpenTelemetry traces, configured by the usual OTEL_* environment variables
+    #[arg(long, default_value_t = false)]
+    otel: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -45,6 +65,12 @@ enum Command {
         node_kind: NodeKind,
         #[arg(short, long, default_value_t = false)]
         json: bool,
+        /// Write the output to this file instead of stdout
+        #[arg(long, default_value = None)]
+        output_path: Option<Utf8PathBuf>,
+        /// How to compress the output file
+        #[arg(long, value_enum, default_value_t = OutputCompression::None)]
+        output_compression: OutputCompression,
         #[arg(long = "tenant-id", num_args = 0..)]
         tenant_ids: Vec<TenantShardId>,
         #[arg(long, default_value = None)]
@@ -53,6 +79,216 @@ enum Command {
         /// For safekeeper node_kind only, table in the db with debug dump
         #[arg(long, default_value = None)]
         dump_db_table: Option<String>,
+        /// For pageserver node_kind only, how many of the newest index_part versions count as current
+        #[arg(long, default_value_t = 1)]
+        index_version_window: usize,
+        /// For pageserver node_kind only, how to report indices older than the version window
+        #[arg(long, default_value_t = Severity::Warning)]
+        old_index_version_severity: Severity,
+        /// For pageserver node_kind only, check timelines against their index_part.json from this
+        /// generation instead of the latest one
+        #[arg(long, default_value = None)]
+        index_generation: Option<u32>,
+        /// For pageserver node_kind only, give up listing a timeline, or checking it, after this
+        /// many seconds
+        #[arg(long, default_value_t = 600)]
+        timeline_timeout_secs: u64,
+        /// For pageserver node_kind only, give up downloading a timeline's index_part.json after
+        /// this many seconds, and report the timeline as unreadable
+        #[arg(long, default_value_t = 60)]
+        index_download_timeout_secs: u64,
+        /// For pageserver node_kind only, log the summary so far every this many seconds
+        #[arg(long, default_value = None)]
+        summary_interval_secs: Option<u64>,
+        /// For pageserver node_kind only, check that timelines are readable at this LSN
+        #[arg(long, default_value = None)]
+        gc_horizon: Option<Lsn>,
+        /// For pageserver node_kind only, write one CSV row per referenced layer to this file
+        #[arg(long, default_value = None)]
+        layers_csv: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, write a deletion-ready manifest of orphan layers to
+        /// this file
+        #[arg(long, default_value = None)]
+        orphan_manifest: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, report unexpected subprefixes of a timeline as a whole
+        /// instead of reporting each object under them
+        #[arg(long, default_value_t = false)]
+        detect_subprefixes: bool,
+        /// For pageserver node_kind only, print a running count every this many objects listed
+        /// in a timeline
+        #[arg(long, default_value = None)]
+        listing_progress_interval: Option<usize>,
+        /// For pageserver node_kind only, the tenants' shard stripe size in pages, to check that
+        /// sharded layers cover keys in their own shard's stripes
+        #[arg(long, default_value = None)]
+        shard_stripe_size: Option<u32>,
+        /// For pageserver node_kind only, stop at the first unhealthy timeline
+        #[arg(long, default_value_t = false, conflicts_with = "collect_all")]
+        fail_fast: bool,
+        /// For pageserver node_kind only, report on every timeline even if some are unhealthy:
+        /// this is the default
+        #[arg(long, default_value_t = false)]
+        collect_all: bool,
+        /// For pageserver node_kind only, in a versioned bucket, also count the noncurrent
+        /// versions of layer and index objects
+        #[arg(long, default_value_t = false)]
+        list_versions: bool,
+        /// For pageserver node_kind only, how many objects to ask for per listing request, at
+        /// most 1000
+        #[arg(long, default_value = None)]
+        listing_page_size: Option<usize>,
+        /// For pageserver node_kind only, save the findings of every timeline to this file, to
+        /// compare a later run against
+        #[arg(long, default_value = None)]
+        findings_path: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, report which findings appeared or were resolved since
+        /// the run that saved these findings
+        #[arg(long, default_value = None)]
+        previous_findings: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, compare runs: report every timeline whose fingerprint
+        /// differs from the run that saved these findings with `--include-healthy`, e.g. before a
+        /// migration, and fail if any does.  Implies `--include-healthy`
+        #[arg(long, default_value = None)]
+        baseline_findings: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, with --baseline-findings, also count timelines whose
+        /// disk_consistent_lsn moved as changed: only for timelines that should not have ingested
+        /// since the baseline
+        #[arg(long, default_value_t = false, requires = "baseline_findings")]
+        baseline_compare_lsn: bool,
+        /// For pageserver node_kind only, also write the saved findings to this file as a SQL
+        /// script creating and filling tables of timelines, findings and garbage, e.g. to load
+        /// into SQLite
+        #[arg(long, default_value = None)]
+        findings_sql: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, also send the findings to the syslog daemon listening on
+        /// this socket, e.g. /dev/log, with errors at severity err and warnings at warning
+        #[arg(long, default_value = None)]
+        findings_syslog: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, warn about layers larger than this many bytes
+        #[arg(long, default_value = None)]
+        max_layer_size_bytes: Option<u64>,
+        /// For pageserver node_kind only, the most S3 requests to have in flight at once, across
+        /// tenant discovery, timeline listing and layer checks combined
+        #[arg(long, default_value = None)]
+        max_requests_in_flight: Option<usize>,
+        /// For pageserver node_kind only, the most S3 request retries to make across the whole
+        /// run: once they are spent, failed requests fail at once
+        #[arg(long, default_value = None)]
+        retry_budget: Option<usize>,
+        /// For pageserver node_kind only, report an index_part.json larger than this many bytes
+        /// as an error, without downloading it
+        #[arg(long, default_value = None)]
+        max_index_size_bytes: Option<u64>,
+        /// For pageserver node_kind only, warn about an index_part.json smaller than this many
+        /// bytes for each layer it references, as likely truncated: zero disables the check
+        #[arg(long, default_value = None)]
+        min_index_bytes_per_layer: Option<u64>,
+        /// For pageserver node_kind only, warn about indices that do not match the schema of
+        /// index_part.json that is built into the scrubber
+        #[arg(long, default_value_t = false)]
+        validate_index_schema: bool,
+        /// For pageserver node_kind only, warn about indices that do not match the JSON schema in
+        /// this file, instead of the built-in one
+        #[arg(long, default_value = None)]
+        index_schema_path: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, warn about layers with more than one entry in an
+        /// index_part.json, of which parsing keeps only the last
+        #[arg(long, default_value_t = false)]
+        check_duplicate_index_entries: bool,
+        /// For pageserver node_kind only, warn about image layers whose key ranges do not start
+        /// and end on multiples of this many blocks
+        #[arg(long, default_value = None)]
+        image_partition_blocks: Option<u32>,
+        /// For pageserver node_kind only, warn about image layers spanning the whole key space
+        /// on timelines with more than this many bytes of layers
+        #[arg(long, default_value = None)]
+        full_keyspace_min_timeline_bytes: Option<u64>,
+        /// For pageserver node_kind only, warn about timelines where a read may visit more than
+        /// this many L0 delta layers
+        #[arg(long, default_value = None)]
+        max_l0_stack_depth: Option<usize>,
+        /// For pageserver node_kind only, warn about timelines referencing layers of more than
+        /// this many distinct shards, e.g. after repeated splits
+        #[arg(long, default_value = None)]
+        max_layer_shards: Option<usize>,
+        /// For pageserver node_kind only, download every index_part of a timeline, not just the
+        /// latest, to check that disk_consistent_lsn never goes backwards across generations
+        #[arg(long, default_value_t = false)]
+        check_index_history: bool,
+        /// For pageserver node_kind only, with --check-index-history, warn about a generation
+        /// whose index lost more than this percentage of the previous generation's layers, and of
+        /// their bytes, as a mass delete would and compaction would not
+        #[arg(long, default_value = None, requires = "check_index_history")]
+        max_layer_count_drop_percent: Option<u32>,
+        /// For pageserver node_kind only, warn about layer names that do not parse back to the
+        /// same layer from their string form, which would mean looking for them at wrong paths
+        #[arg(long, default_value_t = false)]
+        check_layer_name_round_trip: bool,
+        /// For pageserver node_kind only, fail the scrub if any timeline's listing or index could
+        /// not be parsed, as a gate that a single unparseable index should block
+        #[arg(long, default_value_t = false)]
+        fatal_on_unparseable: bool,
+        /// For pageserver node_kind only, check only the layers overlapping this key range, given
+        /// as <start>..<end> or as a single key, both in hex
+        #[arg(long, default_value = None, value_parser = KeyRangeFilter::parse_keys)]
+        key_range: Option<Range<Key>>,
+        /// For pageserver node_kind only, also restrict the structural checks of the layer map
+        /// to the layers overlapping the key range
+        #[arg(long, default_value_t = false, requires = "key_range")]
+        filter_layer_map_checks: bool,
+        /// For pageserver node_kind only, save every timeline to the findings file, healthy or
+        /// not, with its layer count, layer bytes and disk_consistent_lsn, as an inventory
+        #[arg(long, default_value_t = false, requires = "findings_path")]
+        include_healthy: bool,
+        /// For pageserver node_kind only, get the tags of every referenced layer, and report those
+        /// tagged for deletion with this <key>=<value> tag: costs a request per layer
+        #[arg(long, default_value = None)]
+        deletion_tag: Option<ObjectTag>,
+        /// For pageserver node_kind only, retain at most this many findings across the scan, and
+        /// after that only count them by kind, to bound memory use on a badly broken bucket
+        #[arg(long, default_value = None)]
+        max_findings: Option<usize>,
+        /// For pageserver node_kind only, report a kind of finding at another severity than its
+        /// check does, given as <check code>=<info|warning|error>: may be repeated
+        #[arg(long = "severity", value_parser = parse_severity_override)]
+        severity_overrides: Vec<(CheckCode, Severity)>,
+        /// For pageserver node_kind only, download every referenced layer and check its content
+        #[arg(long, default_value_t = false)]
+        deep_scrub: bool,
+        /// For pageserver node_kind only, stop deep scrubbing once this many bytes of layers have
+        /// been downloaded: the other checks carry on
+        #[arg(long, default_value = None, requires = "deep_scrub")]
+        deep_scrub_budget_bytes: Option<u64>,
+        /// For pageserver node_kind only, skip this timeline shard entirely, given as
+        /// <tenant shard id>/<timeline id>: may be repeated
+        #[arg(long = "exclude-timeline", num_args = 0..)]
+        excluded_timelines: Vec<TenantShardTimelineId>,
+        /// For pageserver node_kind only, report any shard found in storage of a tenant with
+        /// another shard count than this, given as <tenant id>=<shard count>: may be repeated
+        #[arg(long = "expected-shard-count", value_parser = parse_expected_shard_count)]
+        expected_shard_counts: Vec<(TenantId, ShardCount)>,
+        /// For pageserver node_kind only, reconcile indices and storage with the layers that
+        /// pageservers report having, from this JSON file: an object mapping each
+        /// <tenant shard id>/<timeline id> to its layers' names as in storage
+        #[arg(long, default_value = None)]
+        pageserver_layers: Option<Utf8PathBuf>,
+        /// For pageserver node_kind only, scan each of the buckets in this JSON file in turn
+        /// instead of the one given by the environment: a list of objects with `region`,
+        /// `bucket`, and optionally `prefix_in_bucket` and the credential `profile` to use
+        #[arg(
+            long,
+            default_value = None,
+            conflicts_with_all = [
+                "layers_csv",
+                "orphan_manifest",
+                "findings_path",
+                "previous_findings",
+                "baseline_findings",
+                "findings_sql",
+                "findings_syslog",
+            ]
+        )]
+        buckets_file: Option<Utf8PathBuf>,
     },
     TenantSnapshot {
         #[arg(long = "tenant-id")]
@@ -62,6 +298,123 @@ enum Command {
         #[arg(short, long)]
         output_path: Utf8PathBuf,
     },
+    /// List every key under a tenant shard, or one of its timelines, with its size and last
+    /// modification time, without checking anything
+    DumpKeys {
+        #[arg(long = "tenant-id")]
+        tenant_id: TenantShardId,
+        #[arg(long = "timeline-id")]
+        timeline_id: Option<TimelineId>,
+        /// Write the keys to this file instead of stdout
+        #[arg(short, long)]
+        output_path: Option<Utf8PathBuf>,
+    },
+}
+
+/// Scan the pageserver metadata of each bucket in a file in turn, each with its own credentials,
+/// and report on them together.  A bucket that cannot be scanned stops the run, as a single
+/// bucket would.
+async fn scan_buckets(
+    buckets_file: &Utf8Path,
+    tenant_ids: Vec<TenantShardId>,
+    options: ScrubOptions,
+    json: bool,
+    output_path: Option<&Utf8Path>,
+    output_compression: OutputCompression,
+) -> anyhow::Result<()> {
+    let buckets: Vec<BucketConfig> = serde_json::from_slice(
+        &std::fs::read(buckets_file).with_context(|| format!("reading {buckets_file}"))?,
+    )
+    .with_context(|| format!("parsing {buckets_file}"))?;
+
+    let mut summaries = Vec::with_capacity(buckets.len());
+    for bucket_config in buckets {
+        let bucket = bucket_config.bucket.clone();
+        let summary = scan_metadata(
+            bucket_config,
+            tenant_ids.clone(),
+            options.clone(),
+            None,
+            None,
+        )
+        .await
+        .with_context(|| format!("scanning bucket {bucket}"))?;
+        summaries.push((bucket, summary));
+    }
+
+    let output = if json {
+        serde_json::to_string(&summaries.iter().map(|(_, s)| s).collect::<Vec<_>>()).unwrap()
+    } else {
+        itertools::join(summaries.iter().map(|(_, s)| s.summary_string()), "\n")
+    };
+    emit_output(output, output_path, output_compression).await?;
+
+    let fatal: Vec<&str> = summaries
+        .iter()
+        .filter(|(_, s)| s.is_fatal())
+        .map(|(bucket, _)| bucket.as_str())
+        .collect();
+    if !fatal.is_empty() {
+        bail!(
+            "Fatal scrub errors detected in buckets {}",
+            fatal.join(", ")
+        );
+    }
+    let empty: Vec<&str> = summaries
+        .iter()
+        .filter(|(_, s)| s.is_empty())
+        .map(|(bucket, _)| bucket.as_str())
+        .collect();
+    if !empty.is_empty() {
+        bail!("No timelines found in buckets {}", empty.join(", "));
+    }
+    Ok(())
+}
+
+/// Parse a `<check code>=<severity>` pair, such as `missing-layer=warning`
+fn parse_severity_override(s: &str) -> anyhow::Result<(CheckCode, Severity)> {
+    let (code, severity) = s
+        .split_once('=')
+        .context("expected <check code>=<severity>")?;
+    let code = serde_json::from_value(serde_json::Value::String(code.to_string()))
+        .with_context(|| format!("unknown check code {code}"))?;
+    let severity = Severity::from_str(severity, true).map_err(|e| anyhow::anyhow!(e))?;
+    Ok((code, severity))
+}
+
+/// Parse a `<tenant id>=<shard count>` pair
+fn parse_expected_shard_count(s: &str) -> anyhow::Result<(TenantId, ShardCount)> {
+    let (tenant_id, shard_count) = s
+        .split_once('=')
+        .context("expected <tenant id>=<shard count>")?;
+    let tenant_id = tenant_id
+        .parse()
+        .with_context(|| format!("parsing tenant id {tenant_id}"))?;
+    let shard_count = shard_count
+        .parse()
+        .with_context(|| format!("parsing shard count {shard_count}"))?;
+    Ok((tenant_id, ShardCount::new(shard_count)))
+}
+
+/// Create a file for a scan to write to as it goes
+fn create_output_file(path: &Utf8Path) -> anyhow::Result<Box<dyn std::io::Write>> {
+    let file = std::fs::File::create(path).with_context(|| format!("creating {path}"))?;
+    Ok(Box::new(std::io::BufWriter::new(file)))
+}
+
+/// Print a scan's output, or write it to a file if one was given
+async fn emit_output(
+    output: String,
+    path: Option<&Utf8Path>,
+    compression: OutputCompression,
+) -> anyhow::Result<()> {
+    match path {
+        Some(path) => write_output(path, compression, output.as_bytes()).await,
+        None => {
+            println!("{output}");
+            Ok(())
+        }
+    }
 }
 
 #[tokio::main]
@@ -75,22 +428,78 @@ async fn main() -> anyhow::Result<()> {
         Command::FindGarbage { .. } => "find-garbage",
         Command::PurgeGarbage { .. } => "purge-garbage",
         Command::TenantSnapshot { .. } => "tenant-snapshot",
+        Command::DumpKeys { .. } => "dump-keys",
     };
-    let _guard = init_logging(&format!(
-        "{}_{}_{}_{}.log",
-        std::env::args().next().unwrap(),
-        command_log_name,
-        bucket_config.bucket,
-        chrono::Utc::now().format("%Y_%m_%d__%H_%M_%S")
-    ));
-
-    match cli.command {
+    let _guard = init_logging(
+        &format!(
+            "{}_{}_{}_{}.log",
+            std::env::args().next().unwrap(),
+            command_log_name,
+            bucket_config.bucket,
+            chrono::Utc::now().format("%Y_%m_%d__%H_%M_%S")
+        ),
+        cli.otel,
+    );
+
+    let result = match cli.command {
         Command::ScanMetadata {
             json,
+            output_path,
+            output_compression,
             tenant_ids,
             node_kind,
             dump_db_connstr,
             dump_db_table,
+            index_version_window,
+            old_index_version_severity,
+            index_generation,
+            timeline_timeout_secs,
+            index_download_timeout_secs,
+            summary_interval_secs,
+            gc_horizon,
+            layers_csv,
+            orphan_manifest,
+            detect_subprefixes,
+            listing_progress_interval,
+            shard_stripe_size,
+            fail_fast,
+            collect_all: _,
+            list_versions,
+            listing_page_size,
+            findings_path,
+            previous_findings,
+            baseline_findings,
+            baseline_compare_lsn,
+            findings_sql,
+            findings_syslog,
+            max_layer_size_bytes,
+            max_requests_in_flight,
+            retry_budget,
+            max_index_size_bytes,
+            min_index_bytes_per_layer,
+            validate_index_schema,
+            index_schema_path,
+            check_duplicate_index_entries,
+            image_partition_blocks,
+            full_keyspace_min_timeline_bytes,
+            max_l0_stack_depth,
+            max_layer_shards,
+            check_index_history,
+            max_layer_count_drop_percent,
+            check_layer_name_round_trip,
+            fatal_on_unparseable,
+            key_range,
+            filter_layer_map_checks,
+            include_healthy,
+            max_findings,
+            deletion_tag,
+            severity_overrides,
+            deep_scrub,
+            deep_scrub_budget_bytes,
+            excluded_timelines,
+            expected_shard_counts,
+            pageserver_layers,
+            buckets_file,
         } => {
             if let NodeKind::Safekeeper = node_kind {
                 let dump_db_connstr =
@@ -105,11 +514,12 @@ async fn main() -> anyhow::Result<()> {
                     dump_db_table,
                 )
                 .await?;
-                if json {
-                    println!("{}", serde_json::to_string(&summary).unwrap())
+                let output = if json {
+                    serde_json::to_string(&summary).unwrap()
                 } else {
-                    println!("{}", summary.summary_string());
-                }
+                    summary.summary_string()
+                };
+                emit_output(output, output_path.as_deref(), output_compression).await?;
                 if summary.is_fatal() {
                     bail!("Fatal scrub errors detected");
                 }
@@ -127,32 +537,203 @@ async fn main() -> anyhow::Result<()> {
                 }
                 Ok(())
             } else {
-                match scan_metadata(bucket_config.clone(), tenant_ids).await {
-                    Err(e) => {
-                        tracing::error!("Failed: {e}");
-                        Err(e)
-                    }
-                    Ok(summary) => {
-                        if json {
-                            println!("{}", serde_json::to_string(&summary).unwrap())
-                        } else {
-                            println!("{}", summary.summary_string());
+                let index_schema = match index_schema_path {
+                    Some(path) => Some(
+                        serde_json::from_slice(
+                            &std::fs::read(&path).with_context(|| format!("reading {path}"))?,
+                        )
+                        .with_context(|| format!("parsing {path}"))?,
+                    ),
+                    None => validate_index_schema.then(embedded_index_schema),
+                };
+                let pageserver_layers = match pageserver_layers {
+                    Some(path) => Some(Arc::new(
+                        PageserverLayers::from_json(
+                            &std::fs::read(&path).with_context(|| format!("reading {path}"))?,
+                        )
+                        .with_context(|| format!("parsing {path}"))?,
+                    )),
+                    None => None,
+                };
+                let options = ScrubOptions {
+                    retry_policy: RetryPolicy {
+                        governor: max_requests_in_flight
+                            .map(RequestGovernor::new)
+                            .unwrap_or_default(),
+                        budget: retry_budget.map(RetryBudget::new).unwrap_or_default(),
+                        ..Default::default()
+                    },
+                    index_version_window,
+                    old_index_version_severity,
+                    target_generation: index_generation.map(Generation::new),
+                    timeline_timeout: Duration::from_secs(timeline_timeout_secs),
+                    index_download_timeout: Duration::from_secs(index_download_timeout_secs),
+                    summary_interval: summary_interval_secs.map(Duration::from_secs),
+                    gc_horizon,
+                    detect_subprefixes,
+                    shard_stripe_size: shard_stripe_size.map(ShardStripeSize),
+                    list_versions,
+                    listing_page_size,
+                    max_layer_size: max_layer_size_bytes,
+                    max_index_size: max_index_size_bytes
+                        .unwrap_or(ScrubOptions::default().max_index_size),
+                    min_index_bytes_per_layer: min_index_bytes_per_layer
+                        .unwrap_or(ScrubOptions::default().min_index_bytes_per_layer),
+                    index_schema,
+                    check_duplicate_index_entries,
+                    image_partition_size: image_partition_blocks,
+                    full_keyspace_min_timeline_size: full_keyspace_min_timeline_bytes,
+                    max_l0_stack_depth,
+                    max_layer_shards,
+                    check_index_history,
+                    max_layer_count_drop_percent,
+                    check_layer_name_round_trip,
+                    fatal_on_unparseable,
+                    key_range_filter: key_range.map(|keys| KeyRangeFilter {
+                        keys,
+                        filter_layer_map_checks,
+                    }),
+                    include_healthy: include_healthy || baseline_findings.is_some(),
+                    max_findings,
+                    deletion_tag,
+                    severity_policy: SeverityPolicy {
+                        by_code: severity_overrides.into_iter().collect(),
+                    },
+                    deep_scrub,
+                    deep_scrub_budget: ByteBudget::new(deep_scrub_budget_bytes),
+                    excluded_timelines: excluded_timelines.into_iter().collect(),
+                    expected_shard_counts: expected_shard_counts.into_iter().collect(),
+                    pageserver_layers,
+                    failure_mode: if fail_fast {
+                        FailureMode::FailFast
+                    } else {
+                        FailureMode::CollectAll
+                    },
+                    read_only: cli.read_only,
+                    listing_progress: listing_progress_interval.map(|interval| ListingProgress {
+                        interval,
+                        callback: Arc::new(|ttid: &TenantShardTimelineId, count: usize| {
+                            tracing::info!("{ttid}: listed {count} objects so far")
+                        }),
+                    }),
+                    ..Default::default()
+                };
+                if let Some(path) = buckets_file {
+                    scan_buckets(
+                        &path,
+                        tenant_ids,
+                        options,
+                        json,
+                        output_path.as_deref(),
+                        output_compression,
+                    )
+                    .await
+                } else {
+                    let layers_csv = layers_csv.as_deref().map(create_output_file).transpose()?;
+                    let orphan_manifest = orphan_manifest
+                        .as_deref()
+                        .map(create_output_file)
+                        .transpose()?;
+                    match scan_metadata(
+                        bucket_config.clone(),
+                        tenant_ids,
+                        options,
+                        layers_csv,
+                        orphan_manifest,
+                    )
+                    .await
+                    {
+                        Err(e) => {
+                            tracing::error!("Failed: {e}");
+                            Err(e)
                         }
-                        if summary.is_fatal() {
-                            Err(anyhow::anyhow!("Fatal scrub errors detected"))
-                        } else if summary.is_empty() {
-                            // Strictly speaking an empty bucket is a valid bucket, but if someone ran the
-                            // scrubber they were likely expecting to scan something, and if we see no timelines
-                            // at all then it's likely due to some configuration issues like a bad prefix
-                            Err(anyhow::anyhow!(
-                                "No timelines found in bucket {} prefix {}",
-                                bucket_config.bucket,
-                                bucket_config
-                                    .prefix_in_bucket
-                                    .unwrap_or("<none>".to_string())
-                            ))
-                        } else {
-                            Ok(())
+                        Ok(summary) => {
+                            let output = if json {
+                                serde_json::to_string(&summary).unwrap()
+                            } else {
+                                summary.summary_string()
+                            };
+                            emit_output(output, output_path.as_deref(), output_compression).await?;
+                            if summary.findings_truncated()
+                                && (findings_path.is_some()
+                                    || findings_sql.is_some()
+                                    || findings_syslog.is_some())
+                            {
+                                tracing::warn!("Findings were capped by --max-findings: the saved and sent findings are incomplete");
+                            }
+                            if let Some(path) = findings_path {
+                                std::fs::write(&path, summary.findings_json()?)
+                                    .with_context(|| format!("writing {path}"))?;
+                            }
+                            if let Some(path) = findings_sql {
+                                summary
+                                    .write_findings_sql(&mut *create_output_file(&path)?)
+                                    .with_context(|| format!("writing {path}"))?;
+                            }
+                            if let Some(path) = findings_syslog {
+                                match summary.send_findings_syslog(&path) {
+                                    Ok(sent) => {
+                                        tracing::info!("Sent {sent} findings to syslog at {path}")
+                                    }
+                                    Err(e) => tracing::error!(
+                                        "Failed to send findings to syslog at {path}: {e:#}"
+                                    ),
+                                }
+                            }
+                            if let Some(path) = previous_findings {
+                                let previous = std::fs::read(&path)
+                                    .with_context(|| format!("reading {path}"))?;
+                                let diff = summary.diff_findings(&previous)?;
+                                if json {
+                                    println!("{}", serde_json::to_string(&diff).unwrap());
+                                } else {
+                                    for (ttid, code) in &diff.appeared {
+                                        println!("Appeared: {ttid} {code}");
+                                    }
+                                    for (ttid, code) in &diff.resolved {
+                                        println!("Resolved: {ttid} {code}");
+                                    }
+                                }
+                            }
+                            let mut drifted = false;
+                            if let Some(path) = baseline_findings {
+                                let baseline = std::fs::read(&path)
+                                    .with_context(|| format!("reading {path}"))?;
+                                let diff =
+                                    summary.diff_fingerprints(&baseline, baseline_compare_lsn)?;
+                                if json {
+                                    println!("{}", serde_json::to_string(&diff).unwrap());
+                                } else {
+                                    for ttid in &diff.changed {
+                                        println!("Changed: {ttid}");
+                                    }
+                                    for ttid in &diff.missing {
+                                        println!("Missing: {ttid}");
+                                    }
+                                    for ttid in &diff.added {
+                                        println!("Added: {ttid}");
+                                    }
+                                }
+                                drifted = !diff.is_empty();
+                            }
+                            if summary.is_fatal() {
+                                Err(anyhow::anyhow!("Fatal scrub errors detected"))
+                            } else if drifted {
+                                Err(anyhow::anyhow!("Timelines changed since the baseline"))
+                            } else if summary.is_empty() {
+                                // Strictly speaking an empty bucket is a valid bucket, but if someone ran the
+                                // scrubber they were likely expecting to scan something, and if we see no timelines
+                                // at all then it's likely due to some configuration issues like a bad prefix
+                                Err(anyhow::anyhow!(
+                                    "No timelines found in bucket {} prefix {}",
+                                    bucket_config.bucket,
+                                    bucket_config
+                                        .prefix_in_bucket
+                                        .unwrap_or("<none>".to_string())
+                                ))
+                            } else {
+                                Ok(())
+                            }
                         }
                     }
                 }
@@ -167,7 +748,11 @@ async fn main() -> anyhow::Result<()> {
             find_garbage(bucket_config, console_config, depth, node_kind, output_path).await
         }
         Command::PurgeGarbage { input_path, mode } => {
-            purge_garbage(input_path, mode, !cli.delete).await
+            let options = ScrubOptions {
+                read_only: cli.read_only,
+                ..Default::default()
+            };
+            purge_garbage(input_path, mode, !cli.delete, &options).await
         }
         Command::TenantSnapshot {
             tenant_id,
@@ -178,5 +763,25 @@ async fn main() -> anyhow::Result<()> {
                 SnapshotDownloader::new(bucket_config, tenant_id, output_path, concurrency)?;
             downloader.download().await
         }
+        Command::DumpKeys {
+            tenant_id,
+            timeline_id,
+            output_path,
+        } => {
+            let mut writer: Box<dyn std::io::Write> = match &output_path {
+                Some(path) => create_output_file(path)?,
+                None => Box::new(std::io::stdout()),
+            };
+            let key_count =
+                dump_tenant_keys(bucket_config, tenant_id, timeline_id, &mut *writer).await?;
+            tracing::info!("Dumped {key_count} keys");
+            Ok(())
+        }
+    };
+
+    if cli.otel {
+        // Export whatever spans are still buffered
+        tracing_utils::shutdown_tracing();
     }
+    result
 }
@@ -1,23 +1,30 @@
+use std::io::Write;
+
 use anyhow::Context;
 use async_stream::{stream, try_stream};
-use aws_sdk_s3::{types::ObjectIdentifier, Client};
+use aws_sdk_s3::types::{Object, ObjectIdentifier};
+use futures_util::{StreamExt, TryStreamExt};
 use tokio_stream::Stream;
 
-use crate::{list_objects_with_retries, RootTarget, S3Target, TenantShardTimelineId};
+use crate::{
+    init_remote, list_objects_with_retries, BucketConfig, NodeKind, RetryPolicy, RootTarget,
+    S3Target, StorageClient, TenantShardTimelineId,
+};
 use pageserver_api::shard::TenantShardId;
 use utils::id::{TenantId, TimelineId};
 
 /// Given an S3 bucket, output a stream of TenantIds discovered via ListObjectsv2
 pub fn stream_tenants<'a>(
-    s3_client: &'a Client,
+    s3_client: &'a StorageClient,
     target: &'a RootTarget,
+    retry_policy: RetryPolicy,
 ) -> impl Stream<Item = anyhow::Result<TenantShardId>> + 'a {
     try_stream! {
         let mut continuation_token = None;
         let tenants_target = target.tenants_root();
         loop {
             let fetch_response =
-                list_objects_with_retries(s3_client, &tenants_target, continuation_token.clone()).await?;
+                list_objects_with_retries(s3_client, &tenants_target, continuation_token.clone(), None, &retry_policy).await?;
 
             let new_entry_ids = fetch_response
                 .common_prefixes()
@@ -46,9 +53,10 @@ pub fn stream_tenants<'a>(
 }
 
 pub async fn stream_tenant_shards<'a>(
-    s3_client: &'a Client,
+    s3_client: &'a StorageClient,
     target: &'a RootTarget,
     tenant_id: TenantId,
+    retry_policy: RetryPolicy,
 ) -> anyhow::Result<impl Stream<Item = Result<TenantShardId, anyhow::Error>> + 'a> {
     let mut tenant_shard_ids: Vec<Result<TenantShardId, anyhow::Error>> = Vec::new();
     let mut continuation_token = None;
@@ -56,8 +64,14 @@ pub async fn stream_tenant_shards<'a>(
 
     loop {
         tracing::info!("Listing in {}", shards_target.prefix_in_bucket);
-        let fetch_response =
-            list_objects_with_retries(s3_client, &shards_target, continuation_token.clone()).await;
+        let fetch_response = list_objects_with_retries(
+            s3_client,
+            &shards_target,
+            continuation_token.clone(),
+            None,
+            &retry_policy,
+        )
+        .await;
         let fetch_response = match fetch_response {
             Err(e) => {
                 tenant_shard_ids.push(Err(e));
@@ -105,9 +119,10 @@ pub async fn stream_tenant_shards<'a>(
 /// using ListObjectsv2.  The listing is done before the stream is built, so that this
 /// function can be used to generate concurrency on a stream using buffer_unordered.
 pub async fn stream_tenant_timelines<'a>(
-    s3_client: &'a Client,
+    s3_client: &'a StorageClient,
     target: &'a RootTarget,
     tenant: TenantShardId,
+    retry_policy: RetryPolicy,
 ) -> anyhow::Result<impl Stream<Item = Result<TenantShardTimelineId, anyhow::Error>> + 'a> {
     let mut timeline_ids: Vec<Result<TimelineId, anyhow::Error>> = Vec::new();
     let mut continuation_token = None;
@@ -115,9 +130,14 @@ pub async fn stream_tenant_timelines<'a>(
 
     loop {
         tracing::debug!("Listing in {}", tenant);
-        let fetch_response =
-            list_objects_with_retries(s3_client, &timelines_target, continuation_token.clone())
-                .await;
+        let fetch_response = list_objects_with_retries(
+            s3_client,
+            &timelines_target,
+            continuation_token.clone(),
+            None,
+            &retry_policy,
+        )
+        .await;
         let fetch_response = match fetch_response {
             Err(e) => {
                 timeline_ids.push(Err(e));
@@ -161,25 +181,68 @@ pub async fn stream_tenant_timelines<'a>(
 }
 
 pub(crate) fn stream_listing<'a>(
-    s3_client: &'a Client,
+    s3_client: &'a StorageClient,
     target: &'a S3Target,
+    retry_policy: RetryPolicy,
 ) -> impl Stream<Item = anyhow::Result<ObjectIdentifier>> + 'a {
+    stream_listing_with_sizes(s3_client, target, retry_policy)
+        .map_ok(|(object_id, _size)| object_id)
+}
+
+/// Like [`stream_listing`], but also yields the size in bytes of each object.  Common prefixes,
+/// listed when the target has a delimiter, have a size of zero.
+pub(crate) fn stream_listing_with_sizes<'a>(
+    s3_client: &'a StorageClient,
+    target: &'a S3Target,
+    retry_policy: RetryPolicy,
+) -> impl Stream<Item = anyhow::Result<(ObjectIdentifier, u64)>> + 'a {
+    stream_listing_objects(s3_client, target, retry_policy).and_then(|object| async move {
+        let size = object.size().unwrap_or(0) as u64;
+        let object_id = ObjectIdentifier::builder().set_key(object.key).build()?;
+        anyhow::Ok((object_id, size))
+    })
+}
+
+/// Like [`stream_listing`], but yields each object as listed, with whatever S3 says about it.
+/// Common prefixes, listed when the target has a delimiter, are objects with only a key.
+pub(crate) fn stream_listing_objects<'a>(
+    s3_client: &'a StorageClient,
+    target: &'a S3Target,
+    retry_policy: RetryPolicy,
+) -> impl Stream<Item = anyhow::Result<Object>> + 'a {
     try_stream! {
         let mut continuation_token = None;
+        // The last key yielded, for resuming the listing if a page fails even after retries
+        let mut last_key: Option<String> = None;
         loop {
-            let fetch_response =
-                list_objects_with_retries(s3_client, target, continuation_token.clone()).await?;
+            let fetch_response = match list_objects_with_retries(s3_client, target, continuation_token.clone(), None, &retry_policy).await {
+                Ok(response) => response,
+                Err(e) if last_key.is_some() => {
+                    // The continuation token may be what is failing: resume after the last key
+                    // we yielded instead of restarting the whole listing.
+                    tracing::warn!("Listing failed ({e:#}), resuming after {last_key:?}");
+                    list_objects_with_retries(s3_client, target, None, last_key.clone(), &retry_policy).await?
+                }
+                Err(e) => Err(e)?,
+            };
 
+            // Resuming after a common prefix lists the keys under it, which roll up into that
+            // prefix again: skip whatever was already yielded.
             if target.delimiter.is_empty() {
-                for object_key in fetch_response.contents().iter().filter_map(|object| object.key())
-                {
-                    let object_id = ObjectIdentifier::builder().key(object_key).build()?;
-                    yield object_id;
+                for object in fetch_response.contents() {
+                    let Some(object_key) = object.key().filter(|key| is_after(key, &last_key)) else {
+                        continue;
+                    };
+                    last_key = Some(object_key.to_string());
+                    yield object.clone();
                 }
             } else {
                 for prefix in fetch_response.common_prefixes().iter().filter_map(|p| p.prefix()) {
-                    let object_id = ObjectIdentifier::builder().key(prefix).build()?;
-                    yield object_id;
+                    if !is_after(prefix, &last_key) {
+                        continue;
+                    }
+                    last_key = Some(prefix.to_string());
+                    yield Object::builder().key(prefix).build();
                 }
             }
 
@@ -190,3 +253,59 @@ pub(crate) fn stream_listing<'a>(
         }
     }
 }
+
+/// Whether a listed key comes after the last one yielded, if any
+fn is_after(key: &str, last_key: &Option<String>) -> bool {
+    last_key.as_deref().map_or(true, |last_key| key > last_key)
+}
+
+/// Write every key under `target` to `writer` as it is listed, one per line with its size in
+/// bytes and last modification time, tab-separated: the raw listing, for manual investigation,
+/// without any parsing or checking of the keys.  The listing recurses into every sub-prefix, and
+/// is never held in memory, so it may be as large as the bucket.  Returns the number of keys.
+pub async fn dump_keys(
+    s3_client: &StorageClient,
+    target: &S3Target,
+    retry_policy: RetryPolicy,
+    writer: &mut dyn Write,
+) -> anyhow::Result<usize> {
+    let target = S3Target {
+        delimiter: String::new(),
+        ..target.clone()
+    };
+    let mut objects = std::pin::pin!(stream_listing_objects(s3_client, &target, retry_policy));
+    let mut key_count = 0;
+    while let Some(object) = objects.next().await {
+        let object = object?;
+        let last_modified = object
+            .last_modified()
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        writeln!(
+            writer,
+            "{}\t{}\t{last_modified}",
+            object.key().unwrap_or_default(),
+            object.size().unwrap_or(0)
+        )?;
+        key_count += 1;
+    }
+    writer.flush()?;
+    Ok(key_count)
+}
+
+/// Dump the keys of a tenant shard, or of one of its timelines, with [`dump_keys`].
+pub async fn dump_tenant_keys(
+    bucket_config: BucketConfig,
+    tenant_shard_id: TenantShardId,
+    timeline_id: Option<TimelineId>,
+    writer: &mut dyn Write,
+) -> anyhow::Result<usize> {
+    let (s3_client, target) = init_remote(bucket_config, NodeKind::Pageserver)?;
+    let prefix = match timeline_id {
+        Some(timeline_id) => {
+            target.timeline_root(&TenantShardTimelineId::new(tenant_shard_id, timeline_id))
+        }
+        None => target.tenant_root(&tenant_shard_id),
+    };
+    dump_keys(&s3_client, &prefix, RetryPolicy::default(), writer).await
+}
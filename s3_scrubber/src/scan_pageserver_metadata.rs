@@ -1,33 +1,363 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::checks::{
-    branch_cleanup_and_check_errors, list_timeline_blobs, BlobDataParseResult, S3TimelineBlobData,
+    branch_cleanup_and_check_errors, diff_findings, diff_fingerprints, group_by_code,
+    l0_stack_depth, list_timeline_blobs, BlobDataParseResult, CheckCode, FindingsDiff,
+    FingerprintDiff, LayerReport, ParseErrorCategory, S3TimelineBlobData, ShardTotals,
     TenantObjectListing, TimelineAnalysis,
 };
-use crate::metadata_stream::{stream_tenant_timelines, stream_tenants};
-use crate::{init_remote, BucketConfig, NodeKind, RootTarget, TenantShardTimelineId};
-use aws_sdk_s3::Client;
+use crate::clock::{Clock, SystemClock};
+use crate::metadata_stream::{stream_listing, stream_tenant_timelines, stream_tenants};
+use crate::{
+    init_remote, BucketConfig, FailureMode, NodeKind, RetryPolicy, RootTarget, ScrubOptions,
+    StorageClient, TenantShardTimelineId,
+};
+use anyhow::Context;
+use camino::Utf8Path;
+use chrono::{DateTime, Utc};
 use futures_util::{StreamExt, TryStreamExt};
 use histogram::Histogram;
 use pageserver::tenant::remote_timeline_client::remote_layer_path;
+use pageserver::tenant::storage_layer::LayerName;
 use pageserver::tenant::IndexPart;
-use pageserver_api::shard::TenantShardId;
-use serde::Serialize;
-use utils::id::TenantId;
+use pageserver_api::shard::{ShardIndex, TenantShardId};
+use remote_storage::RemotePath;
+use serde::{Deserialize, Serialize};
+use utils::generation::Generation;
+use utils::id::{TenantId, TimelineId};
+use uuid::Uuid;
+
+/// How many of the slowest tenants to check a summary keeps
+const SLOWEST_TENANTS: usize = 10;
+
+/// The syslog facility of the findings that [`MetadataSummary::send_findings_syslog`] sends:
+/// system daemons
+const SYSLOG_FACILITY: u8 = 3;
+/// Syslog severities: tenant-level errors, which make a scrub fatal without belonging to any
+/// one timeline, are critical
+const SYSLOG_CRIT: u8 = 2;
+const SYSLOG_ERR: u8 = 3;
+const SYSLOG_WARNING: u8 = 4;
+/// The structured data element of every finding sent to syslog, named with the private
+/// enterprise number that RFC 5612 sets aside for documentation, as no number is registered
+const SYSLOG_SD_ID: &str = "scrub@32473";
+
+/// A structured data parameter value, in which RFC 5424 requires `"`, `\` and `]` to be escaped
+fn sd_value(s: impl std::fmt::Display) -> String {
+    s.to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+/// The tables that [`MetadataSummary::write_findings_sql`] fills
+const FINDINGS_SQL_SCHEMA: &str = "\
+CREATE TABLE IF NOT EXISTS timelines (
+    run_id TEXT NOT NULL,
+    tenant_shard_id TEXT NOT NULL,
+    timeline_id TEXT NOT NULL,
+    timed_out INTEGER NOT NULL,
+    layer_count INTEGER,
+    layer_bytes INTEGER,
+    disk_consistent_lsn TEXT,
+    PRIMARY KEY (run_id, tenant_shard_id, timeline_id)
+);
+CREATE TABLE IF NOT EXISTS findings (
+    run_id TEXT NOT NULL,
+    tenant_shard_id TEXT NOT NULL,
+    timeline_id TEXT NOT NULL,
+    code TEXT NOT NULL,
+    severity TEXT NOT NULL,
+    message TEXT NOT NULL,
+    suggestion TEXT
+);
+CREATE TABLE IF NOT EXISTS garbage (
+    run_id TEXT NOT NULL,
+    tenant_shard_id TEXT NOT NULL,
+    timeline_id TEXT NOT NULL,
+    key TEXT NOT NULL
+);
+";
+
+/// A SQL string literal, with any quotes in it doubled
+fn sql_string(s: impl std::fmt::Display) -> String {
+    format!("'{}'", s.to_string().replace('\'', "''"))
+}
 
 #[derive(Serialize)]
 pub struct MetadataSummary {
+    /// The bucket scanned, and the credential profile it was accessed with, so that summaries of
+    /// buckets in several accounts can be told apart
+    bucket: String,
+    profile: Option<String>,
+    /// The run that produced this summary, as also found in its findings and its logs
+    run_id: Uuid,
+    /// The run's clock, for timestamping what is sent on from the summary
+    #[serde(skip)]
+    clock: Arc<dyn Clock>,
     tenant_count: usize,
     timeline_count: usize,
     timeline_shard_count: usize,
     with_errors: HashSet<TenantShardTimelineId>,
     with_warnings: HashSet<TenantShardTimelineId>,
     with_orphans: HashSet<TenantShardTimelineId>,
-    indices_by_version: HashMap<usize, usize>,
+    with_timeouts: HashSet<TenantShardTimelineId>,
+    with_invalid_layer_map: HashSet<TenantShardTimelineId>,
+    /// In fail-fast mode, the unhealthy timeline at which the scan stopped
+    stopped_at: Option<TenantShardTimelineId>,
+    /// Findings that concern a tenant as a whole rather than any one timeline
+    tenant_errors: HashMap<TenantId, Vec<String>>,
+    /// Timeline prefixes holding objects but no index, with the number of objects in each
+    orphan_timelines: HashMap<String, usize>,
+    /// Tenant shards present in storage with no timelines at all, with what they hold instead.
+    /// That is normal for a tenant that has just been created or is being deleted, but not for one
+    /// that lost all its timelines, which is also reported as a tenant error.
+    empty_tenants: Vec<(TenantShardId, EmptyTenantKind)>,
+    /// Timeline IDs found under more than one tenant, with every tenant they were found under.
+    /// Only checked when scanning the whole bucket.
+    timelines_in_multiple_tenants: BTreeMap<TimelineId, BTreeSet<TenantId>>,
+    /// Timeline shards skipped because the options excluded them
+    excluded_timeline_shards: usize,
+    /// Whether a failed request went unretried because the run's retry budget was spent: if so,
+    /// some findings may be due to transient failures
+    retry_budget_exhausted: bool,
+    /// How many timeline shards use each index_part version, in version order: this tells us when
+    /// support for an old version can be dropped
+    indices_by_version: BTreeMap<usize, usize>,
+    /// Timeline listings that could not be parsed, by each category of error they had
+    parse_errors_by_category: HashMap<ParseErrorCategory, usize>,
+    /// Whether any unparseable timeline listing makes the scrub fatal, from the options
+    #[serde(skip)]
+    fatal_on_unparseable: bool,
+    /// How many indices have the duplicated disk_consistent_lsn (TimelineMetadata vs. IndexPart)
+    /// agreeing or not: tracked to support removing the duplicate (issue 8343)
+    disk_consistent_lsn_duplicates_equal: usize,
+    disk_consistent_lsn_duplicates_mismatched: usize,
+    /// Per tenant with any garbage, its bytes of garbage (orphan layers, stale indices, unknown
+    /// keys) against its bytes of referenced layers: sorted by their ratio, descending, so that
+    /// the tenants most in need of cleanup come first
+    garbage_ratios: Vec<TenantGarbage>,
+    /// For sharded tenants, the layers listed under each shard
+    shard_totals: BTreeMap<TenantId, BTreeMap<ShardIndex, ShardTotals>>,
+    /// Per sharded tenant, its largest shard's layer bytes divided by the mean of its shards':
+    /// sorted descending, so that the most imbalanced tenants come first
+    shard_imbalances: Vec<(TenantId, f64)>,
+    /// Health scores of the timeline shards that scored below 100: sorted ascending, so that the
+    /// timelines most in need of a look come first
+    health_scores: Vec<(TenantShardTimelineId, u8)>,
+    /// The tenants that took longest, in seconds of wall-clock time from the start of their
+    /// listing to the end of their checks: sorted descending, and only the slowest few kept as
+    /// tenants finish.  Listings run concurrently, so the times of tenants overlap.
+    slowest_tenants: Vec<(TenantId, f64)>,
+    /// Totals of the per-timeline layer reconciliation
+    layers_referenced_present: usize,
+    layers_referenced_missing: usize,
+    layers_present_unreferenced: usize,
+    /// Referenced layers left unchecked because they do not overlap the key range filter
+    layers_outside_key_filter: usize,
+    /// Noncurrent versions of layer and index objects, if versions were listed
+    noncurrent_versions: usize,
+    noncurrent_version_bytes: u64,
 
     layer_count: MinMaxHisto,
     timeline_size_bytes: MinMaxHisto,
+    /// From the lowest LSN of any layer to disk_consistent_lsn: how much WAL history a timeline
+    /// materializes
+    timeline_lsn_span: MinMaxHisto,
+    /// How many L0 layers a read may visit at worst: see [`l0_stack_depth`]
+    l0_stack_depth: MinMaxHisto,
     layer_size_bytes: MinMaxHisto,
+
+    /// Every orphan layer found, if the scan is to write an orphan manifest
+    #[serde(skip)]
+    orphan_layers: Option<Vec<OrphanManifestEntry>>,
+    /// The analyses of timelines with any findings, or of every timeline if healthy ones are
+    /// included, for comparing against other runs
+    #[serde(skip)]
+    findings: Vec<TimelineAnalysis>,
+    /// How many findings `findings` holds, against the options' cap
+    #[serde(skip)]
+    retained_finding_count: usize,
+    /// Whether the cap on retained findings was reached, after which findings were only counted,
+    /// by kind, to bound the memory a badly broken bucket takes
+    findings_count_only: bool,
+    findings_counted_only: BTreeMap<CheckCode, usize>,
+}
+
+/// Findings as [`MetadataSummary::findings_json`] saves them: a plain list if the scan retained
+/// every one, or else marked as truncated, so that they are not mistaken for a whole bucket's.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SavedFindings<F> {
+    Complete(F),
+    Truncated { truncated_after: usize, findings: F },
+}
+
+impl SavedFindings<Vec<TimelineAnalysis>> {
+    /// Parse saved findings for comparison, refusing truncated ones: timelines past the cap would
+    /// look missing or resolved.
+    fn parse_complete(json: &[u8], what: &str) -> anyhow::Result<Vec<TimelineAnalysis>> {
+        match serde_json::from_slice(json).with_context(|| format!("parsing {what} findings"))? {
+            SavedFindings::Complete(findings) => Ok(findings),
+            SavedFindings::Truncated {
+                truncated_after, ..
+            } => anyhow::bail!(
+                "The {what} findings were truncated after {truncated_after} findings by --max-findings, so cannot be compared against"
+            ),
+        }
+    }
+}
+
+/// The first line of an orphan manifest: where the orphans are, and who found them when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrphanManifestContext {
+    pub bucket: String,
+    /// Orphan paths are relative to this prefix: the one scanned, whether configured or the
+    /// default.  Manifests written by older versions have only a configured one, if any.
+    pub prefix_in_bucket: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub scrubber_version: String,
+    /// The run that found the orphans: absent from manifests written by older versions
+    #[serde(default)]
+    pub run_id: Option<Uuid>,
+}
+
+/// An orphan layer, with enough detail for a deletion tool to re-verify it before deleting: if
+/// the object's size differs, or an index now references its generation, it is no orphan.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OrphanManifestEntry {
+    pub path: RemotePath,
+    /// None for layers written before generations existed
+    pub generation: Option<Generation>,
+    pub size: u64,
+}
+
+/// Write a deletion-ready manifest of orphan layers, as newline-delimited JSON: a header line
+/// with the context, then one line per orphan.  Writing the manifest is all a scan does with
+/// orphans: deleting them is left to a separate tool, which can be audited separately.
+pub fn write_orphan_manifest(
+    listing: &[OrphanManifestEntry],
+    writer: &mut dyn Write,
+    context: &OrphanManifestContext,
+) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *writer, context)?;
+    writeln!(writer)?;
+    for entry in listing {
+        serde_json::to_writer(&mut *writer, entry)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// What a tenant shard with no timelines holds instead, to tell a tenant that is meant to be empty
+/// from one that lost its data
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+enum EmptyTenantKind {
+    /// Marked deleted, with its timelines deleted already: a tenant deletion in progress
+    Deleting,
+    /// Only tenant-level objects, such as a heatmap: never given a timeline, or had every one
+    /// deleted
+    NoTimelines,
+    /// Objects under its timelines prefix, but none in any timeline: what is left after losing
+    /// its timelines, rather than deleting them
+    LostTimelines,
+}
+
+impl EmptyTenantKind {
+    /// Judge a tenant shard by the keys of its objects, relative to its prefix
+    fn from_keys<'a>(keys: impl Iterator<Item = &'a str>) -> Self {
+        let mut kind = Self::NoTimelines;
+        for key in keys {
+            if key == "timelines/deleted" {
+                return Self::Deleting;
+            } else if key.starts_with("timelines/") {
+                kind = Self::LostTimelines;
+            }
+        }
+        kind
+    }
+
+    /// List the objects of a tenant shard with no timelines, to judge why it has none
+    async fn list(
+        s3_client: &StorageClient,
+        target: &RootTarget,
+        tenant_shard_id: TenantShardId,
+        retry_policy: RetryPolicy,
+    ) -> anyhow::Result<Self> {
+        let mut tenant_root = target.tenant_root(&tenant_shard_id);
+        tenant_root.delimiter = String::new();
+        let prefix = tenant_root
+            .prefix_in_bucket
+            .trim_start_matches('/')
+            .to_string();
+        let keys: Vec<String> = stream_listing(s3_client, &tenant_root, retry_policy)
+            .map_ok(|object_id| object_id.key)
+            .try_collect()
+            .await?;
+        Ok(Self::from_keys(keys.iter().filter_map(|key| {
+            key.trim_start_matches('/').strip_prefix(prefix.as_str())
+        })))
+    }
+}
+
+/// A tenant's garbage against the layers its indices reference
+#[derive(Serialize)]
+struct TenantGarbage {
+    tenant_id: TenantId,
+    garbage_bytes: u64,
+    referenced_bytes: u64,
+    /// Bytes of garbage per byte referenced: none if nothing is referenced, and all is garbage
+    ratio: Option<f64>,
+}
+
+/// Logs the summary so far at most once per interval during a scan.  The scan loop waits for it
+/// alongside the next listing, and the analysis of a tenant checks it between timelines, so that
+/// neither slow listings nor a tenant with many timelines hold it up.
+struct SummaryTicker {
+    interval: Duration,
+    due_at: tokio::time::Instant,
+}
+
+impl SummaryTicker {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            due_at: tokio::time::Instant::now() + interval,
+        }
+    }
+
+    /// Log the summary if it is due
+    fn tick(&mut self, summary: &MetadataSummary) {
+        let now = tokio::time::Instant::now();
+        if now >= self.due_at {
+            tracing::info!("Summary so far:\n{}", summary.summary_string());
+            self.due_at = now + self.interval;
+        }
+    }
+}
+
+/// Format a byte count with a binary unit, e.g. 1.5 GiB
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
 }
 
 /// A histogram plus minimum and maximum tracking
@@ -63,25 +393,29 @@ impl MinMaxHisto {
     }
 
     fn oneline(&self) -> String {
+        self.oneline_with(|v| v.to_string())
+    }
+
+    fn oneline_with(&self, format_value: impl Fn(u64) -> String) -> String {
         let percentiles = match self.histo.percentiles(&[1.0, 10.0, 50.0, 90.0, 99.0]) {
             Ok(p) => p,
             Err(e) => return format!("No data: {}", e),
         };
 
-        let percentiles: Vec<u64> = percentiles
+        let percentiles: Vec<String> = percentiles
             .iter()
-            .map(|p| p.bucket().low() + p.bucket().high() / 2)
+            .map(|p| format_value(p.bucket().low() + p.bucket().high() / 2))
             .collect();
 
         format!(
             "min {}, 1% {}, 10% {}, 50% {}, 90% {}, 99% {}, max {}",
-            self.min,
+            format_value(self.min),
             percentiles[0],
             percentiles[1],
             percentiles[2],
             percentiles[3],
             percentiles[4],
-            self.max,
+            format_value(self.max),
         )
     }
 }
@@ -89,16 +423,51 @@ impl MinMaxHisto {
 impl MetadataSummary {
     fn new() -> Self {
         Self {
+            bucket: String::new(),
+            profile: None,
+            run_id: Uuid::nil(),
+            clock: Arc::new(SystemClock),
             tenant_count: 0,
             timeline_count: 0,
             timeline_shard_count: 0,
             with_errors: HashSet::new(),
             with_warnings: HashSet::new(),
             with_orphans: HashSet::new(),
-            indices_by_version: HashMap::new(),
+            with_timeouts: HashSet::new(),
+            with_invalid_layer_map: HashSet::new(),
+            stopped_at: None,
+            tenant_errors: HashMap::new(),
+            orphan_timelines: HashMap::new(),
+            empty_tenants: Vec::new(),
+            timelines_in_multiple_tenants: BTreeMap::new(),
+            excluded_timeline_shards: 0,
+            retry_budget_exhausted: false,
+            indices_by_version: BTreeMap::new(),
+            parse_errors_by_category: HashMap::new(),
+            fatal_on_unparseable: false,
+            disk_consistent_lsn_duplicates_equal: 0,
+            disk_consistent_lsn_duplicates_mismatched: 0,
+            garbage_ratios: Vec::new(),
+            shard_totals: BTreeMap::new(),
+            shard_imbalances: Vec::new(),
+            health_scores: Vec::new(),
+            slowest_tenants: Vec::new(),
+            layers_referenced_present: 0,
+            layers_referenced_missing: 0,
+            layers_present_unreferenced: 0,
+            layers_outside_key_filter: 0,
+            noncurrent_versions: 0,
+            noncurrent_version_bytes: 0,
             layer_count: MinMaxHisto::new(),
             timeline_size_bytes: MinMaxHisto::new(),
+            timeline_lsn_span: MinMaxHisto::new(),
+            l0_stack_depth: MinMaxHisto::new(),
             layer_size_bytes: MinMaxHisto::new(),
+            orphan_layers: None,
+            findings: Vec::new(),
+            retained_finding_count: 0,
+            findings_count_only: false,
+            findings_counted_only: BTreeMap::new(),
         }
     }
 
@@ -112,11 +481,33 @@ impl MetadataSummary {
         }
         self.timeline_size_bytes.sample(total_size)?;
 
+        let lowest_lsn = index_part
+            .layer_metadata
+            .keys()
+            .map(|layer| match layer {
+                LayerName::Image(image) => image.lsn,
+                LayerName::Delta(delta) => delta.lsn_range.start,
+            })
+            .min();
+        if let Some(lowest_lsn) = lowest_lsn {
+            let disk_consistent_lsn = index_part.metadata.disk_consistent_lsn();
+            self.timeline_lsn_span
+                .sample(disk_consistent_lsn.0.saturating_sub(lowest_lsn.0))?;
+        }
+        self.l0_stack_depth
+            .sample(l0_stack_depth(index_part.layer_metadata.keys()) as u64)?;
+
         Ok(())
     }
 
     fn update_data(&mut self, data: &S3TimelineBlobData) {
         self.timeline_shard_count += 1;
+        self.noncurrent_versions += data.noncurrent_versions.len();
+        self.noncurrent_version_bytes += data
+            .noncurrent_versions
+            .iter()
+            .map(|version| version.size)
+            .sum::<u64>();
         if let BlobDataParseResult::Parsed {
             index_part,
             index_part_generation: _,
@@ -128,6 +519,12 @@ impl MetadataSummary {
                 .entry(index_part.get_version())
                 .or_insert(0) += 1;
 
+            if index_part.metadata.disk_consistent_lsn() == index_part.get_disk_consistent_lsn() {
+                self.disk_consistent_lsn_duplicates_equal += 1;
+            } else {
+                self.disk_consistent_lsn_duplicates_mismatched += 1;
+            }
+
             if let Err(e) = self.update_histograms(index_part) {
                 // Value out of range?  Warn that the results are untrustworthy
                 tracing::warn!(
@@ -136,9 +533,18 @@ impl MetadataSummary {
                 );
             }
         }
+
+        if let BlobDataParseResult::Incorrect(errors) = &data.blob_data {
+            let categories: HashSet<_> = errors.iter().map(|error| error.category).collect();
+            for category in categories {
+                *self.parse_errors_by_category.entry(category).or_insert(0) += 1;
+            }
+        }
     }
 
     fn update_analysis(&mut self, id: &TenantShardTimelineId, analysis: &TimelineAnalysis) {
+        self.layers_outside_key_filter += analysis.layers_outside_key_filter;
+
         if !analysis.errors.is_empty() {
             self.with_errors.insert(*id);
         }
@@ -146,10 +552,361 @@ impl MetadataSummary {
         if !analysis.warnings.is_empty() {
             self.with_warnings.insert(*id);
         }
+
+        if analysis.timed_out {
+            self.with_timeouts.insert(*id);
+        }
+
+        if !analysis.layer_map_valid {
+            self.with_invalid_layer_map.insert(*id);
+        }
+
+        self.layers_referenced_present += analysis.reconciliation.referenced_present;
+        self.layers_referenced_missing += analysis.reconciliation.referenced_missing;
+        self.layers_present_unreferenced += analysis.reconciliation.present_unreferenced;
+    }
+
+    fn notify_tenant_elapsed(&mut self, tenant_id: TenantId, elapsed: Duration) {
+        self.slowest_tenants
+            .push((tenant_id, elapsed.as_secs_f64()));
+        self.slowest_tenants.sort_by(|a, b| b.1.total_cmp(&a.1));
+        self.slowest_tenants.truncate(SLOWEST_TENANTS);
     }
 
-    fn notify_timeline_orphan(&mut self, ttid: &TenantShardTimelineId) {
+    fn notify_health_score(&mut self, id: &TenantShardTimelineId, score: u8) {
+        if score < 100 {
+            self.health_scores.push((*id, score));
+        }
+    }
+
+    fn notify_findings(&mut self, mut analysis: TimelineAnalysis, options: &ScrubOptions) {
+        if !options.include_healthy && analysis.errors.is_empty() && analysis.warnings.is_empty() {
+            return;
+        }
+
+        let finding_count = analysis.errors.len() + analysis.warnings.len();
+        if !self.findings_count_only {
+            if let Some(max_findings) = options.max_findings {
+                if self.retained_finding_count + finding_count > max_findings {
+                    tracing::warn!(
+                        "Retained {} findings, the most allowed: counting further findings without retaining them, and no longer retaining healthy timelines",
+                        self.retained_finding_count
+                    );
+                    self.findings_count_only = true;
+                }
+            }
+        }
+        if self.findings_count_only {
+            for finding in analysis.errors.iter().chain(analysis.warnings.iter()) {
+                *self.findings_counted_only.entry(finding.code).or_default() += 1;
+            }
+            return;
+        }
+
+        // The per-layer detail is not part of the findings
+        analysis.layers = Vec::new();
+        self.retained_finding_count += finding_count;
+        self.findings.push(analysis);
+    }
+
+    /// The findings of every timeline that had any, as JSON: save this to compare a later run
+    /// against it with [`Self::diff_findings`].  If the options included healthy timelines, every
+    /// timeline scanned is there, with its basic stats: an inventory of the bucket.
+    ///
+    /// If the cap on retained findings was reached, the findings are marked as truncated, and
+    /// later runs refuse to compare against them.
+    pub fn findings_json(&self) -> serde_json::Result<String> {
+        let findings = self.findings.as_slice();
+        if self.findings_count_only {
+            serde_json::to_string(&SavedFindings::Truncated {
+                truncated_after: self.retained_finding_count,
+                findings,
+            })
+        } else {
+            serde_json::to_string(&SavedFindings::Complete(findings))
+        }
+    }
+
+    /// Whether the cap on retained findings was reached, so that the findings of
+    /// [`Self::findings_json`] and the outputs derived from them miss those of some timelines
+    pub fn findings_truncated(&self) -> bool {
+        self.findings_count_only
+    }
+
+    fn ensure_findings_complete(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.findings_count_only,
+            "This run's findings were truncated after {} findings by --max-findings, so cannot be compared",
+            self.retained_finding_count
+        );
+        Ok(())
+    }
+
+    /// Write the findings of [`Self::findings_json`] as a SQL script that creates and fills
+    /// `timelines`, `findings` and `garbage` tables, for ad-hoc querying, e.g.
+    /// `sqlite3 scrub.db < findings.sql`.  Every row carries the run id, so that the findings of
+    /// many runs can share one database.
+    pub fn write_findings_sql(&self, writer: &mut dyn Write) -> anyhow::Result<()> {
+        writeln!(writer, "BEGIN;")?;
+        writer.write_all(FINDINGS_SQL_SCHEMA.as_bytes())?;
+
+        let run_id = sql_string(self.run_id);
+        for analysis in &self.findings {
+            let ids = format!(
+                "{run_id}, {}, {}",
+                sql_string(analysis.id.tenant_shard_id),
+                sql_string(analysis.id.timeline_id)
+            );
+            let stats = match &analysis.stats {
+                Some(stats) => format!(
+                    "{}, {}, {}",
+                    stats.layer_count,
+                    stats.layer_bytes,
+                    sql_string(stats.disk_consistent_lsn)
+                ),
+                None => "NULL, NULL, NULL".to_string(),
+            };
+            writeln!(
+                writer,
+                "INSERT INTO timelines VALUES ({ids}, {}, {stats});",
+                analysis.timed_out as u8
+            )?;
+
+            let findings = analysis
+                .errors
+                .iter()
+                .map(|finding| ("error", finding))
+                .chain(analysis.warnings.iter().map(|finding| ("warning", finding)));
+            for (severity, finding) in findings {
+                writeln!(
+                    writer,
+                    "INSERT INTO findings VALUES ({ids}, {}, '{severity}', {}, {});",
+                    sql_string(finding.code),
+                    sql_string(&finding.message),
+                    finding
+                        .suggestion
+                        .as_ref()
+                        .map(sql_string)
+                        .unwrap_or("NULL".to_string())
+                )?;
+            }
+
+            for key in &analysis.garbage_keys {
+                writeln!(
+                    writer,
+                    "INSERT INTO garbage VALUES ({ids}, {});",
+                    sql_string(key)
+                )?;
+            }
+        }
+        writeln!(writer, "COMMIT;")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Send every finding to the syslog daemon listening on `socket_path`, usually `/dev/log`, as
+    /// an RFC 5424 message with the run, tenant, timeline and kind of finding as structured data.
+    /// Errors are sent at severity err, warnings at warning and tenant-level errors at crit.
+    /// Messages that fail to send are logged and skipped, rather than failing the run over its
+    /// reporting.  Returns the number of messages sent.
+    pub fn send_findings_syslog(&self, socket_path: &Utf8Path) -> anyhow::Result<usize> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(socket_path)
+            .with_context(|| format!("connecting to {socket_path}"))?;
+        let pid = std::process::id();
+        let mut sent = 0;
+        let mut failed = 0;
+        let mut send = |severity: u8, params: &str, message: &str| {
+            let message = format!(
+                "<{}>1 {} - s3_scrubber {pid} - [{SYSLOG_SD_ID} run=\"{}\"{params}] {message}",
+                SYSLOG_FACILITY * 8 + severity,
+                self.clock.now().to_rfc3339(),
+                self.run_id
+            );
+            match socket.send(message.as_bytes()) {
+                Ok(_) => sent += 1,
+                Err(e) => {
+                    // The first failure is the telling one: the rest are usually the same
+                    if failed == 0 {
+                        tracing::warn!("Failed to send a finding to syslog at {socket_path}: {e}");
+                    }
+                    failed += 1;
+                }
+            }
+        };
+
+        for (tenant_id, errors) in &self.tenant_errors {
+            let params = format!(" tenant=\"{tenant_id}\"");
+            for error in errors {
+                send(SYSLOG_CRIT, &params, error);
+            }
+        }
+        for analysis in &self.findings {
+            let findings = analysis
+                .errors
+                .iter()
+                .map(|finding| (SYSLOG_ERR, finding))
+                .chain(
+                    analysis
+                        .warnings
+                        .iter()
+                        .map(|finding| (SYSLOG_WARNING, finding)),
+                );
+            for (severity, finding) in findings {
+                let params = format!(
+                    " tenant=\"{}\" timeline=\"{}\" code=\"{}\"",
+                    sd_value(analysis.id.tenant_shard_id),
+                    sd_value(analysis.id.timeline_id),
+                    sd_value(finding.code)
+                );
+                send(severity, &params, &finding.message);
+            }
+        }
+        if failed > 0 {
+            tracing::warn!("Failed to send {failed} findings to syslog at {socket_path}");
+        }
+        Ok(sent)
+    }
+
+    /// The timelines affected by each kind of finding, e.g. for a triage view by category
+    pub fn findings_by_code(&self) -> BTreeMap<CheckCode, Vec<TenantShardTimelineId>> {
+        group_by_code(&self.findings)
+    }
+
+    /// Compare findings saved from a previous run by [`Self::findings_json`] against this run's.
+    /// Fails if either run's findings were truncated.
+    pub fn diff_findings(&self, previous_findings_json: &[u8]) -> anyhow::Result<FindingsDiff> {
+        self.ensure_findings_complete()?;
+        let previous = SavedFindings::parse_complete(previous_findings_json, "previous")?;
+        Ok(diff_findings(&previous, &self.findings))
+    }
+
+    /// Compare the timeline fingerprints of a baseline run, saved by [`Self::findings_json`] with
+    /// healthy timelines included, against this run's.  With `compare_lsn`, timelines whose disk
+    /// consistent LSN moved count as changed too.  Fails if either run's findings were truncated.
+    pub fn diff_fingerprints(
+        &self,
+        baseline_findings_json: &[u8],
+        compare_lsn: bool,
+    ) -> anyhow::Result<FingerprintDiff> {
+        self.ensure_findings_complete()?;
+        let baseline = SavedFindings::parse_complete(baseline_findings_json, "baseline")?;
+        Ok(diff_fingerprints(&baseline, &self.findings, compare_lsn))
+    }
+
+    fn notify_timeline_orphan(&mut self, ttid: &TenantShardTimelineId, entry: OrphanManifestEntry) {
         self.with_orphans.insert(*ttid);
+        if let Some(orphan_layers) = self.orphan_layers.as_mut() {
+            orphan_layers.push(entry);
+        }
+    }
+
+    fn notify_tenant_errors(&mut self, tenant_id: TenantId, errors: Vec<String>) {
+        if !errors.is_empty() {
+            tracing::error!("Tenant {tenant_id} errors: {errors:?}");
+            self.tenant_errors.insert(tenant_id, errors);
+        }
+    }
+
+    fn notify_orphan_timeline(&mut self, prefix: &str, object_count: usize) {
+        self.orphan_timelines
+            .insert(prefix.to_string(), object_count);
+    }
+
+    fn notify_tenant_garbage(
+        &mut self,
+        tenant_id: TenantId,
+        garbage_bytes: u64,
+        referenced_bytes: u64,
+    ) {
+        if garbage_bytes == 0 {
+            return;
+        }
+        self.garbage_ratios.push(TenantGarbage {
+            tenant_id,
+            garbage_bytes,
+            referenced_bytes,
+            ratio: (referenced_bytes > 0).then(|| garbage_bytes as f64 / referenced_bytes as f64),
+        });
+    }
+
+    fn notify_shard_totals(
+        &mut self,
+        tenant_id: TenantId,
+        totals: BTreeMap<ShardIndex, ShardTotals>,
+    ) {
+        if totals.is_empty() {
+            return;
+        }
+        let total_bytes: u64 = totals.values().map(|totals| totals.layer_bytes).sum();
+        let largest_bytes = totals
+            .values()
+            .map(|totals| totals.layer_bytes)
+            .max()
+            .unwrap_or_default();
+        if total_bytes > 0 {
+            let mean_bytes = total_bytes as f64 / totals.len() as f64;
+            self.shard_imbalances
+                .push((tenant_id, largest_bytes as f64 / mean_bytes));
+        }
+        self.shard_totals.insert(tenant_id, totals);
+    }
+
+    /// Called once all tenants have been analyzed
+    fn finish(&mut self) {
+        // A tenant with garbage but nothing referenced is all garbage: rank it above everyone
+        self.garbage_ratios.sort_by(|a, b| {
+            let ratio = |garbage: &TenantGarbage| garbage.ratio.unwrap_or(f64::INFINITY);
+            ratio(b).total_cmp(&ratio(a))
+        });
+        self.shard_imbalances.sort_by(|a, b| b.1.total_cmp(&a.1));
+        self.health_scores.sort_by_key(|(id, score)| (*score, *id));
+        self.empty_tenants.sort();
+    }
+
+    /// A timeline ID is unique within a tenant, and timeline IDs are random: one found under
+    /// several tenants was copied there, by a bug or by hand.
+    fn notify_timeline_tenants(
+        &mut self,
+        timeline_tenants: HashMap<TimelineId, BTreeSet<TenantId>>,
+    ) {
+        for (timeline_id, tenant_ids) in timeline_tenants {
+            if tenant_ids.len() > 1 {
+                tracing::error!(
+                    "Timeline {timeline_id} found under multiple tenants: {}",
+                    itertools::join(&tenant_ids, ", ")
+                );
+                self.timelines_in_multiple_tenants
+                    .insert(timeline_id, tenant_ids);
+            }
+        }
+    }
+
+    fn notify_empty_tenants(&mut self, empty_tenants: Vec<(TenantShardId, EmptyTenantKind)>) {
+        for (tenant_shard_id, kind) in &empty_tenants {
+            match kind {
+                EmptyTenantKind::Deleting => {
+                    tracing::info!(
+                        "Tenant shard {tenant_shard_id} has no timelines: it is being deleted"
+                    )
+                }
+                EmptyTenantKind::NoTimelines => {
+                    tracing::info!("Tenant shard {tenant_shard_id} has no timelines")
+                }
+                EmptyTenantKind::LostTimelines => {
+                    let error = format!(
+                        "Tenant shard {tenant_shard_id} has objects under its timelines prefix, but no timelines: they may have been lost"
+                    );
+                    tracing::error!("{error}");
+                    self.tenant_errors
+                        .entry(tenant_shard_id.tenant_id)
+                        .or_default()
+                        .push(error);
+                }
+            }
+        }
+        self.empty_tenants.extend(empty_tenants);
     }
 
     /// Long-form output for printing at end of a scan
@@ -160,33 +917,142 @@ impl MetadataSummary {
                 .map(|(k, v)| format!("{k}: {v}")),
             ", ",
         );
+        let parse_error_summary: String = itertools::join(
+            self.parse_errors_by_category
+                .iter()
+                .map(|(category, count)| format!("{}: {count}", category.as_str())),
+            ", ",
+        );
+        let garbage_summary: String = itertools::join(
+            self.garbage_ratios
+                .iter()
+                .take(10)
+                .map(|garbage| match garbage.ratio {
+                    Some(ratio) => format!("{}: {ratio:.3}", garbage.tenant_id),
+                    None => format!("{}: all garbage", garbage.tenant_id),
+                }),
+            ", ",
+        );
+        let shard_imbalance_summary: String = itertools::join(
+            self.shard_imbalances
+                .iter()
+                .take(10)
+                .map(|(tenant_id, ratio)| format!("{tenant_id}: {ratio:.3}")),
+            ", ",
+        );
+        let findings_by_code_summary: String = itertools::join(
+            self.findings_by_code()
+                .iter()
+                .map(|(code, ids)| format!("{code}: {}", ids.len())),
+            ", ",
+        );
+        let counted_only_summary: String = if self.findings_count_only {
+            itertools::join(
+                self.findings_counted_only
+                    .iter()
+                    .map(|(code, count)| format!("{code}: {count}")),
+                ", ",
+            )
+        } else {
+            "<none>".to_string()
+        };
+        let slowest_tenant_summary: String = itertools::join(
+            self.slowest_tenants
+                .iter()
+                .map(|(tenant_id, secs)| format!("{tenant_id}: {secs:.1}s")),
+            ", ",
+        );
+        let health_summary: String = itertools::join(
+            self.health_scores
+                .iter()
+                .take(10)
+                .map(|(id, score)| format!("{id}: {score}")),
+            ", ",
+        );
 
         format!(
-            "Tenants: {}
+            "Bucket: {}{}
+Run: {}
+Tenants: {}
 Timelines: {}
 Timeline-shards: {}
 With errors: {}
+Tenants with errors: {}
 With warnings: {}
 With orphan layers: {}
+Timed out: {}
+With invalid layer maps: {}
+Stopped at: {}
+Orphan timelines: {}
+Empty tenant shards: {}
+Timeline IDs under multiple tenants: {}
+Excluded timeline shards: {}
+Retry budget exhausted: {}
 Index versions: {version_summary}
+Unparseable timelines by error category: {parse_error_summary}
+Duplicated disk_consistent_lsn equal/mismatched: {}/{}
+Highest garbage ratios: {garbage_summary}
+Most imbalanced sharded tenants (largest shard / mean): {shard_imbalance_summary}
+Lowest health scores: {health_summary}
+Slowest tenants to check: {slowest_tenant_summary}
+Timelines by kind of finding: {findings_by_code_summary}
+Findings counted but not retained, once the cap was reached: {counted_only_summary}
+Layers referenced-and-present/referenced-but-missing/present-but-unreferenced: {}/{}/{}
+Layers outside the key range filter: {}
+Noncurrent object versions: {} ({})
 Timeline size bytes: {}
 Layer size bytes: {}
 Timeline layer count: {}
+Timeline LSN span: {}
+Timeline LSN span (WAL bytes): {}
+Timeline L0 stack depth: {}
 ",
+            self.bucket,
+            self.profile
+                .as_ref()
+                .map(|profile| format!(" (profile {profile})"))
+                .unwrap_or_default(),
+            self.run_id,
             self.tenant_count,
             self.timeline_count,
             self.timeline_shard_count,
             self.with_errors.len(),
+            self.tenant_errors.len(),
             self.with_warnings.len(),
             self.with_orphans.len(),
+            self.with_timeouts.len(),
+            self.with_invalid_layer_map.len(),
+            self.stopped_at
+                .as_ref()
+                .map(|ttid| ttid.to_string())
+                .unwrap_or("<none>".to_string()),
+            self.orphan_timelines.len(),
+            self.empty_tenants.len(),
+            self.timelines_in_multiple_tenants.len(),
+            self.excluded_timeline_shards,
+            self.retry_budget_exhausted,
+            self.disk_consistent_lsn_duplicates_equal,
+            self.disk_consistent_lsn_duplicates_mismatched,
+            self.layers_referenced_present,
+            self.layers_referenced_missing,
+            self.layers_present_unreferenced,
+            self.layers_outside_key_filter,
+            self.noncurrent_versions,
+            human_bytes(self.noncurrent_version_bytes),
             self.timeline_size_bytes.oneline(),
             self.layer_size_bytes.oneline(),
             self.layer_count.oneline(),
+            self.timeline_lsn_span.oneline(),
+            self.timeline_lsn_span.oneline_with(human_bytes),
+            self.l0_stack_depth.oneline(),
         )
     }
 
     pub fn is_fatal(&self) -> bool {
         !self.with_errors.is_empty()
+            || !self.tenant_errors.is_empty()
+            || !self.timelines_in_multiple_tenants.is_empty()
+            || (self.fatal_on_unparseable && !self.parse_errors_by_category.is_empty())
     }
 
     pub fn is_empty(&self) -> bool {
@@ -195,38 +1061,110 @@ Timeline layer count: {}
 }
 
 /// Scan the pageserver metadata in an S3 bucket, reporting errors and statistics.
+///
+/// If `layers_csv` is set, one CSV row is written to it for every layer referenced by an index.
+/// If `orphan_manifest` is set, a manifest of the orphan layers found is written to it: see
+/// [`write_orphan_manifest`].
+#[tracing::instrument(skip_all, fields(run_id = %options.run_id))]
 pub async fn scan_metadata(
     bucket_config: BucketConfig,
     tenant_ids: Vec<TenantShardId>,
+    mut options: ScrubOptions,
+    mut layers_csv: Option<Box<dyn Write>>,
+    orphan_manifest: Option<Box<dyn Write>>,
 ) -> anyhow::Result<MetadataSummary> {
-    let (s3_client, target) = init_remote(bucket_config, NodeKind::Pageserver)?;
+    let profile = bucket_config.profile.clone();
+    let (s3_client, mut target) = init_remote(bucket_config, NodeKind::Pageserver)?;
+    let manifest_context = OrphanManifestContext {
+        bucket: target.bucket_name().to_string(),
+        prefix_in_bucket: Some(target.prefix_in_bucket().to_string()),
+        created_at: options.clock.now(),
+        scrubber_version: env!("CARGO_PKG_VERSION").to_string(),
+        run_id: Some(options.run_id),
+    };
+    target.set_page_size(options.listing_page_size);
+    if let Some(layers_csv) = layers_csv.as_mut() {
+        writeln!(layers_csv, "{}", LayerReport::CSV_HEADER)?;
+        options.report_layers = true;
+    }
+    let retry_policy = options.retry_policy.clone();
+
+    // Only a scan of the whole bucket sees every tenant a timeline ID might be duplicated under
+    let full_bucket = tenant_ids.is_empty();
+    let mut timeline_tenants: HashMap<TimelineId, BTreeSet<TenantId>> = HashMap::new();
 
     let tenants = if tenant_ids.is_empty() {
-        futures::future::Either::Left(stream_tenants(&s3_client, &target))
+        futures::future::Either::Left(stream_tenants(&s3_client, &target, retry_policy.clone()))
     } else {
         futures::future::Either::Right(futures::stream::iter(tenant_ids.into_iter().map(Ok)))
     };
 
-    // How many tenants to process in parallel.  We need to be mindful of pageservers
-    // accessing the same per tenant prefixes, so use a lower setting than pageservers.
-    const CONCURRENCY: usize = 32;
-
-    // Generate a stream of TenantTimelineId
-    let timelines = tenants.map_ok(|t| stream_tenant_timelines(&s3_client, &target, t));
-    let timelines = timelines.try_buffered(CONCURRENCY);
+    // Generate a stream of TenantTimelineId, noting any tenant shard that has none.  Only the
+    // first timeline is waited for, to tell whether there are any.  Each tenant's time is taken
+    // from when the listing of its first shard starts.
+    let empty_tenants = std::sync::Mutex::new(Vec::new());
+    let tenant_started_at: std::sync::Mutex<HashMap<TenantId, Instant>> = Default::default();
+    let timelines = tenants.map_ok(|t| {
+        let (s3_client, target, retry_policy, empty_tenants, tenant_started_at) = (
+            &s3_client,
+            &target,
+            &retry_policy,
+            &empty_tenants,
+            &tenant_started_at,
+        );
+        async move {
+            tenant_started_at
+                .lock()
+                .unwrap()
+                .entry(t.tenant_id)
+                .or_insert_with(Instant::now);
+            let mut timelines = Box::pin(
+                stream_tenant_timelines(s3_client, target, t, retry_policy.clone()).await?,
+            );
+            let first = timelines.next().await;
+            if first.is_none() {
+                let kind =
+                    EmptyTenantKind::list(s3_client, target, t, retry_policy.clone()).await?;
+                empty_tenants.lock().unwrap().push((t, kind));
+            }
+            anyhow::Ok(futures::stream::iter(first).chain(timelines))
+        }
+    });
+    let timelines = timelines.try_buffered(options.concurrency);
     let timelines = timelines.try_flatten();
 
+    // Excluded timelines are skipped before any of their objects are listed
+    let excluded_timelines = AtomicUsize::new(0);
+    let timelines = timelines.try_filter(|ttid| {
+        let excluded = options.excluded_timelines.contains(ttid);
+        if excluded {
+            excluded_timelines.fetch_add(1, Ordering::Relaxed);
+        }
+        std::future::ready(!excluded)
+    });
+
     // Generate a stream of S3TimelineBlobData
     async fn report_on_timeline(
-        s3_client: &Client,
+        s3_client: &StorageClient,
         target: &RootTarget,
         ttid: TenantShardTimelineId,
+        options: &ScrubOptions,
     ) -> anyhow::Result<(TenantShardTimelineId, S3TimelineBlobData)> {
-        let data = list_timeline_blobs(s3_client, ttid, target).await?;
+        // A timeline with a pathological number of objects can stall its listing as much as its
+        // checks, so the timeline timeout bounds both
+        let timeout = options.timeline_timeout;
+        let listing = list_timeline_blobs(s3_client, ttid, target, options);
+        let data = match tokio::time::timeout(timeout, listing).await {
+            Ok(data) => data?,
+            Err(_) => S3TimelineBlobData::listing_failed(anyhow::anyhow!(
+                "Timeline listing and index download did not complete within {timeout:?}"
+            )),
+        };
         Ok((ttid, data))
     }
-    let timelines = timelines.map_ok(|ttid| report_on_timeline(&s3_client, &target, ttid));
-    let mut timelines = std::pin::pin!(timelines.try_buffered(CONCURRENCY));
+    let timelines =
+        timelines.map_ok(|ttid| report_on_timeline(&s3_client, &target, ttid, &options));
+    let mut timelines = std::pin::pin!(timelines.try_buffered(options.concurrency));
 
     // We must gather all the TenantShardTimelineId->S3TimelineBlobData for each tenant, because different
     // shards in the same tenant might refer to one anothers' keys if a shard split has happened.
@@ -235,43 +1173,20 @@ pub async fn scan_metadata(
     let mut tenant_objects = TenantObjectListing::default();
     let mut tenant_timeline_results = Vec::new();
 
-    fn analyze_tenant(
-        tenant_id: TenantId,
+    /// Report the orphans among layers that every index which could reference them has been
+    /// checked against.
+    fn report_orphans(
         summary: &mut MetadataSummary,
-        mut tenant_objects: TenantObjectListing,
-        timelines: Vec<(TenantShardTimelineId, S3TimelineBlobData)>,
+        tenant_id: TenantId,
+        timeline_generations: &HashMap<TenantShardTimelineId, Generation>,
+        layers: &TenantObjectListing,
     ) {
-        summary.tenant_count += 1;
-
-        let mut timeline_ids = HashSet::new();
-        let mut timeline_generations = HashMap::new();
-        for (ttid, data) in timelines {
-            timeline_ids.insert(ttid.timeline_id);
-            // Stash the generation of each timeline, for later use identifying orphan layers
-            if let BlobDataParseResult::Parsed {
-                index_part: _index_part,
-                index_part_generation,
-                s3_layers: _s3_layers,
-            } = &data.blob_data
-            {
-                timeline_generations.insert(ttid, *index_part_generation);
-            }
-
-            // Apply checks to this timeline shard's metadata, and in the process update `tenant_objects`
-            // reference counts for layers across the tenant.
-            let analysis =
-                branch_cleanup_and_check_errors(&ttid, &mut tenant_objects, None, None, Some(data));
-            summary.update_analysis(&ttid, &analysis);
-        }
-
-        summary.timeline_count += timeline_ids.len();
-
-        // Identifying orphan layers must be done on a tenant-wide basis, because individual
-        // shards' layers may be referenced by other shards.
+        // Identifying orphan layers must wait for the indices of all the timeline's shards,
+        // because individual shards' layers may be referenced by other shards.
         //
         // Orphan layers are not a corruption, and not an indication of a problem.  They are just
         // consuming some space in remote storage, and may be cleaned up at leisure.
-        for (shard_index, timeline_id, layer_file, generation) in tenant_objects.get_orphans() {
+        for (shard_index, timeline_id, layer_file, generation, size) in layers.iter_orphans() {
             let ttid = TenantShardTimelineId {
                 tenant_shard_id: TenantShardId {
                     tenant_id,
@@ -295,23 +1210,207 @@ pub async fn scan_metadata(
                 &tenant_id,
                 &timeline_id,
                 shard_index,
-                &layer_file,
+                layer_file,
                 generation,
             );
 
             tracing::info!("Orphan layer detected: {orphan_path}");
 
-            summary.notify_timeline_orphan(&ttid);
+            summary.notify_timeline_orphan(
+                &ttid,
+                OrphanManifestEntry {
+                    path: orphan_path,
+                    generation: (!generation.is_none()).then_some(generation),
+                    size,
+                },
+            );
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn analyze_tenant(
+        s3_client: &StorageClient,
+        target: &RootTarget,
+        tenant_id: TenantId,
+        summary: &mut MetadataSummary,
+        mut tenant_objects: TenantObjectListing,
+        timelines: Vec<(TenantShardTimelineId, S3TimelineBlobData)>,
+        options: &ScrubOptions,
+        layers_csv: &mut Option<Box<dyn Write>>,
+        summary_ticker: &mut Option<SummaryTicker>,
+    ) -> anyhow::Result<ControlFlow<()>> {
+        summary.tenant_count += 1;
+
+        // Excluded timeline shards were never listed, but are no less present for it
+        for ttid in &options.excluded_timelines {
+            if ttid.tenant_shard_id.tenant_id == tenant_id {
+                tenant_objects.push_unlisted(*ttid);
+            }
+        }
+
+        // These need every listing, so are done before the layers of checked timelines are dropped
+        let mut tenant_errors = tenant_objects.check_shard_timelines();
+        if let Some(expected) = options.expected_shard_counts.get(&tenant_id) {
+            let mut referenced = HashSet::new();
+            for (ttid, data) in &timelines {
+                if ttid.tenant_shard_id.shard_count.count() != expected.count() {
+                    continue;
+                }
+                if let BlobDataParseResult::Parsed { index_part, .. } = &data.blob_data {
+                    referenced.extend(
+                        index_part
+                            .layer_metadata
+                            .values()
+                            .map(|metadata| (metadata.shard, ttid.timeline_id)),
+                    );
+                }
+            }
+            tenant_errors.extend(tenant_objects.check_expected_shard_count(*expected, &referenced));
+        }
+        summary.notify_shard_totals(tenant_id, tenant_objects.shard_totals());
+
+        // Only the indices of a timeline's own shards can reference its layers: once they have
+        // all been checked, the timeline's orphans are known, and its layers need not be kept.
+        let mut unchecked_indices: HashMap<TimelineId, usize> = HashMap::new();
+        for (ttid, _) in &timelines {
+            *unchecked_indices.entry(ttid.timeline_id).or_default() += 1;
+        }
+
+        let mut timeline_ids = HashSet::new();
+        let mut timeline_generations = HashMap::new();
+        let mut listed_layer_bytes = 0;
+        let mut referenced_bytes = 0;
+        let mut garbage_bytes = 0;
+        for (ttid, mut data) in timelines {
+            timeline_ids.insert(ttid.timeline_id);
+            listed_layer_bytes += data.listed_layer_bytes;
+            garbage_bytes += data.garbage_bytes;
+            // Stash the generation of each timeline, for later use identifying orphan layers
+            if let BlobDataParseResult::Parsed {
+                index_part: _index_part,
+                index_part_generation,
+                s3_layers,
+            } = &mut data.blob_data
+            {
+                timeline_generations.insert(ttid, *index_part_generation);
+                // The listing was moved into `tenant_objects`: the timeline's checks get a copy
+                // of their own shard's part of it back, only for as long as they run.
+                *s3_layers = tenant_objects.listed_layers(&ttid);
+            }
+
+            // Timeline prefixes with objects but no recognizable timeline structure are reported
+            // at tenant scope: unlike orphan layers, their timeline is not known to anyone.
+            if let BlobDataParseResult::Orphan {
+                prefix,
+                object_count,
+            } = &data.blob_data
+            {
+                tracing::warn!("Orphan timeline detected: {prefix} ({object_count} objects)");
+                summary.notify_orphan_timeline(prefix, *object_count);
+            }
+
+            // Apply checks to this timeline shard's metadata, and in the process update `tenant_objects`
+            // reference counts for layers across the tenant.
+            let analysis = branch_cleanup_and_check_errors(
+                s3_client,
+                target,
+                &ttid,
+                &mut tenant_objects,
+                None,
+                None,
+                Some(data),
+                options,
+            )
+            .await;
+            summary.update_analysis(&ttid, &analysis);
+            summary.notify_health_score(&ttid, analysis.health_score(&options.score_weights));
+
+            if let Some(layers_csv) = layers_csv.as_mut() {
+                for layer in &analysis.layers {
+                    writeln!(layers_csv, "{}", layer.csv_row(&ttid))?;
+                }
+            }
+
+            let healthy = analysis.is_healthy();
+            summary.notify_findings(analysis, options);
+            if let Some(summary_ticker) = summary_ticker.as_mut() {
+                summary_ticker.tick(summary);
+            }
+
+            if options.failure_mode == FailureMode::FailFast && !healthy {
+                tracing::warn!("Stopping scan at unhealthy timeline {ttid}");
+                summary.stopped_at = Some(ttid);
+                return Ok(ControlFlow::Break(()));
+            }
+
+            let unchecked = unchecked_indices
+                .get_mut(&ttid.timeline_id)
+                .expect("Counted above");
+            *unchecked -= 1;
+            if *unchecked == 0 {
+                let checked = tenant_objects.take_timeline(ttid.timeline_id);
+                tenant_errors.extend(checked.check_referenced_generations());
+                referenced_bytes += checked.referenced_bytes();
+                report_orphans(summary, tenant_id, &timeline_generations, &checked);
+            }
         }
+
+        summary.timeline_count += timeline_ids.len();
+        summary.notify_tenant_errors(tenant_id, tenant_errors);
+
+        // Layers listed but not referenced by any index are orphans, and count as garbage
+        garbage_bytes += listed_layer_bytes.saturating_sub(referenced_bytes);
+        summary.notify_tenant_garbage(tenant_id, garbage_bytes, referenced_bytes);
+
+        Ok(ControlFlow::Continue(()))
     }
 
     // Iterate through  all the timeline results.  These are in key-order, so
     // all results for the same tenant will be adjacent.  We accumulate these,
     // and then call `analyze_tenant` to flush, when we see the next tenant ID.
     let mut summary = MetadataSummary::new();
-    while let Some(i) = timelines.next().await {
-        let (ttid, data) = i?;
+    summary.bucket = manifest_context.bucket.clone();
+    summary.profile = profile;
+    summary.run_id = options.run_id;
+    summary.clock = options.clock.clone();
+    summary.fatal_on_unparseable = options.fatal_on_unparseable;
+    if orphan_manifest.is_some() {
+        summary.orphan_layers = Some(Vec::new());
+    }
+    let mut summary_ticker = options.summary_interval.map(SummaryTicker::new);
+    let notify_tenant_elapsed = |summary: &mut MetadataSummary, tenant_id: TenantId| {
+        if let Some(started_at) = tenant_started_at.lock().unwrap().remove(&tenant_id) {
+            summary.notify_tenant_elapsed(tenant_id, started_at.elapsed());
+        }
+    };
+    loop {
+        let next = match summary_ticker.as_ref() {
+            Some(summary_ticker) => {
+                let due = tokio::time::sleep_until(summary_ticker.due_at);
+                tokio::select! {
+                    next = timelines.next() => Some(next),
+                    () = due => None,
+                }
+            }
+            None => Some(timelines.next().await),
+        };
+        let Some(next) = next else {
+            if let Some(summary_ticker) = summary_ticker.as_mut() {
+                summary_ticker.tick(&summary);
+            }
+            continue;
+        };
+        let Some(i) = next else {
+            break;
+        };
+        let (ttid, mut data) = i?;
         summary.update_data(&data);
+        if full_bucket {
+            timeline_tenants
+                .entry(ttid.timeline_id)
+                .or_default()
+                .insert(ttid.tenant_shard_id.tenant_id);
+        }
 
         match tenant_id {
             None => tenant_id = Some(ttid.tenant_shard_id.tenant_id),
@@ -319,31 +1418,154 @@ pub async fn scan_metadata(
                 if prev_tenant_id != ttid.tenant_shard_id.tenant_id {
                     let tenant_objects = std::mem::take(&mut tenant_objects);
                     let timelines = std::mem::take(&mut tenant_timeline_results);
-                    analyze_tenant(prev_tenant_id, &mut summary, tenant_objects, timelines);
+                    let flow = analyze_tenant(
+                        &s3_client,
+                        &target,
+                        prev_tenant_id,
+                        &mut summary,
+                        tenant_objects,
+                        timelines,
+                        &options,
+                        &mut layers_csv,
+                        &mut summary_ticker,
+                    )
+                    .await?;
+                    notify_tenant_elapsed(&mut summary, prev_tenant_id);
+                    if flow.is_break() {
+                        break;
+                    }
                     tenant_id = Some(ttid.tenant_shard_id.tenant_id);
                 }
             }
         }
 
+        tenant_objects.push_index_generations(ttid, &data.index_generations);
         if let BlobDataParseResult::Parsed {
             index_part: _index_part,
             index_part_generation: _index_part_generation,
             s3_layers,
-        } = &data.blob_data
+        } = &mut data.blob_data
         {
-            tenant_objects.push(ttid, s3_layers.clone());
+            // Moved rather than copied, so that the tenant's listing is only held once
+            tenant_objects.push(ttid, std::mem::take(s3_layers));
+        } else if let BlobDataParseResult::ListingFailed(_) | BlobDataParseResult::Incorrect(_) =
+            &data.blob_data
+        {
+            // Its problems are reported as the timeline's own: it is not missing from the shard
+            tenant_objects.push_unlisted(ttid);
         }
         tenant_timeline_results.push((ttid, data));
     }
 
     if !tenant_timeline_results.is_empty() {
-        analyze_tenant(
-            tenant_id.expect("Must be set if results are present"),
+        // This is the last tenant, so there is nothing left to stop scanning
+        let tenant_id = tenant_id.expect("Must be set if results are present");
+        let _ = analyze_tenant(
+            &s3_client,
+            &target,
+            tenant_id,
             &mut summary,
             tenant_objects,
             tenant_timeline_results,
+            &options,
+            &mut layers_csv,
+            &mut summary_ticker,
+        )
+        .await?;
+        notify_tenant_elapsed(&mut summary, tenant_id);
+    }
+
+    if let Some(layers_csv) = layers_csv.as_mut() {
+        layers_csv.flush()?;
+    }
+    if let Some(mut orphan_manifest) = orphan_manifest {
+        let orphan_layers = summary.orphan_layers.take().unwrap_or_default();
+        write_orphan_manifest(&orphan_layers, &mut *orphan_manifest, &manifest_context)?;
+    }
+
+    let empty_tenants = std::mem::take(&mut *empty_tenants.lock().unwrap());
+    summary.notify_empty_tenants(empty_tenants);
+    summary.notify_timeline_tenants(timeline_tenants);
+    summary.excluded_timeline_shards = excluded_timelines.load(Ordering::Relaxed);
+    if summary.excluded_timeline_shards > 0 {
+        tracing::info!(
+            "Skipped {} excluded timeline shards",
+            summary.excluded_timeline_shards
         );
     }
 
+    summary.retry_budget_exhausted = retry_policy.budget.is_exhausted();
+    if summary.retry_budget_exhausted {
+        tracing::warn!("Retry budget exhausted: some requests failed without being retried");
+    }
+
+    summary.finish();
     Ok(summary)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_findings_are_not_compared() {
+        let mut summary = MetadataSummary::new();
+        let complete = summary.findings_json().unwrap();
+        assert_eq!(complete, "[]");
+        assert!(summary.diff_findings(complete.as_bytes()).is_ok());
+
+        summary.findings_count_only = true;
+        summary.retained_finding_count = 3;
+        let truncated = summary.findings_json().unwrap();
+        assert!(summary.diff_findings(complete.as_bytes()).is_err());
+
+        summary.findings_count_only = false;
+        let e = summary.diff_findings(truncated.as_bytes()).unwrap_err();
+        assert!(e.to_string().contains("truncated after 3 findings"));
+        assert!(summary
+            .diff_fingerprints(truncated.as_bytes(), false)
+            .is_err());
+    }
+
+    #[test]
+    fn empty_tenants_by_what_they_hold() {
+        let kind = |keys: &[&str]| EmptyTenantKind::from_keys(keys.iter().copied());
+        assert_eq!(kind(&[]), EmptyTenantKind::NoTimelines);
+        assert_eq!(kind(&["heatmap-v1.json"]), EmptyTenantKind::NoTimelines);
+        assert_eq!(
+            kind(&["heatmap-v1.json", "timelines/deleted"]),
+            EmptyTenantKind::Deleting
+        );
+        assert_eq!(
+            kind(&["timelines/initdb.tar.zst", "timelines/deleted"]),
+            EmptyTenantKind::Deleting
+        );
+        assert_eq!(
+            kind(&["heatmap-v1.json", "timelines/stray"]),
+            EmptyTenantKind::LostTimelines
+        );
+    }
+
+    #[test]
+    fn sql_strings_double_their_quotes() {
+        assert_eq!(sql_string("layer"), "'layer'");
+        assert_eq!(sql_string("it's 'quoted'"), "'it''s ''quoted'''");
+        assert_eq!(sql_string(""), "''");
+
+        let mut sql = Vec::new();
+        let summary = MetadataSummary::new();
+        summary.write_findings_sql(&mut sql).unwrap();
+        let sql = String::from_utf8(sql).unwrap();
+        assert!(sql.starts_with("BEGIN;\n"));
+        assert!(sql.ends_with("COMMIT;\n"));
+    }
+
+    #[test]
+    fn sd_values_escape_what_rfc_5424_reserves() {
+        assert_eq!(sd_value("missing-layer"), "missing-layer");
+        assert_eq!(sd_value(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(sd_value(r"a\b]"), r"a\\b\]");
+        // Only the backslashes already in the value are doubled
+        assert_eq!(sd_value(r#"\""#), r#"\\\""#);
+    }
+}
@@ -4,11 +4,11 @@ use std::sync::Arc;
 use crate::checks::{list_timeline_blobs, BlobDataParseResult, S3TimelineBlobData};
 use crate::metadata_stream::{stream_tenant_shards, stream_tenant_timelines};
 use crate::{
-    download_object_to_file, init_remote, BucketConfig, NodeKind, RootTarget, TenantShardTimelineId,
+    download_object_to_file, init_remote, BucketConfig, NodeKind, RetryPolicy, RootTarget,
+    ScrubOptions, StorageClient, TenantShardTimelineId,
 };
 use anyhow::Context;
 use async_stream::stream;
-use aws_sdk_s3::Client;
 use camino::Utf8PathBuf;
 use futures::{StreamExt, TryStreamExt};
 use pageserver::tenant::remote_timeline_client::index::LayerFileMetadata;
@@ -19,7 +19,7 @@ use utils::generation::Generation;
 use utils::id::TenantId;
 
 pub struct SnapshotDownloader {
-    s3_client: Arc<Client>,
+    s3_client: Arc<StorageClient>,
     s3_root: RootTarget,
     bucket_config: BucketConfig,
     tenant_id: TenantId,
@@ -83,6 +83,7 @@ impl SnapshotDownloader {
             // List versions: the object might be deleted.
             let versions = self
                 .s3_client
+                .s3()?
                 .list_object_versions()
                 .bucket(self.bucket_config.bucket.clone())
                 .prefix(&remote_layer_path)
@@ -97,6 +98,7 @@ impl SnapshotDownloader {
                 &remote_layer_path,
                 version.version_id.as_deref(),
                 &local_path,
+                &RetryPolicy::default(),
             )
             .await?;
 
@@ -218,7 +220,9 @@ impl SnapshotDownloader {
         let (s3_client, target) = init_remote(self.bucket_config.clone(), NodeKind::Pageserver)?;
 
         // Generate a stream of TenantShardId
-        let shards = stream_tenant_shards(&s3_client, &target, self.tenant_id).await?;
+        let shards =
+            stream_tenant_shards(&s3_client, &target, self.tenant_id, RetryPolicy::default())
+                .await?;
         let shards: Vec<TenantShardId> = shards.try_collect().await?;
 
         // Only read from shards that have the highest count: avoids redundantly downloading
@@ -236,15 +240,18 @@ impl SnapshotDownloader {
 
         for shard in shards.into_iter().filter(|s| s.shard_count == shard_count) {
             // Generate a stream of TenantTimelineId
-            let timelines = stream_tenant_timelines(&s3_client, &self.s3_root, shard).await?;
+            let timelines =
+                stream_tenant_timelines(&s3_client, &self.s3_root, shard, RetryPolicy::default())
+                    .await?;
 
             // Generate a stream of S3TimelineBlobData
             async fn load_timeline_index(
-                s3_client: &Client,
+                s3_client: &StorageClient,
                 target: &RootTarget,
                 ttid: TenantShardTimelineId,
             ) -> anyhow::Result<(TenantShardTimelineId, S3TimelineBlobData)> {
-                let data = list_timeline_blobs(s3_client, ttid, target).await?;
+                let data =
+                    list_timeline_blobs(s3_client, ttid, target, &ScrubOptions::default()).await?;
                 Ok((ttid, data))
             }
             let timelines = timelines.map_ok(|ttid| load_timeline_index(&s3_client, &target, ttid));
@@ -268,9 +275,12 @@ impl SnapshotDownloader {
                         .context("Downloading timeline")?;
                     }
                     BlobDataParseResult::Relic => {}
-                    BlobDataParseResult::Incorrect(_) => {
+                    BlobDataParseResult::Orphan { .. } | BlobDataParseResult::Incorrect(_) => {
                         tracing::error!("Bad metadata in timeline {ttid}");
                     }
+                    BlobDataParseResult::ListingFailed(error) => {
+                        anyhow::bail!("Listing timeline {ttid}: {error}");
+                    }
                 };
             }
         }